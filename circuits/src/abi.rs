@@ -0,0 +1,285 @@
+use std::fmt;
+use std::error::Error;
+
+use bellman_ce::groth16::Proof;
+
+use pairing_ce::{
+    CurveAffine,
+    EncodedPoint,
+    bn256,
+    bn256::{ Bn256, G1Affine, G2Affine, G1Uncompressed, G2Uncompressed },
+};
+
+use ff_ce::{
+    PrimeField,
+    PrimeFieldRepr,
+};
+
+use tiny_keccak::{ Hasher, Keccak };
+
+// Ethereum ABI encoding of proofs and public inputs for
+// `verifyProof(uint256[] publicInputs, uint256[] proof)`.
+//
+// Proof is encoded as 8 uints in the order `Verifier.sol` expects:
+// A (x, y), B (x.c1, x.c0, y.c1, y.c0), C (x, y).
+
+pub const WORD_BYTES: usize = 32;
+pub const PROOF_LENGTH: usize = 8;
+pub const VERIFY_PROOF_SIGNATURE: &str = "verifyProof(uint256[],uint256[])";
+
+const G1_WORDS: usize = 2;
+const G2_WORDS: usize = 4;
+const SELECTOR_BYTES: usize = 4;
+
+pub type Word = [u8; WORD_BYTES];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AbiError {
+    InvalidLength { expected: usize, actual: usize },
+    InvalidOffset { offset: usize },
+    InvalidSelector,
+    InvalidFieldElement { index: usize },
+    InvalidPoint { index: usize },
+    InvalidHex,
+}
+
+impl Error for AbiError {}
+
+impl fmt::Display for AbiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            AbiError::InvalidLength { expected, actual } => {
+                write!(f, "Invalid data length: expected {}, got {}", expected, actual)
+            },
+            AbiError::InvalidOffset { offset } => {
+                write!(f, "Invalid array offset {}", offset)
+            },
+            AbiError::InvalidSelector => write!(f, "Unexpected function selector"),
+            AbiError::InvalidFieldElement { index } => {
+                write!(f, "Public input {} is not a field element", index)
+            },
+            AbiError::InvalidPoint { index } => {
+                write!(f, "Proof point {} is not a valid curve point", index)
+            },
+            AbiError::InvalidHex => write!(f, "Invalid hex string"),
+        }
+    }
+}
+
+// words ----------------------------------------------------------------------------
+
+pub fn word_to_hex(word: &Word) -> String {
+    format!("0x{}", hex::encode(word))
+}
+
+fn usize_to_word(value: usize) -> Word {
+    let mut word = [0u8; WORD_BYTES];
+    word[WORD_BYTES - 8..].copy_from_slice(&(value as u64).to_be_bytes());
+    word
+}
+
+fn word_to_usize(word: &[u8]) -> Option<usize> {
+    if word[..WORD_BYTES - 8].iter().any(|byte| *byte != 0) {
+        return None;
+    }
+
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&word[WORD_BYTES - 8..]);
+    Some(u64::from_be_bytes(bytes) as usize)
+}
+
+pub fn fr_to_word(value: &bn256::Fr) -> Word {
+    let mut word = [0u8; WORD_BYTES];
+    value.into_repr().write_be(&mut word[..]).expect("field element fits a word");
+    word
+}
+
+pub fn word_to_fr(word: &[u8]) -> Option<bn256::Fr> {
+    let mut repr = <bn256::Fr as PrimeField>::Repr::default();
+    repr.read_be(word).ok()?;
+    bn256::Fr::from_repr(repr).ok()
+}
+
+// uncompressed encodings hold big-endian coordinates in the abi order,
+// point at infinity is flagged there and encoded as zeros in abi
+fn encoding_to_words(bytes: &[u8]) -> Vec::<Word> {
+    let infinity = bytes[0] & (1 << 6) != 0;
+
+    bytes.chunks(WORD_BYTES).map(|chunk| {
+        let mut word = [0u8; WORD_BYTES];
+        if !infinity {
+            word.copy_from_slice(chunk);
+        }
+        word
+    }).collect()
+}
+
+fn words_to_encoding<P: EncodedPoint>(words: &[Word]) -> P {
+    let mut encoded = P::empty();
+    if words.iter().all(|word| word.iter().all(|byte| *byte == 0)) {
+        encoded.as_mut()[0] |= 1 << 6;
+        return encoded;
+    }
+
+    for (chunk, word) in encoded.as_mut().chunks_mut(WORD_BYTES).zip(words.iter()) {
+        chunk.copy_from_slice(word);
+    }
+    encoded
+}
+
+pub fn g1_to_words(point: &G1Affine) -> Vec::<Word> {
+    encoding_to_words(point.into_uncompressed().as_ref())
+}
+
+pub fn g2_to_words(point: &G2Affine) -> Vec::<Word> {
+    encoding_to_words(point.into_uncompressed().as_ref())
+}
+
+pub fn proof_to_words(proof: &Proof<Bn256>) -> Vec::<Word> {
+    let mut words = Vec::with_capacity(PROOF_LENGTH);
+    words.append(&mut g1_to_words(&proof.a));
+    words.append(&mut g2_to_words(&proof.b));
+    words.append(&mut g1_to_words(&proof.c));
+    words
+}
+
+pub fn words_to_proof(words: &[Word]) -> Result<Proof<Bn256>, AbiError> {
+    if words.len() != PROOF_LENGTH {
+        return Err(AbiError::InvalidLength { expected: PROOF_LENGTH, actual: words.len() });
+    }
+
+    let (a, rest) = words.split_at(G1_WORDS);
+    let (b, c) = rest.split_at(G2_WORDS);
+
+    let a = words_to_encoding::<G1Uncompressed>(a).into_affine()
+        .map_err(|_| AbiError::InvalidPoint { index: 0 })?;
+    let b = words_to_encoding::<G2Uncompressed>(b).into_affine()
+        .map_err(|_| AbiError::InvalidPoint { index: 1 })?;
+    let c = words_to_encoding::<G1Uncompressed>(c).into_affine()
+        .map_err(|_| AbiError::InvalidPoint { index: 2 })?;
+
+    Ok(Proof { a, b, c })
+}
+
+pub fn inputs_to_words(public_inputs: &[bn256::Fr]) -> Vec::<Word> {
+    public_inputs.iter().map(fr_to_word).collect()
+}
+
+pub fn words_to_inputs(words: &[Word]) -> Result<Vec::<bn256::Fr>, AbiError> {
+    words.iter().enumerate().map(|(index, word)| {
+        word_to_fr(word).ok_or(AbiError::InvalidFieldElement { index })
+    }).collect()
+}
+
+// calldata -------------------------------------------------------------------------
+
+pub fn verify_proof_selector() -> [u8; SELECTOR_BYTES] {
+    let mut hash = [0u8; WORD_BYTES];
+    let mut keccak = Keccak::v256();
+    keccak.update(VERIFY_PROOF_SIGNATURE.as_bytes());
+    keccak.finalize(&mut hash);
+
+    let mut selector = [0u8; SELECTOR_BYTES];
+    selector.copy_from_slice(&hash[..SELECTOR_BYTES]);
+    selector
+}
+
+// abi encoded (uint256[], uint256[]) arguments, without selector
+pub fn encode_arguments(
+    public_inputs: &[bn256::Fr],
+    proof: &Proof<Bn256>,
+) -> Vec::<u8> {
+    let inputs_offset = 2 * WORD_BYTES;
+    let proof_offset = inputs_offset + (1 + public_inputs.len()) * WORD_BYTES;
+
+    let mut words = vec![
+        usize_to_word(inputs_offset),
+        usize_to_word(proof_offset),
+        usize_to_word(public_inputs.len()),
+    ];
+    words.append(&mut inputs_to_words(public_inputs));
+    words.push(usize_to_word(PROOF_LENGTH));
+    words.append(&mut proof_to_words(proof));
+
+    words.concat()
+}
+
+pub fn encode_call(
+    public_inputs: &[bn256::Fr],
+    proof: &Proof<Bn256>,
+) -> Vec::<u8> {
+    let mut calldata = verify_proof_selector().to_vec();
+    calldata.append(&mut encode_arguments(public_inputs, proof));
+    calldata
+}
+
+fn read_word(data: &[u8], offset: usize) -> Result<&[u8], AbiError> {
+    match offset.checked_add(WORD_BYTES) {
+        Some(end) if end <= data.len() => Ok(&data[offset..end]),
+        _ => Err(AbiError::InvalidOffset { offset }),
+    }
+}
+
+fn read_array(data: &[u8], head_index: usize) -> Result<Vec::<Word>, AbiError> {
+    let offset = word_to_usize(read_word(data, head_index * WORD_BYTES)?)
+        .ok_or(AbiError::InvalidOffset { offset: head_index * WORD_BYTES })?;
+    let length = word_to_usize(read_word(data, offset)?)
+        .ok_or(AbiError::InvalidOffset { offset })?;
+
+    (0..length).map(|i| {
+        let position = (i + 1).checked_mul(WORD_BYTES)
+            .and_then(|shift| shift.checked_add(offset))
+            .ok_or(AbiError::InvalidOffset { offset })?;
+
+        let mut word = [0u8; WORD_BYTES];
+        word.copy_from_slice(read_word(data, position)?);
+        Ok(word)
+    }).collect()
+}
+
+pub fn decode_arguments(
+    data: &[u8],
+) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), AbiError> {
+    let public_inputs = words_to_inputs(&read_array(data, 0)?)?;
+    let proof = words_to_proof(&read_array(data, 1)?)?;
+
+    Ok((public_inputs, proof))
+}
+
+pub fn decode_call(
+    calldata: &[u8],
+) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), AbiError> {
+    if calldata.len() < SELECTOR_BYTES || calldata[..SELECTOR_BYTES] != verify_proof_selector() {
+        return Err(AbiError::InvalidSelector);
+    }
+
+    decode_arguments(&calldata[SELECTOR_BYTES..])
+}
+
+// hex & json -----------------------------------------------------------------------
+
+pub fn to_hex(data: &[u8]) -> String {
+    format!("0x{}", hex::encode(data))
+}
+
+pub fn from_hex(data: &str) -> Result<Vec::<u8>, AbiError> {
+    let data = data.trim_start_matches("0x");
+    hex::decode(data).map_err(|_| AbiError::InvalidHex)
+}
+
+fn json_array(words: &[Word]) -> String {
+    let quoted: Vec<_> = words.iter().map(|word| format!("\"{}\"", word_to_hex(word))).collect();
+    format!("[{}]", quoted.join(", "))
+}
+
+// arguments as uint arrays of hex strings, e.g. for web3 calls
+pub fn to_json(
+    public_inputs: &[bn256::Fr],
+    proof: &Proof<Bn256>,
+) -> String {
+    format!(
+        "{{\n  \"public_inputs\": {},\n  \"proof\": {}\n}}\n",
+        json_array(&inputs_to_words(public_inputs)),
+        json_array(&proof_to_words(proof)),
+    )
+}
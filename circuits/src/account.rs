@@ -7,20 +7,56 @@ use sapling_crypto_ce::{
     poseidon::{
         PoseidonEngine,
         QuinticSBox,
+        poseidon_hash,
     },
     jubjub::{
         JubjubEngine,
         edwards::Point,
         Unknown,
     },
+    circuit::{
+        num::AllocatedNum,
+        boolean::{ Boolean, AllocatedBit },
+    },
 };
 
+use ff_ce::Field;
+
 use super::utils::tree::{
     TreeCircuit,
     TreeState,
 };
+use super::utils::alloc::alloc_nums;
 
+// account leaf is [pubkey x, pubkey y, nonce, balances root], balances
+// root is the root of the account's balance subtree indexed by token id
 pub const ACCOUNT_LEAF_SIZE: usize = 4;
+pub const BALANCE_LEAF_SIZE: usize = 1;
+
+// fixed widths of leaf values, circuits range check amounts and updated
+// leaves against them so no value wraps around the field
+pub const BALANCE_BITS: usize = 128;
+pub const AMOUNT_BITS: usize = 128;
+pub const NONCE_BITS: usize = 32;
+
+// pubkey of an empty leaf, it has no known private key
+pub fn empty_pubkey<E: JubjubEngine>(
+    sign_params: &<E as JubjubEngine>::Params,
+) -> Point<E, Unknown> {
+    Point::<E, Unknown>::get_for_y(E::Fr::zero(), true, sign_params).unwrap()
+}
+
+// balances root of an empty leaf, all balances are zero
+pub fn empty_balances_root<E: PoseidonEngine>(
+    balance_depth: usize,
+    hash_params: &<E as PoseidonEngine>::Params,
+) -> E::Fr {
+    let mut root = poseidon_hash::<E>(hash_params, &[E::Fr::zero()])[0];
+    for _ in 0..balance_depth {
+        root = poseidon_hash::<E>(hash_params, &[root, root])[0];
+    }
+    root
+}
 
 #[derive(Clone)]
 pub struct AccountState<E: JubjubEngine> {
@@ -32,11 +68,14 @@ pub struct AccountState<E: JubjubEngine> {
     pub new_nonce: Option<E::Fr>,
     pub account_path: Vec::<Option<E::Fr>>,
     pub account_indices: Vec::<Option<bool>>,
+    pub balance_path: Vec::<Option<E::Fr>>,
+    pub balance_indices: Vec::<Option<bool>>,
 }
 
 #[derive(Clone)]
 pub struct AccountCircuit<'a, E: JubjubEngine + PoseidonEngine> {
     pub accounts_tree: TreeCircuit<'a, E>,
+    pub balances_tree: TreeCircuit<'a, E>,
 }
 
 impl<'a, E> AccountCircuit<'a, E>
@@ -45,6 +84,7 @@ impl<'a, E> AccountCircuit<'a, E>
     pub fn new<CS: ConstraintSystem<E>> (
         mut cs: CS,
         account_depth: usize,
+        balance_depth: usize,
         params: &'a <E as PoseidonEngine>::Params,
         state: &AccountState<E>,
     ) -> Result<Self, SynthesisError> {
@@ -65,39 +105,126 @@ impl<'a, E> AccountCircuit<'a, E>
             None => (None, None),
         };
 
-        let account_old_leaf = vec![
-            old_pubkey_x,
-            old_pubkey_y,
-            state.old_nonce.clone(),
-            state.old_balance.clone(),
-        ];
-
-        let account_new_leaf = vec![
-            new_pubkey_x,
-            new_pubkey_y,
-            state.new_nonce.clone(),
-            state.new_balance.clone(),
-        ];
-
-        let tree_state = TreeState {
-            old_leaf: account_old_leaf,
-            new_leaf: account_new_leaf,
-            path: state.account_path.clone(),
-            indices: state.account_indices.clone(),
+        // balance subtree, leaf index is the token id
+
+        let balance_state = TreeState {
+            old_leaf: vec![state.old_balance],
+            new_leaf: vec![state.new_balance],
+            path: state.balance_path.clone(),
+            indices: state.balance_indices.clone(),
         };
 
-        let accounts_tree = TreeCircuit::<'a, E>::new(
+        let balances_tree = TreeCircuit::<'a, E>::new(
+            cs.namespace(|| "allocate balances tree"),
+            BALANCE_LEAF_SIZE,
+            balance_depth,
+            params,
+            &balance_state,
+        )?;
+
+        let old_balances_root = balances_tree.calc_old_root(
+            cs.namespace(|| "calculate old balances root"),
+        )?;
+
+        let new_balances_root = balances_tree.calc_new_root(
+            cs.namespace(|| "calculate new balances root"),
+        )?;
+
+        // account leaf
+
+        let mut account_old_leaf = alloc_nums(
+            cs.namespace(|| "allocate old leaf"),
+            &[old_pubkey_x, old_pubkey_y, state.old_nonce],
+        )?;
+        account_old_leaf.push(old_balances_root);
+
+        let mut account_new_leaf = alloc_nums(
+            cs.namespace(|| "allocate new leaf"),
+            &[new_pubkey_x, new_pubkey_y, state.new_nonce],
+        )?;
+        account_new_leaf.push(new_balances_root);
+        assert_eq!(account_new_leaf.len(), ACCOUNT_LEAF_SIZE);
+
+        let accounts_tree = TreeCircuit::<'a, E>::with_leaves(
             cs.namespace(|| "allocate accounts tree"),
-            ACCOUNT_LEAF_SIZE,
             account_depth,
             params,
-            &tree_state,
+            account_old_leaf,
+            account_new_leaf,
+            &state.account_path,
+            &state.account_indices,
         )?;
 
         let circuit = AccountCircuit {
             accounts_tree,
+            balances_tree,
         };
 
         Ok(circuit)
     }
+
+    // Pubkey of the account is unchanged, unless the operation is addressed
+    // to the recipient pubkey (a deposit or a transfer by pubkey): then the
+    // account already has it, or the leaf was empty and the account is
+    // created with it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn check_recipient<CS: ConstraintSystem<E>> (
+        &self,
+        mut cs: CS,
+        recipient_x: &AllocatedNum<E>,
+        recipient_y: &AllocatedNum<E>,
+        by_pubkey: &Boolean,
+        new_account: &AllocatedBit,
+        balance_depth: usize,
+        hash_params: &<E as PoseidonEngine>::Params,
+        sign_params: &<E as JubjubEngine>::Params,
+    ) {
+        let old_leaf = &self.accounts_tree.old_leaf_alloc;
+        let new_leaf = &self.accounts_tree.new_leaf_alloc;
+        let (empty_x, empty_y) = empty_pubkey::<E>(sign_params).into_xy();
+        let empty_root = empty_balances_root::<E>(balance_depth, hash_params);
+        let by_pubkey = by_pubkey.lc(CS::one(), E::Fr::one());
+
+        cs.enforce(
+            || "new account only by pubkey",
+            |lc| lc + new_account.get_variable(),
+            |lc| lc + CS::one() - &by_pubkey,
+            |lc| lc,
+        );
+
+        for (i, recipient) in [recipient_x, recipient_y].iter().enumerate() {
+            cs.enforce(
+                || format!("check pubkey {} the same", i),
+                |lc| lc + new_leaf[i].get_variable() - old_leaf[i].get_variable(),
+                |lc| lc + CS::one() - &by_pubkey,
+                |lc| lc,
+            );
+
+            cs.enforce(
+                || format!("check new pubkey {} is recipient", i),
+                |lc| lc + new_leaf[i].get_variable() - recipient.get_variable(),
+                |lc| lc + &by_pubkey,
+                |lc| lc,
+            );
+
+            cs.enforce(
+                || format!("check old pubkey {} is recipient", i),
+                |lc| lc + old_leaf[i].get_variable() - recipient.get_variable(),
+                |lc| lc + &by_pubkey - new_account.get_variable(),
+                |lc| lc,
+            );
+        }
+
+        // new account takes an empty leaf
+
+        let empty_leaf = [empty_x, empty_y, E::Fr::zero(), empty_root];
+        for (i, value) in empty_leaf.iter().enumerate() {
+            cs.enforce(
+                || format!("check old leaf {} is empty", i),
+                |lc| lc + old_leaf[i].get_variable() - (*value, CS::one()),
+                |lc| lc + new_account.get_variable(),
+                |lc| lc,
+            );
+        }
+    }
 }
@@ -0,0 +1,800 @@
+use bellman_ce::{
+    Circuit,
+    ConstraintSystem,
+    SynthesisError,
+};
+
+use sapling_crypto_ce::{
+    jubjub::{
+        Unknown,
+        JubjubEngine,
+        FixedGenerators,
+        edwards::Point,
+    },
+    poseidon::{
+        PoseidonEngine,
+        QuinticSBox,
+    },
+    circuit::{
+        Assignment,
+        poseidon_hash::poseidon_hash,
+        num::AllocatedNum,
+        boolean::{ Boolean, AllocatedBit },
+    },
+    eddsa::{ Signature, PrivateKey, PublicKey },
+};
+
+use ff_ce::Field;
+use rand::{
+    Rng,
+    SeedableRng,
+    XorShiftRng,
+};
+
+use crate::utils::sign::verify_signature;
+
+use super::account::{ AccountState, AccountCircuit, BALANCE_BITS, AMOUNT_BITS, NONCE_BITS };
+use super::utils::calc::check_decomposition_le;
+use super::utils::commitment::expose_inputs;
+use super::fee_circuit::{ FeeCircuit, check_fee_token };
+
+const NUM_BYTES_TO_SIGN: usize = 31;
+
+// the padding key is public: the padding account never holds funds,
+// it only signs no-op requests used to fill partial batches
+const PADDING_KEY_SEED: [u32; 4] = [0x6f70_656e, 0x706c_6173, 0x6d61_7061, 0x6464_696e];
+
+pub fn padding_key<E: JubjubEngine>() -> E::Fs {
+    XorShiftRng::from_seed(PADDING_KEY_SEED).gen()
+}
+
+// padding pubkey is a constant of the block circuit, unsigned operations
+// must be signed by it
+pub fn padding_pubkey<E: JubjubEngine>(sign_params: &E::Params) -> PublicKey<E> {
+    PublicKey::from_private(
+        &PrivateKey::<E>(padding_key::<E>()),
+        FixedGenerators::SpendingKeyGenerator,
+        sign_params,
+    )
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperationType {
+    Noop,
+    Deposit,
+    Transfer,
+    OffchainWithdrawal,
+    OnchainWithdrawal,
+}
+
+// State chained from one block slot to the next
+#[derive(Clone)]
+pub struct BlockState<E: JubjubEngine> {
+    pub account_root: AllocatedNum<E>,
+    pub deposit_accum_hash: AllocatedNum<E>,
+    pub withdrawal_accum_hash: AllocatedNum<E>,
+}
+
+// One slot of a block. Every slot performs two account updates: `from` is
+// the deposit target, transfer sender or withdrawn account, `to` is the
+// transfer receiver and an unchanged leaf for all other operations. Both
+// updates touch the balance of the slot's token.
+//
+// Every slot verifies a signature over the request message. For transfers
+// and offchain withdrawals it must be made by the `from` account owner, for
+// other operations by the padding key fixed in the circuit.
+// Only transfers and offchain withdrawals may carry a fee. Transfers may be
+// addressed to a recipient pubkey, possibly creating the to account (see
+// `TransferCircuit`).
+#[derive(Clone)]
+pub struct BlockOperationCircuit<E: JubjubEngine + PoseidonEngine> {
+    pub op_type: Option::<OperationType>,
+    pub account_state_from: AccountState<E>,
+    pub account_state_to: AccountState<E>,
+    pub account_id_from: Option::<E::Fr>,
+    pub account_id_to: Option::<E::Fr>,
+    pub pubkey_to: Option::<Point<E, Unknown>>,
+    pub by_pubkey: Option::<bool>,
+    pub new_account_from: Option::<bool>,
+    pub new_account_to: Option::<bool>,
+    pub token_id: Option::<E::Fr>,
+    pub amount: Option::<E::Fr>,
+    pub fee: Option::<E::Fr>,
+    pub nonce: Option::<E::Fr>,
+    pub deposit_pubkey: Option::<Point<E, Unknown>>,
+    pub sign: Option::<Signature<E>>,
+    pub pubkey: Option::<Point<E, Unknown>>,
+}
+
+impl<E> BlockOperationCircuit<E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    fn alloc_flag<CS: ConstraintSystem<E>> (
+        &self,
+        cs: CS,
+        op_type: OperationType,
+    ) -> Result<AllocatedBit, SynthesisError> {
+        AllocatedBit::alloc(
+            cs,
+            self.op_type.map(|value| value == op_type),
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn process<'a, CS: ConstraintSystem<E>> (
+        &self,
+        mut cs: CS,
+        account_depth: usize,
+        balance_depth: usize,
+        hash_params: &'a <E as PoseidonEngine>::Params,
+        sign_params: &'a <E as JubjubEngine>::Params,
+        padding_pubkey: &PublicKey<E>,
+        fee_token_id: &AllocatedNum<E>,
+        old_state: &BlockState<E>,
+        fees: &mut Vec::<AllocatedNum<E>>,
+        inputs: &mut Vec::<AllocatedNum<E>>,
+    ) -> Result<BlockState<E>, SynthesisError> {
+
+        // allocate operation type ------------------------------------------------------
+
+        let is_deposit = self.alloc_flag(
+            cs.namespace(|| "allocate deposit flag"),
+            OperationType::Deposit,
+        )?;
+        let is_transfer = self.alloc_flag(
+            cs.namespace(|| "allocate transfer flag"),
+            OperationType::Transfer,
+        )?;
+        let is_offchain = self.alloc_flag(
+            cs.namespace(|| "allocate offchain withdrawal flag"),
+            OperationType::OffchainWithdrawal,
+        )?;
+        let is_onchain = self.alloc_flag(
+            cs.namespace(|| "allocate onchain withdrawal flag"),
+            OperationType::OnchainWithdrawal,
+        )?;
+
+        // no flags set means noop
+        cs.enforce(
+            || "at most one operation type",
+            |lc| lc + is_deposit.get_variable() + is_transfer.get_variable()
+                + is_offchain.get_variable() + is_onchain.get_variable(),
+            |lc| lc + is_deposit.get_variable() + is_transfer.get_variable()
+                + is_offchain.get_variable() + is_onchain.get_variable() - CS::one(),
+            |lc| lc,
+        );
+
+        // allocate variables ----------------------------------------------------------
+
+        let account_circuit_from = AccountCircuit::new(
+            cs.namespace(|| "allocate account circuit from"),
+            account_depth,
+            balance_depth,
+            hash_params,
+            &self.account_state_from,
+        )?;
+
+        let account_circuit_to = AccountCircuit::new(
+            cs.namespace(|| "allocate account circuit to"),
+            account_depth,
+            balance_depth,
+            hash_params,
+            &self.account_state_to,
+        )?;
+
+        let from_tree = &account_circuit_from.accounts_tree;
+        let to_tree = &account_circuit_to.accounts_tree;
+        let from_balances = &account_circuit_from.balances_tree;
+        let to_balances = &account_circuit_to.balances_tree;
+
+        let account_id_alloc_from = AllocatedNum::alloc(
+            cs.namespace(|| "allocate account id from"),
+            || self.account_id_from.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let account_id_alloc_to = AllocatedNum::alloc(
+            cs.namespace(|| "allocate account id to"),
+            || self.account_id_to.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let token_id_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate token id"),
+            || self.token_id.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let amount_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate amount"),
+            || self.amount.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let fee_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate fee"),
+            || self.fee.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+        fees.push(fee_alloc.clone());
+
+        let nonce_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate nonce"),
+            || self.nonce.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let (deposit_pubkey_x, deposit_pubkey_y) = match &self.deposit_pubkey {
+            Some(point) => {
+                let (x, y) = point.into_xy();
+                (Some(x), Some(y))
+            },
+            None => (None, None),
+        };
+
+        let deposit_pubkey_x_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate deposit pubkey x"),
+            || deposit_pubkey_x.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let deposit_pubkey_y_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate deposit pubkey y"),
+            || deposit_pubkey_y.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let (pubkey_to_x, pubkey_to_y) = match &self.pubkey_to {
+            Some(point) => {
+                let (x, y) = point.into_xy();
+                (Some(x), Some(y))
+            },
+            None => (None, None),
+        };
+
+        let pubkey_to_x_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate recipient pubkey x"),
+            || pubkey_to_x.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let pubkey_to_y_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate recipient pubkey y"),
+            || pubkey_to_y.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let by_pubkey = AllocatedBit::alloc(
+            cs.namespace(|| "allocate by pubkey flag"),
+            self.by_pubkey,
+        )?;
+
+        let new_account_from = AllocatedBit::alloc(
+            cs.namespace(|| "allocate new account from flag"),
+            self.new_account_from,
+        )?;
+
+        let new_account_to = AllocatedBit::alloc(
+            cs.namespace(|| "allocate new account to flag"),
+            self.new_account_to,
+        )?;
+
+        // check signature --------------------------------------------------------------
+
+        let transfer_hash = {
+            let hash_vec = poseidon_hash(
+                cs.namespace(|| "calculate transfer message hash"),
+                &[
+                    account_id_alloc_from.clone(),
+                    account_id_alloc_to.clone(),
+                    token_id_alloc.clone(),
+                    amount_alloc.clone(),
+                    fee_alloc.clone(),
+                    nonce_alloc.clone(),
+                ],
+                hash_params,
+            )?;
+            hash_vec[0].clone()
+        };
+
+        let pubkey_transfer_hash = {
+            let hash_vec = poseidon_hash(
+                cs.namespace(|| "calculate pubkey transfer message hash"),
+                &[
+                    account_id_alloc_from.clone(),
+                    token_id_alloc.clone(),
+                    amount_alloc.clone(),
+                    fee_alloc.clone(),
+                    nonce_alloc.clone(),
+                    pubkey_to_x_alloc.clone(),
+                    pubkey_to_y_alloc.clone(),
+                ],
+                hash_params,
+            )?;
+            hash_vec[0].clone()
+        };
+
+        let transfer_hash = AllocatedNum::conditionally_select(
+            cs.namespace(|| "select transfer message hash"),
+            &pubkey_transfer_hash,
+            &transfer_hash,
+            &Boolean::from(by_pubkey.clone()),
+        )?;
+
+        let withdrawal_hash = {
+            let hash_vec = poseidon_hash(
+                cs.namespace(|| "calculate withdrawal message hash"),
+                &[
+                    account_id_alloc_from.clone(),
+                    token_id_alloc.clone(),
+                    amount_alloc.clone(),
+                    fee_alloc.clone(),
+                    nonce_alloc.clone(),
+                ],
+                hash_params,
+            )?;
+            hash_vec[0].clone()
+        };
+
+        let message_hash = AllocatedNum::conditionally_select(
+            cs.namespace(|| "select message hash"),
+            &transfer_hash,
+            &withdrawal_hash,
+            &Boolean::from(is_transfer.clone()),
+        )?;
+
+        let sign_alloc = verify_signature(
+            cs.namespace(|| "verify signature"),
+            self.sign.clone(),
+            self.pubkey.clone(),
+            &message_hash,
+            NUM_BYTES_TO_SIGN,
+            sign_params,
+        )?;
+
+        // check changes validity -------------------------------------------------------
+
+        // signed operations are made by the from account owner, all others
+        // by the padding key
+
+        cs.enforce(
+            || "check signer pubkey x",
+            |lc| lc + sign_alloc.pk.get_x().get_variable() - from_tree.old_leaf_alloc[0].get_variable(),
+            |lc| lc + is_transfer.get_variable() + is_offchain.get_variable(),
+            |lc| lc,
+        );
+
+        cs.enforce(
+            || "check signer pubkey y",
+            |lc| lc + sign_alloc.pk.get_y().get_variable() - from_tree.old_leaf_alloc[1].get_variable(),
+            |lc| lc + is_transfer.get_variable() + is_offchain.get_variable(),
+            |lc| lc,
+        );
+
+        let (padding_x, padding_y) = padding_pubkey.0.into_xy();
+
+        cs.enforce(
+            || "check padding pubkey x",
+            |lc| lc + sign_alloc.pk.get_x().get_variable() - (padding_x, CS::one()),
+            |lc| lc + CS::one() - is_transfer.get_variable() - is_offchain.get_variable(),
+            |lc| lc,
+        );
+
+        cs.enforce(
+            || "check padding pubkey y",
+            |lc| lc + sign_alloc.pk.get_y().get_variable() - (padding_y, CS::one()),
+            |lc| lc + CS::one() - is_transfer.get_variable() - is_offchain.get_variable(),
+            |lc| lc,
+        );
+
+        // from pubkey is only changed by deposits creating the account,
+        // deposits to an account owned by another pubkey are rejected
+
+        account_circuit_from.check_recipient(
+            cs.namespace(|| "depositor consistence"),
+            &deposit_pubkey_x_alloc,
+            &deposit_pubkey_y_alloc,
+            &Boolean::from(is_deposit.clone()),
+            &new_account_from,
+            balance_depth,
+            hash_params,
+            sign_params,
+        );
+
+        // to account only gets its balance changed, or is created by a
+        // transfer addressed to a pubkey
+
+        cs.enforce(
+            || "by pubkey only for transfers",
+            |lc| lc + by_pubkey.get_variable(),
+            |lc| lc + CS::one() - is_transfer.get_variable(),
+            |lc| lc,
+        );
+
+        account_circuit_to.check_recipient(
+            cs.namespace(|| "recipient consistence"),
+            &pubkey_to_x_alloc,
+            &pubkey_to_y_alloc,
+            &Boolean::from(by_pubkey.clone()),
+            &new_account_to,
+            balance_depth,
+            hash_params,
+            sign_params,
+        );
+
+        cs.enforce(
+            || "check to nonce the same",
+            |lc| lc + to_tree.old_leaf_alloc[2].get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + to_tree.new_leaf_alloc[2].get_variable(),
+        );
+
+        // check account id consistency
+
+        check_decomposition_le(
+            cs.namespace(|| "account id from consistence"),
+            &account_id_alloc_from,
+            &from_tree.indices_alloc,
+        )?;
+
+        check_decomposition_le(
+            cs.namespace(|| "account id to consistence"),
+            &account_id_alloc_to,
+            &to_tree.indices_alloc,
+        )?;
+
+        // check token id consistency, both balances are of the same token
+
+        check_decomposition_le(
+            cs.namespace(|| "token id from consistence"),
+            &token_id_alloc,
+            &from_balances.indices_alloc,
+        )?;
+
+        check_decomposition_le(
+            cs.namespace(|| "token id to consistence"),
+            &token_id_alloc,
+            &to_balances.indices_alloc,
+        )?;
+
+        // check amount: deposit credits from, all other operations debit it
+
+        cs.enforce(
+            || "check amount from",
+            |lc| lc + amount_alloc.get_variable(),
+            |lc| lc + is_deposit.get_variable() - is_transfer.get_variable()
+                - is_offchain.get_variable() - is_onchain.get_variable(),
+            |lc| lc + from_balances.new_leaf_alloc[0].get_variable() - from_balances.old_leaf_alloc[0].get_variable()
+                + fee_alloc.get_variable(),
+        );
+
+        cs.enforce(
+            || "check amount to",
+            |lc| lc + amount_alloc.get_variable(),
+            |lc| lc + is_transfer.get_variable(),
+            |lc| lc + to_balances.new_leaf_alloc[0].get_variable() - to_balances.old_leaf_alloc[0].get_variable(),
+        );
+
+        // fee is paid in the fee token by signed operations only
+
+        cs.enforce(
+            || "check fee only for signed operations",
+            |lc| lc + fee_alloc.get_variable(),
+            |lc| lc + CS::one() - is_transfer.get_variable() - is_offchain.get_variable(),
+            |lc| lc,
+        );
+
+        check_fee_token(
+            cs.namespace(|| "check fee token"),
+            &fee_alloc,
+            &token_id_alloc,
+            fee_token_id,
+        );
+
+        // onchain withdrawal takes all value
+
+        cs.enforce(
+            || "check onchain withdrawal takes all value",
+            |lc| lc + from_balances.new_leaf_alloc[0].get_variable(),
+            |lc| lc + is_onchain.get_variable(),
+            |lc| lc,
+        );
+
+        // check amounts range, balances for underflow and overflow
+
+        amount_alloc.limit_number_of_bits(
+            cs.namespace(|| "check amount range"),
+            AMOUNT_BITS,
+        )?;
+
+        fee_alloc.limit_number_of_bits(
+            cs.namespace(|| "check fee range"),
+            AMOUNT_BITS,
+        )?;
+
+        from_balances.new_leaf_alloc[0].limit_number_of_bits(
+            cs.namespace(|| "check from balance overflow"),
+            BALANCE_BITS,
+        )?;
+
+        to_balances.new_leaf_alloc[0].limit_number_of_bits(
+            cs.namespace(|| "check to balance overflow"),
+            BALANCE_BITS,
+        )?;
+
+        // check nonce: signed operations carry the next nonce and bump it
+
+        cs.enforce(
+            || "nonce consistence",
+            |lc| lc + nonce_alloc.get_variable() - from_tree.old_leaf_alloc[2].get_variable() - CS::one(),
+            |lc| lc + is_transfer.get_variable() + is_offchain.get_variable(),
+            |lc| lc,
+        );
+
+        cs.enforce(
+            || "check nonce bump",
+            |lc| lc + CS::one(),
+            |lc| lc + is_transfer.get_variable() + is_offchain.get_variable(),
+            |lc| lc + from_tree.new_leaf_alloc[2].get_variable() - from_tree.old_leaf_alloc[2].get_variable(),
+        );
+
+        nonce_alloc.limit_number_of_bits(
+            cs.namespace(|| "check nonce range"),
+            NONCE_BITS,
+        )?;
+
+        // calculate new accumulated hashes ---------------------------------------------
+
+        let deposit_hash = {
+            let hashes_vec = poseidon_hash(
+                cs.namespace(|| "calculate deposit accum hash"),
+                &[
+                    old_state.deposit_accum_hash.clone(),
+                    deposit_pubkey_x_alloc,
+                    deposit_pubkey_y_alloc,
+                    account_id_alloc_from.clone(),
+                    token_id_alloc.clone(),
+                    amount_alloc.clone(),
+                ],
+                hash_params,
+            )?;
+            hashes_vec[0].clone()
+        };
+
+        let deposit_accum_hash = AllocatedNum::conditionally_select(
+            cs.namespace(|| "select deposit accum hash"),
+            &deposit_hash,
+            &old_state.deposit_accum_hash,
+            &Boolean::from(is_deposit),
+        )?;
+
+        let withdrawal_hash = {
+            let hashes_vec = poseidon_hash(
+                cs.namespace(|| "calculate withdrawal accum hash"),
+                &[
+                    old_state.withdrawal_accum_hash.clone(),
+                    account_id_alloc_from.clone(),
+                    token_id_alloc.clone(),
+                ],
+                hash_params,
+            )?;
+            hashes_vec[0].clone()
+        };
+
+        let withdrawal_accum_hash = AllocatedNum::conditionally_select(
+            cs.namespace(|| "select withdrawal accum hash"),
+            &withdrawal_hash,
+            &old_state.withdrawal_accum_hash,
+            &Boolean::from(is_onchain.clone()),
+        )?;
+
+        // input withdrawal, zeros for other operations ---------------------------------
+
+        let withdrawn_account_id = AllocatedNum::alloc(
+            cs.namespace(|| "allocate withdrawn account id"),
+            || {
+                let is_withdrawal = is_offchain.get_value().get()? | is_onchain.get_value().get()?;
+                match is_withdrawal {
+                    true => account_id_alloc_from.get_value().ok_or(SynthesisError::AssignmentMissing),
+                    false => Ok(E::Fr::zero()),
+                }
+            },
+        )?;
+        inputs.push(withdrawn_account_id.clone());
+
+        cs.enforce(
+            || "check withdrawn account id",
+            |lc| lc + account_id_alloc_from.get_variable(),
+            |lc| lc + is_offchain.get_variable() + is_onchain.get_variable(),
+            |lc| lc + withdrawn_account_id.get_variable(),
+        );
+
+        let withdrawn_token_id = AllocatedNum::alloc(
+            cs.namespace(|| "allocate withdrawn token id"),
+            || {
+                let is_withdrawal = is_offchain.get_value().get()? | is_onchain.get_value().get()?;
+                match is_withdrawal {
+                    true => token_id_alloc.get_value().ok_or(SynthesisError::AssignmentMissing),
+                    false => Ok(E::Fr::zero()),
+                }
+            },
+        )?;
+        inputs.push(withdrawn_token_id.clone());
+
+        cs.enforce(
+            || "check withdrawn token id",
+            |lc| lc + token_id_alloc.get_variable(),
+            |lc| lc + is_offchain.get_variable() + is_onchain.get_variable(),
+            |lc| lc + withdrawn_token_id.get_variable(),
+        );
+
+        let withdrawn_amount = AllocatedNum::alloc(
+            cs.namespace(|| "allocate withdrawn amount"),
+            || {
+                let is_withdrawal = is_offchain.get_value().get()? | is_onchain.get_value().get()?;
+                match is_withdrawal {
+                    true => amount_alloc.get_value().ok_or(SynthesisError::AssignmentMissing),
+                    false => Ok(E::Fr::zero()),
+                }
+            },
+        )?;
+        inputs.push(withdrawn_amount.clone());
+
+        cs.enforce(
+            || "check withdrawn amount",
+            |lc| lc + amount_alloc.get_variable(),
+            |lc| lc + is_offchain.get_variable() + is_onchain.get_variable(),
+            |lc| lc + withdrawn_amount.get_variable(),
+        );
+
+        // verify old root & calculate new root -----------------------------------------
+
+        from_tree.verify_old_root(
+            cs.namespace(|| "verify from old root"),
+            &old_state.account_root,
+        )?;
+
+        let root = from_tree.calc_new_root(
+            cs.namespace(|| "calculate from new root"),
+        )?;
+
+        to_tree.verify_old_root(
+            cs.namespace(|| "verify to old root"),
+            &root,
+        )?;
+
+        let account_root = to_tree.calc_new_root(
+            cs.namespace(|| "calculate to new root"),
+        )?;
+
+        Ok(BlockState {
+            account_root,
+            deposit_accum_hash,
+            withdrawal_accum_hash,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct BlockCircuit<'a, E: JubjubEngine + PoseidonEngine> {
+    pub block_size: usize,
+    pub account_depth: usize,
+    pub balance_depth: usize,
+    pub hash_params: &'a <E as PoseidonEngine>::Params,
+    pub sign_params: &'a <E as JubjubEngine>::Params,
+    pub commit_inputs: bool,
+
+    pub operations: Vec::<BlockOperationCircuit<E>>,
+    pub fee: FeeCircuit<E>,
+    pub old_account_root: Option::<E::Fr>,
+    pub new_account_root: Option::<E::Fr>,
+    pub old_deposit_accum_hash: Option::<E::Fr>,
+    pub new_deposit_accum_hash: Option::<E::Fr>,
+    pub old_withdrawal_accum_hash: Option::<E::Fr>,
+    pub new_withdrawal_accum_hash: Option::<E::Fr>,
+}
+
+impl<'a, E> Circuit<E> for BlockCircuit<'a, E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    fn synthesize<CS: ConstraintSystem<E>> (
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        assert_eq!(self.block_size, self.operations.len());
+
+        let mut inputs = Vec::new();
+
+        let old_root = AllocatedNum::alloc(
+            cs.namespace(|| "allocate old root"),
+            || self.old_account_root.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+        inputs.push(old_root.clone());
+
+        let new_root = AllocatedNum::alloc(
+            cs.namespace(|| "allocate new root"),
+            || self.new_account_root.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+        inputs.push(new_root.clone());
+
+        let old_deposit_hash = AllocatedNum::alloc(
+            cs.namespace(|| "allocate old deposit accum hash"),
+            || self.old_deposit_accum_hash.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+        inputs.push(old_deposit_hash.clone());
+
+        let new_deposit_hash = AllocatedNum::alloc(
+            cs.namespace(|| "allocate new deposit accum hash"),
+            || self.new_deposit_accum_hash.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+        inputs.push(new_deposit_hash.clone());
+
+        let old_withdrawal_hash = AllocatedNum::alloc(
+            cs.namespace(|| "allocate old withdrawal accum hash"),
+            || self.old_withdrawal_accum_hash.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+        inputs.push(old_withdrawal_hash.clone());
+
+        let new_withdrawal_hash = AllocatedNum::alloc(
+            cs.namespace(|| "allocate new withdrawal accum hash"),
+            || self.new_withdrawal_accum_hash.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+        inputs.push(new_withdrawal_hash.clone());
+
+        let mut state = BlockState {
+            account_root: old_root,
+            deposit_accum_hash: old_deposit_hash,
+            withdrawal_accum_hash: old_withdrawal_hash,
+        };
+
+        let fee_token_id = self.fee.alloc_token_id(
+            cs.namespace(|| "allocate fee token id"),
+        )?;
+        let mut fees = Vec::with_capacity(self.block_size);
+        let padding_pubkey = padding_pubkey::<E>(self.sign_params);
+
+        for (i, operation) in self.operations.iter().enumerate() {
+            state = operation.process(
+                cs.namespace(|| format!("verify operation {}", i)),
+                self.account_depth,
+                self.balance_depth,
+                self.hash_params,
+                self.sign_params,
+                &padding_pubkey,
+                &fee_token_id,
+                &state,
+                &mut fees,
+                &mut inputs,
+            )?;
+        }
+
+        state.account_root = self.fee.process(
+            cs.namespace(|| "credit fees"),
+            self.account_depth,
+            self.balance_depth,
+            self.hash_params,
+            &fee_token_id,
+            &fees,
+            &state.account_root,
+        )?;
+
+        cs.enforce(
+            || "enforce new root equivalence",
+            |lc| lc + state.account_root.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + new_root.get_variable(),
+        );
+
+        cs.enforce(
+            || "enforce new deposit accum hash equivalence",
+            |lc| lc + state.deposit_accum_hash.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + new_deposit_hash.get_variable(),
+        );
+
+        cs.enforce(
+            || "enforce new withdrawal accum hash equivalence",
+            |lc| lc + state.withdrawal_accum_hash.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + new_withdrawal_hash.get_variable(),
+        );
+
+        expose_inputs(
+            cs.namespace(|| "expose public inputs"),
+            &inputs,
+            self.commit_inputs,
+        )?;
+
+        Ok(())
+    }
+}
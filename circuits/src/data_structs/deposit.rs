@@ -4,62 +4,80 @@ use pairing_ce::bn256::Bn256;
 use crate::account::AccountState;
 
 use super::super::{
-    tree::account::AccountsTree,
+    tree::account::{ AccountsTree, TreeError },
 };
 
 use crate::utils::utils::{
     optionalize,
-    fr_to_usize,
-    usize_to_fr,
+    u128_to_fr,
 };
 
 #[derive(Clone)]
 pub struct Deposit {
     pub pubkey: Option::<PublicKey::<Bn256>>,
     pub account_id: usize,
-    pub amount: usize,
+    pub token_id: usize,
+    pub amount: u128,
 }
 
 impl Deposit {
     pub fn update_tree_and_record_state(
         &self,
         tree: &mut AccountsTree,
-    ) -> AccountState::<Bn256> {
-        assert!(self.account_id < tree.accounts.len());
+    ) -> Result<AccountState::<Bn256>, TreeError> {
+        if self.account_id >= tree.num_accounts() {
+            return Err(TreeError::UnknownAccount { account_id: self.account_id });
+        }
+        if self.token_id >= tree.num_tokens() {
+            return Err(TreeError::UnknownToken { token_id: self.token_id });
+        }
+        let new_pubkey = self.pubkey.clone().ok_or(
+            TreeError::InvalidRequest { account_id: self.account_id }
+        )?;
 
         // count balances
-        let old_balance = tree.accounts[self.account_id].balance;
-        let new_balance = usize_to_fr(fr_to_usize(old_balance) + self.amount);
+        let old_balance = tree.get_balance(self.account_id, self.token_id)?;
+        let new_balance = old_balance.checked_add(self.amount).ok_or(
+            TreeError::AmountOverflow {
+                account_id: self.account_id,
+                balance: old_balance,
+                amount: self.amount,
+            }
+        )?;
 
         // prepare paths, indices, pubkeys, nonces
-        let old_pubkey = tree.accounts[self.account_id].pubkey.clone();
-        let nonce = tree.accounts[self.account_id].nonce;
-        let new_pubkey = self.pubkey.clone().unwrap();
+        let old_pubkey = tree.get_account(self.account_id)?.pubkey.clone();
+        let nonce = tree.get_account(self.account_id)?.nonce;
         let account_path = tree.accounts_tree.get_leaf_path(self.account_id);
         let account_indices = tree.accounts_tree.get_leaf_indices(self.account_id);
+        let balance_path = tree.get_balance_path(self.account_id, self.token_id)?;
+        let balance_indices = tree.get_balance_indices(self.account_id, self.token_id)?;
 
         // update balance & account
         tree.update_balance(
             self.account_id,
+            self.token_id,
             new_balance,
-        );
+        )?;
 
         tree.update_account(
             self.account_id,
             new_pubkey.clone(),
             nonce,
-        );
+        )?;
 
         // record account state
-        AccountState::<Bn256> {
-            old_balance: Some(old_balance),
-            new_balance: Some(new_balance),
+        Ok(AccountState::<Bn256> {
+            old_balance: Some(u128_to_fr(old_balance)),
+            new_balance: Some(u128_to_fr(new_balance)),
             old_pubkey: Some(old_pubkey.0),
             new_pubkey: Some(new_pubkey.0),
             old_nonce: Some(nonce),
             new_nonce: Some(nonce),
             account_path: optionalize(account_path),
             account_indices: optionalize(account_indices),
-        }
+            balance_path: optionalize(balance_path),
+            balance_indices: optionalize(balance_indices),
+        })
     }
 }
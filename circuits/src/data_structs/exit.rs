@@ -0,0 +1,158 @@
+use crate::account::AccountState;
+use sapling_crypto_ce::eddsa::Signature;
+
+use super::super::{
+    tree::account::{ AccountsTree, TreeError },
+};
+
+use crate::utils::utils::{
+    usize_to_fr,
+    u128_to_fr,
+    fr_to_bytes_le,
+};
+
+use sapling_crypto_ce::{
+    eddsa::{
+        PrivateKey,
+        PublicKey,
+    },
+    poseidon::{
+        poseidon_hash,
+        bn256::Bn256PoseidonParams,
+    },
+    jubjub::FixedGenerators,
+    alt_babyjubjub::AltJubjubBn256,
+};
+
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use rand::thread_rng;
+
+pub const NUM_BYTES_TO_SIGN: usize = 31;
+
+// first element of the signed message, an exit signature can't be taken
+// for another request of the same account
+pub const EXIT_DOMAIN: usize = 0x6578_6974;
+
+// Exodus exit: the account owner claims the whole balance of a token
+// against the last committed root when the operator is unresponsive. The
+// recipient address and the root are signed, so a proof can't be replayed
+// to pay someone else or against another root.
+#[derive(Clone)]
+pub struct Exit {
+    pub account_id: usize,
+    pub token_id: usize,
+    pub amount: u128,
+    pub recipient: bn256::Fr,
+    pub root: bn256::Fr,
+    pub sign: Option<Signature::<Bn256>>,
+}
+
+impl Exit {
+
+    pub fn hash(
+        &self,
+        hash_params: &Bn256PoseidonParams
+    ) -> bn256::Fr {
+        let request = vec![
+            usize_to_fr(EXIT_DOMAIN),
+            self.root,
+            usize_to_fr(self.account_id),
+            usize_to_fr(self.token_id),
+            u128_to_fr(self.amount),
+            self.recipient,
+        ];
+
+        let hash_vec = poseidon_hash::<Bn256>(hash_params, &request);
+        hash_vec[0]
+    }
+
+    // hash of the account pubkey, exposed by the exit proof
+    pub fn pubkey_hash(
+        pubkey: &PublicKey::<Bn256>,
+        hash_params: &Bn256PoseidonParams,
+    ) -> bn256::Fr {
+        let (pubkey_x, pubkey_y) = pubkey.0.into_xy();
+        poseidon_hash::<Bn256>(hash_params, &[pubkey_x, pubkey_y])[0]
+    }
+
+    pub fn sign(
+        &mut self,
+        seckey: &PrivateKey::<Bn256>,
+        hash_params: &Bn256PoseidonParams,
+        sign_params: &AltJubjubBn256,
+    ) {
+        let hash = self.hash(hash_params);
+        let hash_bytes: Vec<_> = fr_to_bytes_le(hash, NUM_BYTES_TO_SIGN);
+        let mut rng = thread_rng();
+
+        let sign = seckey.sign_raw_message(
+            &hash_bytes,
+            &mut rng,
+            FixedGenerators::SpendingKeyGenerator,
+            sign_params,
+            NUM_BYTES_TO_SIGN,
+        );
+
+        self.sign = Some(sign);
+    }
+
+    pub fn verify_signature(
+        &self,
+        pubkey: &PublicKey::<Bn256>,
+        hash_params: &Bn256PoseidonParams,
+        sign_params: &AltJubjubBn256,
+    ) -> bool {
+        let sign = match &self.sign {
+            Some(sign) => sign,
+            None => return false,
+        };
+
+        let hash = self.hash(hash_params);
+        let hash_bytes: Vec<_> = fr_to_bytes_le(hash, NUM_BYTES_TO_SIGN);
+
+        pubkey.verify_for_raw_message(
+            &hash_bytes,
+            sign,
+            FixedGenerators::SpendingKeyGenerator,
+            sign_params,
+            NUM_BYTES_TO_SIGN,
+        )
+    }
+
+    // exit leaves the tree unchanged, the state proves the claimed balance
+    pub fn record_state(
+        &self,
+        tree: &AccountsTree,
+        hash_params: &Bn256PoseidonParams,
+        sign_params: &AltJubjubBn256,
+    ) -> Result<AccountState::<Bn256>, TreeError> {
+        if self.account_id >= tree.num_accounts() {
+            return Err(TreeError::UnknownAccount { account_id: self.account_id });
+        }
+        if self.token_id >= tree.num_tokens() {
+            return Err(TreeError::UnknownToken { token_id: self.token_id });
+        }
+        if self.root != tree.get_root() {
+            return Err(TreeError::RootMismatch { expected: tree.get_root(), found: self.root });
+        }
+
+        let balance = tree.get_balance(self.account_id, self.token_id)?;
+        if balance != self.amount {
+            return Err(TreeError::BalanceMismatch {
+                account_id: self.account_id,
+                balance,
+                amount: self.amount,
+            });
+        }
+
+        if !self.verify_signature(&tree.get_pubkey(self.account_id)?, hash_params, sign_params) {
+            return Err(TreeError::InvalidSignature { account_id: self.account_id });
+        }
+
+        tree.record_account_state(self.account_id, self.token_id)
+    }
+}
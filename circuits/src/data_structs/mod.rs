@@ -2,3 +2,4 @@ pub mod transfer;
 pub mod deposit;
 pub mod onchain_withdrawal;
 pub mod offchain_withdrawal;
+pub mod exit;
@@ -2,13 +2,13 @@ use crate::account::AccountState;
 use sapling_crypto_ce::eddsa::Signature;
 
 use super::super::{
-    tree::account::AccountsTree,
+    tree::account::{ AccountsTree, TreeError },
 };
 
 use crate::utils::utils::{
     optionalize,
-    fr_to_usize,
     usize_to_fr,
+    u128_to_fr,
     fr_to_bytes_le,
 };
 
@@ -37,7 +37,9 @@ pub const NUM_BYTES_TO_SIGN: usize = 31;
 #[derive(Clone)]
 pub struct OffchainWithdrawal {
     pub account_id: usize,
-    pub amount: usize,
+    pub token_id: usize,
+    pub amount: u128,
+    pub fee: u128,
     pub nonce: usize,
     pub sign: Option<Signature::<Bn256>>,
 }
@@ -50,7 +52,9 @@ impl OffchainWithdrawal {
     ) -> bn256::Fr {
         let request = vec![
             usize_to_fr(self.account_id),
-            usize_to_fr(self.amount),
+            usize_to_fr(self.token_id),
+            u128_to_fr(self.amount),
+            u128_to_fr(self.fee),
             usize_to_fr(self.nonce),
         ];
     
@@ -85,12 +89,17 @@ impl OffchainWithdrawal {
         hash_params: &Bn256PoseidonParams,
         sign_params: &AltJubjubBn256,
     ) -> bool {
+        let sign = match &self.sign {
+            Some(sign) => sign,
+            None => return false,
+        };
+
         let hash = self.hash(hash_params);
         let hash_bytes: Vec<_> = fr_to_bytes_le(hash, NUM_BYTES_TO_SIGN);
 
         pubkey.verify_for_raw_message(
             &hash_bytes,
-            &self.sign.clone().unwrap(),
+            sign,
             FixedGenerators::SpendingKeyGenerator,
             sign_params,
             NUM_BYTES_TO_SIGN,
@@ -100,47 +109,66 @@ impl OffchainWithdrawal {
     pub fn update_tree_and_record_state(
         &self,
         tree: &mut AccountsTree,
-    ) -> AccountState::<Bn256> {
-        assert!(self.account_id < tree.accounts.len());
+    ) -> Result<AccountState::<Bn256>, TreeError> {
+        if self.account_id >= tree.num_accounts() {
+            return Err(TreeError::UnknownAccount { account_id: self.account_id });
+        }
+        if self.token_id >= tree.num_tokens() {
+            return Err(TreeError::UnknownToken { token_id: self.token_id });
+        }
 
         // count balances
-        let old_balance = tree.accounts[self.account_id].balance;
-        let new_balance = {
-            let old_balance = fr_to_usize(old_balance);
-            assert!(old_balance >= self.amount);
-            usize_to_fr(old_balance - self.amount)
-        };
+        let old_balance = tree.get_balance(self.account_id, self.token_id)?;
+        let new_balance = self.amount.checked_add(self.fee)
+            .and_then(|amount| old_balance.checked_sub(amount))
+            .ok_or(TreeError::InsufficientBalance {
+                account_id: self.account_id,
+                balance: old_balance,
+                amount: self.amount.saturating_add(self.fee),
+            })?;
 
         // prepare paths, indices, pubkeys, nonces
-        let pubkey = tree.accounts[self.account_id].pubkey.clone();
-        let old_nonce = tree.accounts[self.account_id].nonce;
-        assert!(fr_to_usize(old_nonce) == self.nonce - 1);
+        let pubkey = tree.get_account(self.account_id)?.pubkey.clone();
+        let old_nonce = tree.get_account(self.account_id)?.nonce;
+        let expected_nonce = tree.get_nonce_value(self.account_id)? + 1;
+        if self.nonce != expected_nonce {
+            return Err(TreeError::NonceMismatch {
+                account_id: self.account_id,
+                expected: expected_nonce,
+                actual: self.nonce,
+            });
+        }
         let new_nonce = usize_to_fr(self.nonce);
         let account_path = tree.accounts_tree.get_leaf_path(self.account_id);
         let account_indices = tree.accounts_tree.get_leaf_indices(self.account_id);
+        let balance_path = tree.get_balance_path(self.account_id, self.token_id)?;
+        let balance_indices = tree.get_balance_indices(self.account_id, self.token_id)?;
 
         // update balance
         tree.update_balance(
             self.account_id,
+            self.token_id,
             new_balance,
-        );
+        )?;
         
         tree.update_account(
             self.account_id,
-            tree.accounts[self.account_id].pubkey.clone(),
+            pubkey.clone(),
             new_nonce,
-        );
+        )?;
 
         // record account state
-        AccountState::<Bn256> {
-            old_balance: Some(old_balance),
-            new_balance: Some(new_balance),
+        Ok(AccountState::<Bn256> {
+            old_balance: Some(u128_to_fr(old_balance)),
+            new_balance: Some(u128_to_fr(new_balance)),
             old_pubkey: Some(pubkey.0.clone()),
             new_pubkey: Some(pubkey.0),
             old_nonce: Some(old_nonce),
             new_nonce: Some(new_nonce),
             account_path: optionalize(account_path),
             account_indices: optionalize(account_indices),
-        }
+            balance_path: optionalize(balance_path),
+            balance_indices: optionalize(balance_indices),
+        })
     }
 }
\ No newline at end of file
@@ -3,54 +3,65 @@ use pairing_ce::bn256::Bn256;
 use crate::account::AccountState;
 
 use super::super::{
-    tree::account::AccountsTree,
+    tree::account::{ AccountsTree, TreeError },
 };
 
 use crate::utils::utils::{
     optionalize,
-    usize_to_fr,
+    u128_to_fr,
 };
 
 #[derive(Clone)]
 pub struct OnchainWithdrawal {
     pub account_id: usize,
-    pub amount: Option<usize>,
+    pub token_id: usize,
+    pub amount: Option<u128>,
 }
 
 impl OnchainWithdrawal {
     pub fn update_tree_and_record_state(
         &self,
         tree: &mut AccountsTree,
-    ) -> AccountState::<Bn256> {
-        assert!(self.account_id < tree.accounts.len());
+    ) -> Result<AccountState::<Bn256>, TreeError> {
+        if self.account_id >= tree.num_accounts() {
+            return Err(TreeError::UnknownAccount { account_id: self.account_id });
+        }
+        if self.token_id >= tree.num_tokens() {
+            return Err(TreeError::UnknownToken { token_id: self.token_id });
+        }
 
         // count balances
-        let old_balance = tree.accounts[self.account_id].balance;
+        let old_balance = tree.get_balance(self.account_id, self.token_id)?;
         // onchain withdrawal takes all asset's value
-        let new_balance = usize_to_fr(0);
+        let new_balance = 0;
 
         // prepare paths, indices, pubkeys, nonces
-        let pubkey = tree.accounts[self.account_id].pubkey.clone();
-        let nonce = tree.accounts[self.account_id].nonce;
+        let pubkey = tree.get_account(self.account_id)?.pubkey.clone();
+        let nonce = tree.get_account(self.account_id)?.nonce;
         let account_path = tree.accounts_tree.get_leaf_path(self.account_id);
         let account_indices = tree.accounts_tree.get_leaf_indices(self.account_id);
+        let balance_path = tree.get_balance_path(self.account_id, self.token_id)?;
+        let balance_indices = tree.get_balance_indices(self.account_id, self.token_id)?;
 
         // update balance
         tree.update_balance(
             self.account_id,
+            self.token_id,
             new_balance,
-        );
+        )?;
 
         // record account state
-        AccountState::<Bn256> {
-            old_balance: Some(old_balance),
-            new_balance: Some(new_balance),
+        Ok(AccountState::<Bn256> {
+            old_balance: Some(u128_to_fr(old_balance)),
+            new_balance: Some(u128_to_fr(new_balance)),
             old_pubkey: Some(pubkey.0.clone()),
             new_pubkey: Some(pubkey.0),
             old_nonce: Some(nonce),
             new_nonce: Some(nonce),
             account_path: optionalize(account_path),
             account_indices: optionalize(account_indices),
-        }
+            balance_path: optionalize(balance_path),
+            balance_indices: optionalize(balance_indices),
+        })
     }
 }
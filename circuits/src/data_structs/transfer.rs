@@ -2,13 +2,13 @@ use crate::account::AccountState;
 use sapling_crypto_ce::eddsa::Signature;
 
 use super::super::{
-    tree::account::AccountsTree,
+    tree::account::{ AccountsTree, TreeError },
 };
 
 use crate::utils::utils::{
     optionalize,
-    fr_to_usize,
     usize_to_fr,
+    u128_to_fr,
     fr_to_bytes_le,
 };
 
@@ -33,11 +33,16 @@ use pairing_ce::{
 use rand::thread_rng;
 
 
+// Transfer to `pubkey_to` if set: the operator picks `account_id_to`, the
+// account holding the pubkey or an empty one that is created
 #[derive(Clone)]
 pub struct Transfer {
     pub account_id_from: usize,
     pub account_id_to: usize,
-    pub amount: usize,
+    pub pubkey_to: Option<PublicKey::<Bn256>>,
+    pub token_id: usize,
+    pub amount: u128,
+    pub fee: u128,
     pub nonce: usize,
     pub sign: Option<Signature::<Bn256>>,
 }
@@ -50,12 +55,28 @@ impl Transfer {
         & self, 
         hash_params: &Bn256PoseidonParams
     ) -> bn256::Fr {
-        let request = vec![
-            usize_to_fr(self.account_id_from),
-            usize_to_fr(self.account_id_to),
-            usize_to_fr(self.amount),
-            usize_to_fr(self.nonce),
-        ];
+        let request = match &self.pubkey_to {
+            Some(pubkey) => {
+                let (pubkey_x, pubkey_y) = pubkey.0.into_xy();
+                vec![
+                    usize_to_fr(self.account_id_from),
+                    usize_to_fr(self.token_id),
+                    u128_to_fr(self.amount),
+                    u128_to_fr(self.fee),
+                    usize_to_fr(self.nonce),
+                    pubkey_x,
+                    pubkey_y,
+                ]
+            },
+            None => vec![
+                usize_to_fr(self.account_id_from),
+                usize_to_fr(self.account_id_to),
+                usize_to_fr(self.token_id),
+                u128_to_fr(self.amount),
+                u128_to_fr(self.fee),
+                usize_to_fr(self.nonce),
+            ],
+        };
     
         let hash_vec = poseidon_hash::<Bn256>(hash_params, &request);
         hash_vec[0]
@@ -88,12 +109,17 @@ impl Transfer {
         hash_params: &Bn256PoseidonParams,
         sign_params: &AltJubjubBn256,
     ) -> bool {
+        let sign = match &self.sign {
+            Some(sign) => sign,
+            None => return false,
+        };
+
         let hash = self.hash(hash_params);
         let hash_bytes: Vec<_> = fr_to_bytes_le(hash, NUM_BYTES_TO_SIGN);
 
         pubkey.verify_for_raw_message(
             &hash_bytes,
-            &self.sign.clone().unwrap(),
+            sign,
             FixedGenerators::SpendingKeyGenerator,
             sign_params,
             NUM_BYTES_TO_SIGN,
@@ -103,82 +129,129 @@ impl Transfer {
     pub fn update_tree_and_record_state(
         & self,
         tree: &mut AccountsTree,
-    ) -> (AccountState::<Bn256>, AccountState::<Bn256>) {
-        
-        assert!(self.account_id_from < tree.accounts.len());
-        assert!(self.account_id_to < tree.accounts.len());
+    ) -> Result<(AccountState::<Bn256>, AccountState::<Bn256>), TreeError> {
+        if self.account_id_from >= tree.num_accounts() {
+            return Err(TreeError::UnknownAccount { account_id: self.account_id_from });
+        }
+        if self.account_id_to >= tree.num_accounts() {
+            return Err(TreeError::UnknownAccount { account_id: self.account_id_to });
+        }
+        if self.token_id >= tree.num_tokens() {
+            return Err(TreeError::UnknownToken { token_id: self.token_id });
+        }
+
+        // check credit before touching the tree, a self-transfer can't overflow
+        if self.account_id_from != self.account_id_to {
+            let balance = tree.get_balance(self.account_id_to, self.token_id)?;
+            if balance.checked_add(self.amount).is_none() {
+                return Err(TreeError::AmountOverflow {
+                    account_id: self.account_id_to,
+                    balance,
+                    amount: self.amount,
+                });
+            }
+        }
 
         // account from ------------------------------------------------------------
 
         // count balances
-        let old_balance = tree.accounts[self.account_id_from].balance;
-        let new_balance = {
-            let old_balance = fr_to_usize(old_balance);
-            assert!(old_balance >= self.amount);
-            usize_to_fr(old_balance - self.amount)
-        };
+        let old_balance = tree.get_balance(self.account_id_from, self.token_id)?;
+        let new_balance = self.amount.checked_add(self.fee)
+            .and_then(|amount| old_balance.checked_sub(amount))
+            .ok_or(TreeError::InsufficientBalance {
+                account_id: self.account_id_from,
+                balance: old_balance,
+                amount: self.amount.saturating_add(self.fee),
+            })?;
 
         // prepare paths, indices, pubkeys, nonces
-        let pubkey = tree.accounts[self.account_id_from].pubkey.clone();
-        let old_nonce = tree.accounts[self.account_id_from].nonce;
-        assert!(fr_to_usize(old_nonce) == self.nonce - 1);
+        let pubkey = tree.get_account(self.account_id_from)?.pubkey.clone();
+        let old_nonce = tree.get_account(self.account_id_from)?.nonce;
+        let expected_nonce = tree.get_nonce_value(self.account_id_from)? + 1;
+        if self.nonce != expected_nonce {
+            return Err(TreeError::NonceMismatch {
+                account_id: self.account_id_from,
+                expected: expected_nonce,
+                actual: self.nonce,
+            });
+        }
         let new_nonce = usize_to_fr(self.nonce);
         let account_path = tree.accounts_tree.get_leaf_path(self.account_id_from);
         let account_indices = tree.accounts_tree.get_leaf_indices(self.account_id_from);
+        let balance_path = tree.get_balance_path(self.account_id_from, self.token_id)?;
+        let balance_indices = tree.get_balance_indices(self.account_id_from, self.token_id)?;
 
         // update balance
         tree.update_balance(
             self.account_id_from,
+            self.token_id,
             new_balance,
-        );
+        )?;
 
         tree.update_nonce(
             self.account_id_from,
             new_nonce,
-        );
+        )?;
 
         // record account state
         let account_state_from = AccountState::<Bn256> {
-            old_balance: Some(old_balance),
-            new_balance: Some(new_balance),
+            old_balance: Some(u128_to_fr(old_balance)),
+            new_balance: Some(u128_to_fr(new_balance)),
             old_pubkey: Some(pubkey.0.clone()),
             new_pubkey: Some(pubkey.0),
             old_nonce: Some(old_nonce),
             new_nonce: Some(new_nonce),
             account_path: optionalize(account_path),
             account_indices: optionalize(account_indices),
+            balance_path: optionalize(balance_path),
+            balance_indices: optionalize(balance_indices),
         };
 
         // account to --------------------------------------------------------------
 
+        // recorded after the from update, so a self-transfer opens the leaf
+        // as debited above and only pays the fee and bumps the nonce
+
         // count balances
-        let old_balance = tree.accounts[self.account_id_to].balance;
-        let new_balance = usize_to_fr(fr_to_usize(old_balance) + self.amount);
+        let old_balance = tree.get_balance(self.account_id_to, self.token_id)?;
+        let new_balance = old_balance + self.amount;
 
         // prepare paths, indices, pubkeys, nonces
-        let pubkey = tree.accounts[self.account_id_to].pubkey.clone();
-        let nonce = tree.accounts[self.account_id_to].nonce;
+        let old_pubkey = tree.get_account(self.account_id_to)?.pubkey.clone();
+        let new_pubkey = self.pubkey_to.clone().unwrap_or_else(|| old_pubkey.clone());
+        let nonce = tree.get_account(self.account_id_to)?.nonce;
         let account_path = tree.accounts_tree.get_leaf_path(self.account_id_to);
         let account_indices = tree.accounts_tree.get_leaf_indices(self.account_id_to);
+        let balance_path = tree.get_balance_path(self.account_id_to, self.token_id)?;
+        let balance_indices = tree.get_balance_indices(self.account_id_to, self.token_id)?;
 
-        // update balance
+        // update balance, new account gets its pubkey
         tree.update_balance(
             self.account_id_to,
+            self.token_id,
             new_balance,
-        );
+        )?;
+
+        tree.update_account(
+            self.account_id_to,
+            new_pubkey.clone(),
+            nonce,
+        )?;
 
         // record account state
         let account_state_to = AccountState::<Bn256> {
-            old_balance: Some(old_balance),
-            new_balance: Some(new_balance),
-            old_pubkey: Some(pubkey.0.clone()),
-            new_pubkey: Some(pubkey.0),
+            old_balance: Some(u128_to_fr(old_balance)),
+            new_balance: Some(u128_to_fr(new_balance)),
+            old_pubkey: Some(old_pubkey.0),
+            new_pubkey: Some(new_pubkey.0),
             old_nonce: Some(nonce),
             new_nonce: Some(nonce),
             account_path: optionalize(account_path),
             account_indices: optionalize(account_indices),
+            balance_path: optionalize(balance_path),
+            balance_indices: optionalize(balance_indices),
         };
 
-        (account_state_from, account_state_to)
+        Ok((account_state_from, account_state_to))
     }
 }
@@ -16,29 +16,42 @@ use sapling_crypto_ce::{
     },
     circuit::{
         num::AllocatedNum,
+        boolean::{ Boolean, AllocatedBit },
         poseidon_hash::poseidon_hash,
     },
 };
 
-use super::account::{ AccountState, AccountCircuit };
+use ff_ce::Field;
+
+use super::account::{ AccountState, AccountCircuit, BALANCE_BITS, AMOUNT_BITS };
 use super::utils::calc::check_decomposition_le;
+use super::utils::commitment::expose_inputs;
 
 #[derive(Clone)]
 pub struct DepositCircuit<E: JubjubEngine + PoseidonEngine> {
     pub account_state: AccountState<E>,
     pub pubkey: Option::<Point<E, Unknown>>,
     pub account_id: Option::<E::Fr>,
+    pub token_id: Option::<E::Fr>,
     pub amount: Option::<E::Fr>,
+    // deposit to an empty leaf creates the account, otherwise the leaf
+    // must already be owned by the deposit pubkey
+    pub new_account: Option::<bool>,
+    // padding deposits fill partial batches and are not accumulated
+    pub padding: Option::<bool>,
 }
 
 impl<E> DepositCircuit<E>
     where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
 {
-    pub fn process_deposit<'a, CS: ConstraintSystem<E>> (
+    #[allow(clippy::too_many_arguments)]
+    pub fn process_deposit<CS: ConstraintSystem<E>> (
         &self,
         mut cs: CS,
         account_depth: usize,
-        hash_params: &'a <E as PoseidonEngine>::Params,
+        balance_depth: usize,
+        hash_params: &<E as PoseidonEngine>::Params,
+        sign_params: &<E as JubjubEngine>::Params,
         old_hash: &AllocatedNum<E>,
         old_root: &AllocatedNum<E>,
     ) -> Result<(AllocatedNum<E>, AllocatedNum<E>), SynthesisError> {
@@ -46,6 +59,7 @@ impl<E> DepositCircuit<E>
         let account_circuit = AccountCircuit::new(
             cs.namespace(|| "allocate account circuit"),
             account_depth,
+            balance_depth,
             hash_params,
             &self.account_state,
         )?;
@@ -73,25 +87,37 @@ impl<E> DepositCircuit<E>
             || self.account_id.ok_or(SynthesisError::AssignmentMissing),
         )?;
 
+        let token_id_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate token id"),
+            || self.token_id.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
         let amount_alloc = AllocatedNum::alloc(
             cs.namespace(|| "allocate amount"),
             || self.amount.ok_or(SynthesisError::AssignmentMissing),
         )?;
 
-        // check pubkey consistence
+        let padding_alloc = Boolean::from(AllocatedBit::alloc(
+            cs.namespace(|| "allocate padding flag"),
+            self.padding,
+        )?);
 
-        cs.enforce(
-            || "check pubkey x consistence",
-            |lc| lc + pubkey_x_alloc.get_variable(),
-            |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[0].get_variable(),
-        );
+        let new_account_alloc = AllocatedBit::alloc(
+            cs.namespace(|| "allocate new account flag"),
+            self.new_account,
+        )?;
 
-        cs.enforce(
-            || "check pubkey y consistence",
-            |lc| lc + pubkey_y_alloc.get_variable(),
-            |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[1].get_variable(),
+        // check pubkey consistence, deposit never takes an owned account
+
+        account_circuit.check_recipient(
+            cs.namespace(|| "check pubkey consistence"),
+            &pubkey_x_alloc,
+            &pubkey_y_alloc,
+            &Boolean::constant(true),
+            &new_account_alloc,
+            balance_depth,
+            hash_params,
+            sign_params,
         );
 
         // check account id, asset id consistency
@@ -102,14 +128,57 @@ impl<E> DepositCircuit<E>
             &account_circuit.accounts_tree.indices_alloc,
         )?;
 
+        check_decomposition_le(
+            cs.namespace(|| "token id consistence"),
+            &token_id_alloc,
+            &account_circuit.balances_tree.indices_alloc,
+        )?;
+
         // check amount deposit
 
         cs.enforce(
             || "check amount deposit",
-            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[3].get_variable()
+            |lc| lc + account_circuit.balances_tree.old_leaf_alloc[0].get_variable()
                     + amount_alloc.get_variable(),
             |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[3].get_variable(),
+            |lc| lc + account_circuit.balances_tree.new_leaf_alloc[0].get_variable(),
+        );
+
+        // check amount range, balance for overflow
+
+        amount_alloc.limit_number_of_bits(
+            cs.namespace(|| "check amount range"),
+            AMOUNT_BITS,
+        )?;
+
+        account_circuit.balances_tree.new_leaf_alloc[0].limit_number_of_bits(
+            cs.namespace(|| "check balance overflow"),
+            BALANCE_BITS,
+        )?;
+
+        // check padding deposit is a no-op
+
+        cs.enforce(
+            || "check padding amount is zero",
+            |lc| lc + amount_alloc.get_variable(),
+            |_| padding_alloc.lc(CS::one(), E::Fr::one()),
+            |lc| lc,
+        );
+
+        cs.enforce(
+            || "check padding keeps pubkey x",
+            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[0].get_variable()
+                    - pubkey_x_alloc.get_variable(),
+            |_| padding_alloc.lc(CS::one(), E::Fr::one()),
+            |lc| lc,
+        );
+
+        cs.enforce(
+            || "check padding keeps pubkey y",
+            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[1].get_variable()
+                    - pubkey_y_alloc.get_variable(),
+            |_| padding_alloc.lc(CS::one(), E::Fr::one()),
+            |lc| lc,
         );
 
         // check nonce the same
@@ -121,9 +190,9 @@ impl<E> DepositCircuit<E>
             |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[2].get_variable(),
         );
 
-        // calculate new hash
+        // calculate new hash, padding keeps the old one
 
-        let new_hash = {
+        let deposit_hash = {
             let hashes_vec = poseidon_hash(
                 cs.namespace(|| "calculate new accum hash"),
                 &[
@@ -131,6 +200,7 @@ impl<E> DepositCircuit<E>
                     pubkey_x_alloc,
                     pubkey_y_alloc,
                     account_id_alloc,
+                    token_id_alloc,
                     amount_alloc,
                 ],
                 hash_params,
@@ -138,6 +208,13 @@ impl<E> DepositCircuit<E>
             hashes_vec[0].clone()
         };
 
+        let new_hash = AllocatedNum::conditionally_select(
+            cs.namespace(|| "select new accum hash"),
+            old_hash,
+            &deposit_hash,
+            &padding_alloc,
+        )?;
+
         // verify old root & calculate new root
 
         account_circuit.accounts_tree.verify_old_root(
@@ -157,7 +234,10 @@ impl<E> DepositCircuit<E>
 pub struct DepositBatchCircuit<'a, E: JubjubEngine + PoseidonEngine> {
     pub deposit_batch: usize,
     pub account_depth: usize,
+    pub balance_depth: usize,
     pub hash_params: &'a <E as PoseidonEngine>::Params,
+    pub sign_params: &'a <E as JubjubEngine>::Params,
+    pub commit_inputs: bool,
 
     pub deposit_queue: Vec::<DepositCircuit<E>>,
     pub old_accum_hash: Option::<E::Fr>,
@@ -175,35 +255,39 @@ impl<'a, E> Circuit<E> for DepositBatchCircuit<'a, E>
     ) -> Result<(), SynthesisError> {
         assert_eq!(self.deposit_batch, self.deposit_queue.len());
 
+        let mut inputs = Vec::new();
+
         let mut prev_hash = AllocatedNum::alloc(
             cs.namespace(|| "allocate old accum hash"),
             || self.old_accum_hash.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        prev_hash.inputize(cs.namespace(|| "input old accum hash"))?;
+        inputs.push(prev_hash.clone());
 
         let new_hash = AllocatedNum::alloc(
             cs.namespace(|| "allocate new accum hash"),
             || self.new_accum_hash.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        new_hash.inputize(cs.namespace(|| "input new accum hash"))?;
+        inputs.push(new_hash.clone());
 
         let mut prev_root = AllocatedNum::alloc(
             cs.namespace(|| "allocate old root"),
             || self.old_account_root.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        prev_root.inputize(cs.namespace(|| "input old root"))?;
+        inputs.push(prev_root.clone());
 
         let new_root = AllocatedNum::alloc(
             cs.namespace(|| "allocate new root"),
             || self.new_account_root.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        new_root.inputize(cs.namespace(|| "input new root"))?;
+        inputs.push(new_root.clone());
 
         for (i, deposit) in self.deposit_queue.iter().enumerate() {
             let (hash, root) = deposit.process_deposit(
                 cs.namespace(|| format!("verify deposit {}", i)),
                 self.account_depth,
+                self.balance_depth,
                 self.hash_params,
+                self.sign_params,
                 &prev_hash,
                 &prev_root,
             )?;
@@ -226,6 +310,12 @@ impl<'a, E> Circuit<E> for DepositBatchCircuit<'a, E>
             |lc| lc + new_root.get_variable(),
         );
 
+        expose_inputs(
+            cs.namespace(|| "expose public inputs"),
+            &inputs,
+            self.commit_inputs,
+        )?;
+
         Ok(())
     }
 }
@@ -0,0 +1,338 @@
+use std::path::Path;
+
+use bellman_ce::{
+    Circuit,
+    ConstraintSystem,
+    SynthesisError,
+    groth16::{
+        Proof,
+        Parameters,
+        create_random_proof,
+    },
+};
+
+use sapling_crypto_ce::{
+    jubjub::{
+        Unknown,
+        JubjubEngine,
+        edwards::Point,
+    },
+    poseidon::{
+        PoseidonEngine,
+        QuinticSBox,
+        bn256::Bn256PoseidonParams,
+    },
+    circuit::{
+        poseidon_hash::poseidon_hash,
+        num::AllocatedNum,
+    },
+    alt_babyjubjub::AltJubjubBn256,
+    eddsa::{ Signature, PublicKey },
+};
+
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use ff_ce::PrimeField;
+
+use rand::thread_rng;
+
+use crate::utils::sign::verify_signature;
+use crate::utils::utils::{ usize_to_fr, u128_to_fr };
+use crate::utils::commitment::calc_commitment;
+use crate::data_structs::exit::{ Exit, NUM_BYTES_TO_SIGN, EXIT_DOMAIN };
+use crate::tree::account::AccountsTree;
+use crate::operator::OperatorError;
+use crate::verifier::verify_batch_proof;
+use crate::storage::load_state;
+
+use super::account::{ AccountState, AccountCircuit, BALANCE_BITS };
+use super::utils::calc::check_decomposition_le;
+use super::utils::commitment::expose_inputs;
+use super::offchain_withdrawal_circuit::OffchainWithdrawalCircuit;
+
+// Exodus exit: proves that the account with the signing pubkey holds
+// `amount` of the token under `root`, the tree is left unchanged. Public
+// inputs are root, account id, token id, amount, recipient and the hash of
+// the account pubkey, so the contract can release the balance against its
+// last committed root to the address the owner signed for.
+#[derive(Clone)]
+pub struct ExitCircuit<'a, E: JubjubEngine + PoseidonEngine> {
+    pub account_depth: usize,
+    pub balance_depth: usize,
+    pub hash_params: &'a <E as PoseidonEngine>::Params,
+    pub sign_params: &'a <E as JubjubEngine>::Params,
+    pub commit_inputs: bool,
+
+    pub account_state: AccountState<E>,
+    pub root: Option::<E::Fr>,
+    pub account_id: Option::<E::Fr>,
+    pub token_id: Option::<E::Fr>,
+    pub amount: Option::<E::Fr>,
+    pub recipient: Option::<E::Fr>,
+    pub sign: Option::<Signature<E>>,
+    pub pubkey: Option::<Point<E, Unknown>>,
+}
+
+// recipient is an L1 address
+pub const ADDRESS_BITS: usize = 160;
+
+impl<'a, E> Circuit<E> for ExitCircuit<'a, E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    fn synthesize<CS: ConstraintSystem<E>> (
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        let mut inputs = Vec::new();
+
+        // allocate variables -----------------------------------------------------------
+
+        let root_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate root"),
+            || self.root.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+        inputs.push(root_alloc.clone());
+
+        let account_circuit = AccountCircuit::new(
+            cs.namespace(|| "allocate account circuit"),
+            self.account_depth,
+            self.balance_depth,
+            self.hash_params,
+            &self.account_state,
+        )?;
+
+        let account_id_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate account id"),
+            || self.account_id.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+        inputs.push(account_id_alloc.clone());
+
+        let token_id_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate token id"),
+            || self.token_id.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+        inputs.push(token_id_alloc.clone());
+
+        let amount_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate amount"),
+            || self.amount.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+        inputs.push(amount_alloc.clone());
+
+        let recipient_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate recipient"),
+            || self.recipient.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+        inputs.push(recipient_alloc.clone());
+
+        let domain = E::Fr::from_str(&EXIT_DOMAIN.to_string()).unwrap();
+        let domain_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate domain tag"),
+            || Ok(domain),
+        )?;
+
+        cs.enforce(
+            || "check domain tag",
+            |lc| lc + domain_alloc.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + (domain, CS::one()),
+        );
+
+        // check signature --------------------------------------------------------------
+
+        let exit_hash = {
+            let hash_vec = poseidon_hash(
+                cs.namespace(|| "calculate message hash"),
+                &[
+                    domain_alloc,
+                    root_alloc.clone(),
+                    account_id_alloc.clone(),
+                    token_id_alloc.clone(),
+                    amount_alloc.clone(),
+                    recipient_alloc.clone(),
+                ],
+                self.hash_params,
+            )?;
+            hash_vec[0].clone()
+        };
+
+        let sign_alloc = verify_signature(
+            cs.namespace(|| "verify signature"),
+            self.sign.clone(),
+            self.pubkey.clone(),
+            &exit_hash,
+            NUM_BYTES_TO_SIGN,
+            self.sign_params,
+        )?;
+
+        // check account ----------------------------------------------------------------
+
+        OffchainWithdrawalCircuit::check_pubkey(
+            cs.namespace(|| "public key consistence"),
+            &sign_alloc.pk,
+            &account_circuit,
+        );
+
+        let pubkey_hash = {
+            let hash_vec = poseidon_hash(
+                cs.namespace(|| "calculate pubkey hash"),
+                &[
+                    sign_alloc.pk.get_x().clone(),
+                    sign_alloc.pk.get_y().clone(),
+                ],
+                self.hash_params,
+            )?;
+            hash_vec[0].clone()
+        };
+        inputs.push(pubkey_hash);
+
+        check_decomposition_le(
+            cs.namespace(|| "account id consistence"),
+            &account_id_alloc,
+            &account_circuit.accounts_tree.indices_alloc,
+        )?;
+
+        check_decomposition_le(
+            cs.namespace(|| "token id consistence"),
+            &token_id_alloc,
+            &account_circuit.balances_tree.indices_alloc,
+        )?;
+
+        // amount is the whole balance, the leaf is left unchanged
+
+        cs.enforce(
+            || "check amount is balance",
+            |lc| lc + account_circuit.balances_tree.old_leaf_alloc[0].get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + amount_alloc.get_variable(),
+        );
+
+        cs.enforce(
+            || "check balance unchanged",
+            |lc| lc + account_circuit.balances_tree.old_leaf_alloc[0].get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + account_circuit.balances_tree.new_leaf_alloc[0].get_variable(),
+        );
+
+        cs.enforce(
+            || "check nonce unchanged",
+            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[2].get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[2].get_variable(),
+        );
+
+        amount_alloc.limit_number_of_bits(
+            cs.namespace(|| "check amount range"),
+            BALANCE_BITS,
+        )?;
+
+        recipient_alloc.limit_number_of_bits(
+            cs.namespace(|| "check recipient range"),
+            ADDRESS_BITS,
+        )?;
+
+        // verify root ------------------------------------------------------------------
+
+        account_circuit.accounts_tree.verify_old_root(
+            cs.namespace(|| "verify root"),
+            &root_alloc,
+        )?;
+
+        expose_inputs(
+            cs.namespace(|| "expose public inputs"),
+            &inputs,
+            self.commit_inputs,
+        )?;
+
+        Ok(())
+    }
+}
+
+// witness helpers ----------------------------------------------------------------------
+
+impl<'a> ExitCircuit<'a, Bn256> {
+    // exit against the root of the tree, the exit must be signed by the account key
+    pub fn from_tree(
+        tree: &AccountsTree,
+        exit: &Exit,
+        hash_params: &'a Bn256PoseidonParams,
+        sign_params: &'a AltJubjubBn256,
+        commit_inputs: bool,
+    ) -> Result<Self, OperatorError> {
+        let account_state = exit.record_state(tree, hash_params, sign_params)?;
+
+        Ok(ExitCircuit {
+            account_depth: tree.account_depth,
+            balance_depth: tree.balance_depth,
+            hash_params,
+            sign_params,
+            commit_inputs,
+            account_state,
+            root: Some(tree.get_root()),
+            account_id: Some(usize_to_fr(exit.account_id)),
+            token_id: Some(usize_to_fr(exit.token_id)),
+            amount: Some(u128_to_fr(exit.amount)),
+            recipient: Some(exit.recipient),
+            sign: exit.sign.clone(),
+            pubkey: Some(tree.get_pubkey(exit.account_id)?.0),
+        })
+    }
+
+    // public inputs as the verifier expects them
+    pub fn public_inputs(&self) -> Vec::<bn256::Fr> {
+        let pubkey = PublicKey::<Bn256>(self.pubkey.clone().unwrap());
+        let public_inputs = vec![
+            self.root.unwrap(),
+            self.account_id.unwrap(),
+            self.token_id.unwrap(),
+            self.amount.unwrap(),
+            self.recipient.unwrap(),
+            Exit::pubkey_hash(&pubkey, self.hash_params),
+        ];
+
+        if self.commit_inputs {
+            return vec![calc_commitment(&public_inputs)];
+        }
+
+        public_inputs
+    }
+}
+
+pub fn prove_exit(
+    tree: &AccountsTree,
+    exit: &Exit,
+    exit_circuit_params: &Parameters::<Bn256>,
+    hash_params: &Bn256PoseidonParams,
+    sign_params: &AltJubjubBn256,
+    commit_inputs: bool,
+) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
+    let circuit = ExitCircuit::from_tree(tree, exit, hash_params, sign_params, commit_inputs)?;
+    let public_inputs = circuit.public_inputs();
+
+    let proof = create_random_proof(circuit, exit_circuit_params, &mut thread_rng())?;
+
+    // check proof before publishing it
+    verify_batch_proof(&exit_circuit_params.vk, &public_inputs, &proof)?;
+
+    Ok((public_inputs, proof))
+}
+
+// exit against a state saved by the operator, e.g. at the last committed block
+#[allow(clippy::too_many_arguments)]
+pub fn prove_exit_from_state<P: AsRef<Path>>(
+    path: P,
+    account_depth: usize,
+    balance_depth: usize,
+    exit: &Exit,
+    exit_circuit_params: &Parameters::<Bn256>,
+    hash_params: &Bn256PoseidonParams,
+    sign_params: &AltJubjubBn256,
+    commit_inputs: bool,
+) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
+    let state = load_state(path, account_depth, balance_depth, hash_params, sign_params)?;
+
+    prove_exit(&state.tree, exit, exit_circuit_params, hash_params, sign_params, commit_inputs)
+}
@@ -0,0 +1,133 @@
+use bellman_ce::{
+    ConstraintSystem,
+    SynthesisError,
+};
+
+use sapling_crypto_ce::{
+    poseidon::{
+        PoseidonEngine,
+        QuinticSBox,
+    },
+    jubjub::JubjubEngine,
+    circuit::num::AllocatedNum,
+};
+
+use super::account::{ AccountState, AccountCircuit, BALANCE_BITS };
+use super::utils::calc::check_decomposition_le;
+
+
+// Credit of the fees collected by a batch to the fee account. Fees are only
+// paid in the fee token, so the batch ends with a single account update.
+#[derive(Clone)]
+pub struct FeeCircuit<E: JubjubEngine + PoseidonEngine> {
+    pub account_state: AccountState<E>,
+    pub account_id: Option::<E::Fr>,
+    pub token_id: Option::<E::Fr>,
+}
+
+impl<E> FeeCircuit<E>
+    where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
+{
+    pub fn alloc_token_id<CS: ConstraintSystem<E>> (
+        &self,
+        mut cs: CS,
+    ) -> Result<AllocatedNum<E>, SynthesisError> {
+        AllocatedNum::alloc(
+            cs.namespace(|| "allocate fee token id"),
+            || self.token_id.ok_or(SynthesisError::AssignmentMissing),
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn process<CS: ConstraintSystem<E>> (
+        &self,
+        mut cs: CS,
+        account_depth: usize,
+        balance_depth: usize,
+        hash_params: &<E as PoseidonEngine>::Params,
+        token_id: &AllocatedNum<E>,
+        fees: &[AllocatedNum<E>],
+        old_root: &AllocatedNum<E>,
+    ) -> Result<AllocatedNum<E>, SynthesisError> {
+        let account_circuit = AccountCircuit::new(
+            cs.namespace(|| "allocate fee account circuit"),
+            account_depth,
+            balance_depth,
+            hash_params,
+            &self.account_state,
+        )?;
+
+        let account_id_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate fee account id"),
+            || self.account_id.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        // check account id, token id consistency
+
+        check_decomposition_le(
+            cs.namespace(|| "fee account id consistence"),
+            &account_id_alloc,
+            &account_circuit.accounts_tree.indices_alloc,
+        )?;
+
+        check_decomposition_le(
+            cs.namespace(|| "fee token id consistence"),
+            token_id,
+            &account_circuit.balances_tree.indices_alloc,
+        )?;
+
+        // check fee account is only credited
+
+        for i in 0..3 {
+            cs.enforce(
+                || format!("check fee account leaf {} the same", i),
+                |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[i].get_variable(),
+                |lc| lc + CS::one(),
+                |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[i].get_variable(),
+            );
+        }
+
+        cs.enforce(
+            || "check collected fees",
+            |lc| fees.iter().fold(
+                lc + account_circuit.balances_tree.old_leaf_alloc[0].get_variable(),
+                |lc, fee| lc + fee.get_variable(),
+            ),
+            |lc| lc + CS::one(),
+            |lc| lc + account_circuit.balances_tree.new_leaf_alloc[0].get_variable(),
+        );
+
+        account_circuit.balances_tree.new_leaf_alloc[0].limit_number_of_bits(
+            cs.namespace(|| "check fee balance overflow"),
+            BALANCE_BITS,
+        )?;
+
+        // verify old root & calculate new root
+
+        account_circuit.accounts_tree.verify_old_root(
+            cs.namespace(|| "verify old root"),
+            old_root,
+        )?;
+
+        account_circuit.accounts_tree.calc_new_root(
+            cs.namespace(|| "calculate new root"),
+        )
+    }
+}
+
+// fees are paid in the fee token only, other requests carry zero fee
+pub fn check_fee_token<E, CS> (
+    mut cs: CS,
+    fee: &AllocatedNum<E>,
+    token_id: &AllocatedNum<E>,
+    fee_token_id: &AllocatedNum<E>,
+) where E: JubjubEngine,
+        CS: ConstraintSystem<E>,
+{
+    cs.enforce(
+        || "check fee token",
+        |lc| lc + fee.get_variable(),
+        |lc| lc + token_id.get_variable() - fee_token_id.get_variable(),
+        |lc| lc,
+    );
+}
@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+
+use ff_ce::PrimeField;
+
+use pairing_ce::bn256;
+
+use crate::storage::BatchRecord;
+
+// Chain of executed batches and blocks ordered by number. A restored operator
+// may start its history after the first block, numbers are kept as recorded.
+#[derive(Clone, Default)]
+pub struct BlockHistory {
+    pub blocks: Vec<BatchRecord>,
+    // new root to position of the block that first produced it
+    roots: HashMap::<[u64; 4], usize>,
+}
+
+fn root_key(root: &bn256::Fr) -> [u64; 4] {
+    root.into_repr().0
+}
+
+impl BlockHistory {
+    pub fn new() -> Self {
+        BlockHistory {
+            blocks: Vec::new(),
+            roots: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn push(&mut self, block: BatchRecord) {
+        self.roots.entry(root_key(&block.new_root)).or_insert(self.blocks.len());
+        self.blocks.push(block);
+    }
+
+    pub fn latest(&self) -> Option<&BatchRecord> {
+        self.blocks.last()
+    }
+
+    pub fn get_block(&self, number: usize) -> Option<&BatchRecord> {
+        self.blocks
+            .binary_search_by_key(&number, |block| block.batch_number)
+            .ok()
+            .map(|index| &self.blocks[index])
+    }
+
+    // block after which the tree had the given root
+    pub fn find_block_by_root(&self, root: &bn256::Fr) -> Option<&BatchRecord> {
+        self.roots.get(&root_key(root)).map(|index| &self.blocks[*index])
+    }
+
+    // transfers and offchain withdrawals are identified by sender and nonce
+
+    pub fn find_transfer(&self, account_id_from: usize, nonce: usize) -> Option<&BatchRecord> {
+        self.blocks.iter().find(|block| {
+            block.transfers.iter().any(|transfer| {
+                transfer.account_id_from == account_id_from && transfer.nonce == nonce
+            })
+        })
+    }
+
+    pub fn find_offchain_withdrawal(&self, account_id: usize, nonce: usize) -> Option<&BatchRecord> {
+        self.blocks.iter().find(|block| {
+            block.offchain_withdrawals.iter().any(|withdrawal| {
+                withdrawal.account_id == account_id && withdrawal.nonce == nonce
+            })
+        })
+    }
+}
@@ -0,0 +1,684 @@
+use std::fmt;
+use std::io::{ self, Read, Write };
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+
+use bellman_ce::{
+    SynthesisError,
+    groth16::{
+        Parameters,
+        VerifyingKey,
+        generate_random_parameters,
+    },
+};
+
+use sapling_crypto_ce::{
+    poseidon::bn256::Bn256PoseidonParams,
+    alt_babyjubjub::AltJubjubBn256,
+};
+
+use pairing_ce::bn256::Bn256;
+
+use rand::thread_rng;
+
+use crate::{
+    account::AccountState,
+    deposit_circuit::{ DepositCircuit, DepositBatchCircuit },
+    onchain_withdrawal_circuit::{ OnchainWithdrawalCircuit, OnchainWithdrawalBatchCircuit },
+    offchain_withdrawal_circuit::{ OffchainWithdrawalCircuit, OffchainWithdrawalBatchCircuit },
+    transfer_circuit::{ TransferCircuit, TransferBatchCircuit },
+    block_circuit::{ BlockOperationCircuit, BlockCircuit },
+    exit_circuit::ExitCircuit,
+    fee_circuit::FeeCircuit,
+};
+
+// Key files start with a header describing the circuit the key was
+// generated for, keys are only loaded for the circuit shape they match:
+//
+//   magic (4 bytes) | version (1) | circuit type (1) | batch size (u32 BE)
+//   | account depth (u32 BE) | balance depth (u32 BE) | commit inputs (1)
+
+const KEY_MAGIC: [u8; 4] = *b"OPKY";
+const KEY_VERSION: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitType {
+    Deposit,
+    Transfer,
+    OffchainWithdrawal,
+    OnchainWithdrawal,
+    Block,
+    Exit,
+}
+
+impl CircuitType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CircuitType::Deposit => "deposit",
+            CircuitType::Transfer => "transfer",
+            CircuitType::OffchainWithdrawal => "offchain_withdrawal",
+            CircuitType::OnchainWithdrawal => "onchain_withdrawal",
+            CircuitType::Block => "block",
+            CircuitType::Exit => "exit",
+        }
+    }
+
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            CircuitType::Deposit => 0,
+            CircuitType::Transfer => 1,
+            CircuitType::OffchainWithdrawal => 2,
+            CircuitType::OnchainWithdrawal => 3,
+            CircuitType::Block => 4,
+            CircuitType::Exit => 5,
+        }
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(CircuitType::Deposit),
+            1 => Some(CircuitType::Transfer),
+            2 => Some(CircuitType::OffchainWithdrawal),
+            3 => Some(CircuitType::OnchainWithdrawal),
+            4 => Some(CircuitType::Block),
+            5 => Some(CircuitType::Exit),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyHeader {
+    pub circuit_type: CircuitType,
+    pub batch_size: usize,
+    pub account_depth: usize,
+    pub balance_depth: usize,
+    pub commit_inputs: bool,
+}
+
+impl KeyHeader {
+    pub fn new(
+        circuit_type: CircuitType,
+        batch_size: usize,
+        account_depth: usize,
+        balance_depth: usize,
+        commit_inputs: bool,
+    ) -> Self {
+        KeyHeader {
+            circuit_type,
+            batch_size,
+            account_depth,
+            balance_depth,
+            commit_inputs,
+        }
+    }
+
+    // number of public inputs of the circuit
+    pub fn num_inputs(&self) -> usize {
+        if self.commit_inputs {
+            return 1;
+        }
+
+        match self.circuit_type {
+            CircuitType::Deposit => 4,
+            CircuitType::Transfer => 2,
+            CircuitType::OffchainWithdrawal => 2 + 3 * self.batch_size,
+            CircuitType::OnchainWithdrawal => 4 + 3 * self.batch_size,
+            CircuitType::Block => 6 + 3 * self.batch_size,
+            CircuitType::Exit => 6,
+        }
+    }
+
+    // file name for the key, e.g. `deposit_b2_d24_t8.params`
+    pub fn file_name(&self, extension: &str) -> String {
+        format!(
+            "{}_b{}_d{}_t{}{}.{}",
+            self.circuit_type.name(),
+            self.batch_size,
+            self.account_depth,
+            self.balance_depth,
+            if self.commit_inputs { "_c" } else { "" },
+            extension,
+        )
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&KEY_MAGIC)?;
+        writer.write_all(&[KEY_VERSION, self.circuit_type.to_u8()])?;
+        writer.write_all(&(self.batch_size as u32).to_be_bytes())?;
+        writer.write_all(&(self.account_depth as u32).to_be_bytes())?;
+        writer.write_all(&(self.balance_depth as u32).to_be_bytes())?;
+        writer.write_all(&[self.commit_inputs as u8])?;
+
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> Result<Self, KeyError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != KEY_MAGIC {
+            return Err(KeyError::InvalidHeader);
+        }
+
+        let mut bytes = [0u8; 2];
+        reader.read_exact(&mut bytes)?;
+        if bytes[0] != KEY_VERSION {
+            return Err(KeyError::InvalidHeader);
+        }
+        let circuit_type = CircuitType::from_u8(bytes[1]).ok_or(KeyError::InvalidHeader)?;
+
+        let mut word = [0u8; 4];
+        reader.read_exact(&mut word)?;
+        let batch_size = u32::from_be_bytes(word) as usize;
+        reader.read_exact(&mut word)?;
+        let account_depth = u32::from_be_bytes(word) as usize;
+        reader.read_exact(&mut word)?;
+        let balance_depth = u32::from_be_bytes(word) as usize;
+
+        let mut flag = [0u8; 1];
+        reader.read_exact(&mut flag)?;
+        let commit_inputs = match flag[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(KeyError::InvalidHeader),
+        };
+
+        Ok(KeyHeader {
+            circuit_type,
+            batch_size,
+            account_depth,
+            balance_depth,
+            commit_inputs,
+        })
+    }
+}
+
+impl fmt::Display for KeyHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "{} circuit (batch size {}, account depth {}, balance depth {}{})",
+            self.circuit_type.name(),
+            self.batch_size,
+            self.account_depth,
+            self.balance_depth,
+            if self.commit_inputs { ", committed inputs" } else { "" },
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    InvalidHeader,
+    HeaderMismatch { expected: KeyHeader, found: KeyHeader },
+    InputsMismatch { expected: usize, found: usize },
+    IoError(io::Error),
+}
+
+impl Error for KeyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KeyError::IoError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            KeyError::InvalidHeader => write!(f, "Invalid key file header"),
+            KeyError::HeaderMismatch { expected, found } => {
+                write!(f, "Key is generated for {}, expected {}", found, expected)
+            },
+            KeyError::InputsMismatch { expected, found } => {
+                write!(f, "Key has {} public inputs, expected {}", found, expected)
+            },
+            KeyError::IoError(e) => {
+                write!(f, "I/O error: ")?;
+                e.fmt(f)
+            },
+        }
+    }
+}
+
+impl From<io::Error> for KeyError {
+    fn from(err: io::Error) -> Self {
+        KeyError::IoError(err)
+    }
+}
+
+fn check_header<R: Read>(
+    reader: R,
+    expected: &KeyHeader,
+) -> Result<(), KeyError> {
+    let found = KeyHeader::read(reader)?;
+    if found != *expected {
+        return Err(KeyError::HeaderMismatch { expected: *expected, found });
+    }
+
+    Ok(())
+}
+
+fn check_inputs(
+    vk: &VerifyingKey<Bn256>,
+    header: &KeyHeader,
+) -> Result<(), KeyError> {
+    // first ic element is the constant term
+    let found = vk.ic.len() - 1;
+    if found != header.num_inputs() {
+        return Err(KeyError::InputsMismatch { expected: header.num_inputs(), found });
+    }
+
+    Ok(())
+}
+
+// serialization ------------------------------------------------------------------------
+
+pub fn write_parameters<W: Write>(
+    mut writer: W,
+    header: &KeyHeader,
+    params: &Parameters<Bn256>,
+) -> Result<(), KeyError> {
+    check_inputs(&params.vk, header)?;
+    header.write(&mut writer)?;
+    params.write(&mut writer)?;
+
+    Ok(())
+}
+
+pub fn read_parameters<R: Read>(
+    mut reader: R,
+    expected: &KeyHeader,
+) -> Result<Parameters<Bn256>, KeyError> {
+    check_header(&mut reader, expected)?;
+    let params = Parameters::read(&mut reader, true)?;
+    check_inputs(&params.vk, expected)?;
+
+    Ok(params)
+}
+
+pub fn write_verifying_key<W: Write>(
+    mut writer: W,
+    header: &KeyHeader,
+    vk: &VerifyingKey<Bn256>,
+) -> Result<(), KeyError> {
+    check_inputs(vk, header)?;
+    header.write(&mut writer)?;
+    vk.write(&mut writer)?;
+
+    Ok(())
+}
+
+pub fn read_verifying_key<R: Read>(
+    mut reader: R,
+    expected: &KeyHeader,
+) -> Result<VerifyingKey<Bn256>, KeyError> {
+    check_header(&mut reader, expected)?;
+    let vk = VerifyingKey::read(&mut reader)?;
+    check_inputs(&vk, expected)?;
+
+    Ok(vk)
+}
+
+pub fn save_parameters<P: AsRef<Path>>(
+    path: P,
+    header: &KeyHeader,
+    params: &Parameters<Bn256>,
+) -> Result<(), KeyError> {
+    check_inputs(&params.vk, header)?;
+    let file = File::create(path)?;
+    write_parameters(io::BufWriter::new(file), header, params)
+}
+
+pub fn load_parameters<P: AsRef<Path>>(
+    path: P,
+    expected: &KeyHeader,
+) -> Result<Parameters<Bn256>, KeyError> {
+    let file = File::open(path)?;
+    read_parameters(io::BufReader::new(file), expected)
+}
+
+pub fn save_verifying_key<P: AsRef<Path>>(
+    path: P,
+    header: &KeyHeader,
+    vk: &VerifyingKey<Bn256>,
+) -> Result<(), KeyError> {
+    check_inputs(vk, header)?;
+    let file = File::create(path)?;
+    write_verifying_key(io::BufWriter::new(file), header, vk)
+}
+
+pub fn load_verifying_key<P: AsRef<Path>>(
+    path: P,
+    expected: &KeyHeader,
+) -> Result<VerifyingKey<Bn256>, KeyError> {
+    let file = File::open(path)?;
+    read_verifying_key(io::BufReader::new(file), expected)
+}
+
+// circuit params generation ------------------------------------------------------------
+
+// account state without witness values, parameters only depend on the shape
+fn empty_account_state(
+    account_depth: usize,
+    balance_depth: usize,
+) -> AccountState<Bn256> {
+    AccountState::<Bn256> {
+        old_balance: None,
+        new_balance: None,
+        old_pubkey: None,
+        old_nonce: None,
+        new_pubkey: None,
+        new_nonce: None,
+        account_path: vec![None; account_depth],
+        account_indices: vec![None; account_depth],
+        balance_path: vec![None; balance_depth],
+        balance_indices: vec![None; balance_depth],
+    }
+}
+
+pub fn generate_parameters(
+    header: &KeyHeader,
+    hash_params: &Bn256PoseidonParams,
+    sign_params: &AltJubjubBn256,
+) -> Result<Parameters<Bn256>, SynthesisError> {
+    let KeyHeader { circuit_type, batch_size, account_depth, balance_depth, commit_inputs } = *header;
+
+    match circuit_type {
+        CircuitType::Deposit => setup_deposit_circuit(
+            batch_size, account_depth, balance_depth, hash_params, sign_params, commit_inputs,
+        ),
+        CircuitType::Transfer => setup_transfer_circuit(
+            batch_size, account_depth, balance_depth, hash_params, sign_params, commit_inputs,
+        ),
+        CircuitType::OffchainWithdrawal => setup_offchain_withdraw_circuit(
+            batch_size, account_depth, balance_depth, hash_params, sign_params, commit_inputs,
+        ),
+        CircuitType::OnchainWithdrawal => setup_onchain_withdraw_circuit(
+            batch_size, account_depth, balance_depth, hash_params, commit_inputs,
+        ),
+        CircuitType::Block => setup_block_circuit(
+            batch_size, account_depth, balance_depth, hash_params, sign_params, commit_inputs,
+        ),
+        CircuitType::Exit => setup_exit_circuit(
+            account_depth, balance_depth, hash_params, sign_params, commit_inputs,
+        ),
+    }
+}
+
+pub fn setup_deposit_circuit<'a>(
+    deposit_batch: usize,
+    account_depth: usize,
+    balance_depth: usize,
+    hash_params: &'a Bn256PoseidonParams,
+    sign_params: &'a AltJubjubBn256,
+    commit_inputs: bool,
+) -> Result<Parameters<Bn256>, SynthesisError> {
+    let account_state = empty_account_state(account_depth, balance_depth);
+
+    let deposit_gen = || {
+        DepositCircuit::<Bn256> {
+            account_state: account_state.clone(),
+            pubkey: None,
+            account_id: None,
+            token_id: None,
+            amount: None,
+            new_account: None,
+            padding: None,
+        }
+    };
+
+    let mut deposit_queue = Vec::with_capacity(deposit_batch);
+    deposit_queue.resize_with(deposit_batch, deposit_gen);
+
+    let circuit = DepositBatchCircuit {
+        deposit_batch,
+        account_depth,
+        balance_depth,
+        hash_params,
+        sign_params,
+        commit_inputs,
+        deposit_queue,
+        old_accum_hash: None,
+        new_accum_hash: None,
+        old_account_root: None,
+        new_account_root: None,
+    };
+
+    let mut rng = thread_rng();
+    generate_random_parameters(circuit, &mut rng)
+}
+
+pub fn setup_onchain_withdraw_circuit(
+    batch_size: usize,
+    account_depth: usize,
+    balance_depth: usize,
+    hash_params: &Bn256PoseidonParams,
+    commit_inputs: bool,
+) -> Result<Parameters<Bn256>, SynthesisError> {
+    let account_state = empty_account_state(account_depth, balance_depth);
+
+    let withdrawal_gen = || {
+        OnchainWithdrawalCircuit::<Bn256> {
+            account_state: account_state.clone(),
+            account_id: None,
+            token_id: None,
+            amount: None,
+            padding: None,
+        }
+    };
+
+    let mut queue = Vec::with_capacity(batch_size);
+    queue.resize_with(batch_size, withdrawal_gen);
+
+    let circuit = OnchainWithdrawalBatchCircuit {
+        batch_size,
+        account_depth,
+        balance_depth,
+        hash_params,
+        commit_inputs,
+        queue,
+        old_accum_hash: None,
+        new_accum_hash: None,
+        old_account_root: None,
+        new_account_root: None,
+    };
+
+    let mut rng = thread_rng();
+    generate_random_parameters(circuit, &mut rng)
+}
+
+pub fn setup_offchain_withdraw_circuit<'a>(
+    batch_size: usize,
+    account_depth: usize,
+    balance_depth: usize,
+    hash_params: &'a Bn256PoseidonParams,
+    sign_params: &'a AltJubjubBn256,
+    commit_inputs: bool,
+) -> Result<Parameters<Bn256>, SynthesisError> {
+    let account_state = empty_account_state(account_depth, balance_depth);
+
+    let withdrawal_gen = || {
+        OffchainWithdrawalCircuit::<Bn256> {
+            account_state: account_state.clone(),
+            account_id: None,
+            token_id: None,
+            amount: None,
+            fee: None,
+            nonce: None,
+            sign: None,
+            pubkey: None,
+        }
+    };
+
+    let mut queue = Vec::with_capacity(batch_size);
+    queue.resize_with(batch_size, withdrawal_gen);
+
+    let fee = FeeCircuit::<Bn256> {
+        account_state: account_state.clone(),
+        account_id: None,
+        token_id: None,
+    };
+
+    let circuit = OffchainWithdrawalBatchCircuit {
+        batch_size,
+        account_depth,
+        balance_depth,
+        hash_params,
+        sign_params,
+        commit_inputs,
+        queue,
+        fee,
+        old_account_root: None,
+        new_account_root: None,
+    };
+
+    let mut rng = thread_rng();
+    generate_random_parameters(circuit, &mut rng)
+}
+
+pub fn setup_transfer_circuit<'a>(
+    batch_size: usize,
+    account_depth: usize,
+    balance_depth: usize,
+    hash_params: &'a Bn256PoseidonParams,
+    sign_params: &'a AltJubjubBn256,
+    commit_inputs: bool,
+) -> Result<Parameters<Bn256>, SynthesisError> {
+    let account_state = empty_account_state(account_depth, balance_depth);
+
+    let transfer_gen = || {
+        TransferCircuit::<Bn256> {
+            account_state_from: account_state.clone(),
+            account_state_to: account_state.clone(),
+            account_id_from: None,
+            account_id_to: None,
+            pubkey_to: None,
+            by_pubkey: None,
+            new_account: None,
+            token_id: None,
+            amount: None,
+            fee: None,
+            nonce: None,
+            sign: None,
+            pubkey: None,
+        }
+    };
+
+    let mut queue = Vec::with_capacity(batch_size);
+    queue.resize_with(batch_size, transfer_gen);
+
+    let fee = FeeCircuit::<Bn256> {
+        account_state: account_state.clone(),
+        account_id: None,
+        token_id: None,
+    };
+
+    let circuit = TransferBatchCircuit {
+        batch_size,
+        account_depth,
+        balance_depth,
+        hash_params,
+        sign_params,
+        commit_inputs,
+        queue,
+        fee,
+        old_account_root: None,
+        new_account_root: None,
+    };
+
+    let mut rng = thread_rng();
+    generate_random_parameters(circuit, &mut rng)
+}
+
+pub fn setup_block_circuit<'a>(
+    block_size: usize,
+    account_depth: usize,
+    balance_depth: usize,
+    hash_params: &'a Bn256PoseidonParams,
+    sign_params: &'a AltJubjubBn256,
+    commit_inputs: bool,
+) -> Result<Parameters<Bn256>, SynthesisError> {
+    let account_state = empty_account_state(account_depth, balance_depth);
+
+    let operation_gen = || {
+        BlockOperationCircuit::<Bn256> {
+            op_type: None,
+            account_state_from: account_state.clone(),
+            account_state_to: account_state.clone(),
+            account_id_from: None,
+            account_id_to: None,
+            pubkey_to: None,
+            by_pubkey: None,
+            new_account_from: None,
+            new_account_to: None,
+            token_id: None,
+            amount: None,
+            fee: None,
+            nonce: None,
+            deposit_pubkey: None,
+            sign: None,
+            pubkey: None,
+        }
+    };
+
+    let mut operations = Vec::with_capacity(block_size);
+    operations.resize_with(block_size, operation_gen);
+
+    let fee = FeeCircuit::<Bn256> {
+        account_state: account_state.clone(),
+        account_id: None,
+        token_id: None,
+    };
+
+    let circuit = BlockCircuit {
+        block_size,
+        account_depth,
+        balance_depth,
+        hash_params,
+        sign_params,
+        commit_inputs,
+        operations,
+        fee,
+        old_account_root: None,
+        new_account_root: None,
+        old_deposit_accum_hash: None,
+        new_deposit_accum_hash: None,
+        old_withdrawal_accum_hash: None,
+        new_withdrawal_accum_hash: None,
+    };
+
+    let mut rng = thread_rng();
+    generate_random_parameters(circuit, &mut rng)
+}
+
+pub fn setup_exit_circuit<'a>(
+    account_depth: usize,
+    balance_depth: usize,
+    hash_params: &'a Bn256PoseidonParams,
+    sign_params: &'a AltJubjubBn256,
+    commit_inputs: bool,
+) -> Result<Parameters<Bn256>, SynthesisError> {
+    let account_state = empty_account_state(account_depth, balance_depth);
+
+    let circuit = ExitCircuit {
+        account_depth,
+        balance_depth,
+        hash_params,
+        sign_params,
+        commit_inputs,
+        account_state,
+        root: None,
+        account_id: None,
+        token_id: None,
+        amount: None,
+        recipient: None,
+        sign: None,
+        pubkey: None,
+    };
+
+    let mut rng = thread_rng();
+    generate_random_parameters(circuit, &mut rng)
+}
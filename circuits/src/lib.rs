@@ -7,3 +7,12 @@ pub mod operator;
 pub mod data_structs;
 pub mod tree;
 pub mod transfer_circuit;
+pub mod block_circuit;
+pub mod exit_circuit;
+pub mod fee_circuit;
+pub mod keys;
+pub mod storage;
+pub mod history;
+pub mod solidity;
+pub mod abi;
+pub mod verifier;
@@ -1,5 +1,3 @@
-use std::mem;
-
 use bellman_ce::{
     Circuit,
     ConstraintSystem,
@@ -17,31 +15,29 @@ use sapling_crypto_ce::{
         QuinticSBox,
     },
     circuit::{
-        baby_eddsa::EddsaSignature,
         poseidon_hash::poseidon_hash,
         num::AllocatedNum,
-        boolean::{ Boolean, AllocatedBit },
         ecc::EdwardsPoint,
     },  
     eddsa::Signature,
 };
 
-use crate::utils::{
-    sign::verify_signature,
-    calc::boolean_to_allocated_num,
-};
+use crate::utils::sign::verify_signature;
 
-use super::account::{ AccountState, AccountCircuit };
+use super::account::{ AccountState, AccountCircuit, BALANCE_BITS, AMOUNT_BITS, NONCE_BITS };
 use super::utils::calc::check_decomposition_le;
+use super::utils::commitment::expose_inputs;
+use super::fee_circuit::{ FeeCircuit, check_fee_token };
 
-const BITS_IN_BYTE: usize = 8;
 const NUM_BYTES_TO_SIGN: usize = 31;
 
 #[derive(Clone)]
 pub struct OffchainWithdrawalCircuit<E: JubjubEngine + PoseidonEngine> {
     pub account_state: AccountState<E>,
     pub account_id: Option::<E::Fr>,
+    pub token_id: Option::<E::Fr>,
     pub amount: Option::<E::Fr>,
+    pub fee: Option::<E::Fr>,
     pub nonce: Option::<E::Fr>,
     pub sign: Option::<Signature<E>>,
     pub pubkey: Option::<Point<E, Unknown>>,
@@ -50,20 +46,25 @@ pub struct OffchainWithdrawalCircuit<E: JubjubEngine + PoseidonEngine> {
 impl<E> OffchainWithdrawalCircuit<E>
     where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
 {
+    #[allow(clippy::too_many_arguments)]
     pub fn process<'a, CS: ConstraintSystem<E>> (
         &self,
         mut cs: CS,
         account_depth: usize,
+        balance_depth: usize,
         hash_params: &'a <E as PoseidonEngine>::Params,
         sign_params: &'a <E as JubjubEngine>::Params,
+        fee_token_id: &AllocatedNum<E>,
         old_root: &AllocatedNum<E>,
-    ) -> Result<AllocatedNum<E>, SynthesisError> {
+        inputs: &mut Vec::<AllocatedNum<E>>,
+    ) -> Result<(AllocatedNum<E>, AllocatedNum<E>), SynthesisError> {
         
         // allocate avariables ----------------------------------------------------------
         
         let account_circuit = AccountCircuit::new(
             cs.namespace(|| "allocate account circuit"),
             account_depth,
+            balance_depth,
             hash_params,
             &self.account_state,
         )?;
@@ -72,13 +73,24 @@ impl<E> OffchainWithdrawalCircuit<E>
             cs.namespace(|| "allocate account id"),
             || self.account_id.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        account_id_alloc.inputize(cs.namespace(|| "input account id"))?;
+        inputs.push(account_id_alloc.clone());
+
+        let token_id_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate token id"),
+            || self.token_id.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+        inputs.push(token_id_alloc.clone());
 
         let amount_alloc = AllocatedNum::alloc(
             cs.namespace(|| "allocate amount"),
             || self.amount.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        amount_alloc.inputize(cs.namespace(|| "input amount"))?;
+        inputs.push(amount_alloc.clone());
+
+        let fee_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate fee"),
+            || self.fee.ok_or(SynthesisError::AssignmentMissing),
+        )?;
 
         let nonce_alloc = AllocatedNum::alloc(
             cs.namespace(|| "allocate nonce"),
@@ -92,7 +104,9 @@ impl<E> OffchainWithdrawalCircuit<E>
                 cs.namespace(|| "calculate message hash"),
                 &[
                     account_id_alloc.clone(),
+                    token_id_alloc.clone(),
                     amount_alloc.clone(),
+                    fee_alloc.clone(),
                     nonce_alloc.clone(),
                 ],
                 hash_params,
@@ -127,21 +141,44 @@ impl<E> OffchainWithdrawalCircuit<E>
             &account_circuit.accounts_tree.indices_alloc,
         )?;
 
-        // check amount
+        check_decomposition_le(
+            cs.namespace(|| "token id consistence"),
+            &token_id_alloc,
+            &account_circuit.balances_tree.indices_alloc,
+        )?;
+
+        // check amount, account pays the fee on top
 
         cs.enforce(
             || "check amount withdrawal",
-            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[3].get_variable(),
+            |lc| lc + account_circuit.balances_tree.old_leaf_alloc[0].get_variable(),
             |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[3].get_variable()
-                + amount_alloc.get_variable(),
+            |lc| lc + account_circuit.balances_tree.new_leaf_alloc[0].get_variable()
+                + amount_alloc.get_variable() + fee_alloc.get_variable(),
         );
 
-        // check balance for overflow
+        check_fee_token(
+            cs.namespace(|| "check fee token"),
+            &fee_alloc,
+            &token_id_alloc,
+            fee_token_id,
+        );
+
+        // check amounts range, balance for underflow
+
+        amount_alloc.limit_number_of_bits(
+            cs.namespace(|| "check amount range"),
+            AMOUNT_BITS,
+        )?;
+
+        fee_alloc.limit_number_of_bits(
+            cs.namespace(|| "check fee range"),
+            AMOUNT_BITS,
+        )?;
 
-        account_circuit.accounts_tree.new_leaf_alloc[3].limit_number_of_bits(
-            cs.namespace(|| "check buy balance overflow"),
-            mem::size_of::<usize>() * BITS_IN_BYTE,
+        account_circuit.balances_tree.new_leaf_alloc[0].limit_number_of_bits(
+            cs.namespace(|| "check balance underflow"),
+            BALANCE_BITS,
         )?;
 
         // check nonce
@@ -160,6 +197,11 @@ impl<E> OffchainWithdrawalCircuit<E>
             |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[2].get_variable(),
         );
 
+        nonce_alloc.limit_number_of_bits(
+            cs.namespace(|| "check nonce range"),
+            NONCE_BITS,
+        )?;
+
         // verify old root & calculate new root -----------------------------------------
 
         account_circuit.accounts_tree.verify_old_root(
@@ -171,7 +213,7 @@ impl<E> OffchainWithdrawalCircuit<E>
             cs.namespace(|| "calculate new root"),
         )?;
 
-        Ok(new_root)
+        Ok((new_root, fee_alloc))
     }
 
     pub fn check_pubkey<CS: ConstraintSystem<E>> (
@@ -213,10 +255,13 @@ impl<E> OffchainWithdrawalCircuit<E>
 pub struct OffchainWithdrawalBatchCircuit<'a, E: JubjubEngine + PoseidonEngine> {
     pub batch_size: usize,
     pub account_depth: usize,
+    pub balance_depth: usize,
     pub hash_params: &'a <E as PoseidonEngine>::Params,
     pub sign_params: &'a <E as JubjubEngine>::Params,
+    pub commit_inputs: bool,
 
     pub queue: Vec::<OffchainWithdrawalCircuit<E>>,
+    pub fee: FeeCircuit<E>,
     pub old_account_root: Option::<E::Fr>,
     pub new_account_root: Option::<E::Fr>,
 }
@@ -230,30 +275,51 @@ impl<'a, E> Circuit<E> for OffchainWithdrawalBatchCircuit<'a, E>
     ) -> Result<(), SynthesisError> {
         assert_eq!(self.batch_size, self.queue.len());
 
+        let mut inputs = Vec::new();
+
         let mut prev_root = AllocatedNum::alloc(
             cs.namespace(|| "allocate old root"),
             || self.old_account_root.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        prev_root.inputize(cs.namespace(|| "input old root"))?;
+        inputs.push(prev_root.clone());
 
         let new_root = AllocatedNum::alloc(
             cs.namespace(|| "allocate new root"),
             || self.new_account_root.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        new_root.inputize(cs.namespace(|| "input new root"))?;
+        inputs.push(new_root.clone());
+
+        let fee_token_id = self.fee.alloc_token_id(
+            cs.namespace(|| "allocate fee token id"),
+        )?;
+        let mut fees = Vec::with_capacity(self.batch_size);
 
         for (i, withdrawal) in self.queue.iter().enumerate() {
-            let root = withdrawal.process(
+            let (root, fee) = withdrawal.process(
                 cs.namespace(|| format!("verify withdrawal {}", i)),
                 self.account_depth,
+                self.balance_depth,
                 self.hash_params,
                 self.sign_params,
+                &fee_token_id,
                 &prev_root,
+                &mut inputs,
             )?;
 
             prev_root = root;
+            fees.push(fee);
         }
 
+        prev_root = self.fee.process(
+            cs.namespace(|| "credit fees"),
+            self.account_depth,
+            self.balance_depth,
+            self.hash_params,
+            &fee_token_id,
+            &fees,
+            &prev_root,
+        )?;
+
         cs.enforce(
             || "enforce new root equivalence",
             |lc| lc + prev_root.get_variable(),
@@ -261,6 +327,12 @@ impl<'a, E> Circuit<E> for OffchainWithdrawalBatchCircuit<'a, E>
             |lc| lc + new_root.get_variable(),
         );
 
+        expose_inputs(
+            cs.namespace(|| "expose public inputs"),
+            &inputs,
+            self.commit_inputs,
+        )?;
+
         Ok(())
     }
 }
@@ -1,5 +1,3 @@
-use std::mem;
-
 use bellman_ce::{
     Circuit,
     ConstraintSystem,
@@ -16,32 +14,41 @@ use sapling_crypto_ce::{
     },
     circuit::{
         num::AllocatedNum,
+        boolean::{ Boolean, AllocatedBit },
         poseidon_hash::poseidon_hash,
     },
 };
 
-use super::account::{ AccountState, AccountCircuit };
+use ff_ce::Field;
+
+use super::account::{ AccountState, AccountCircuit, AMOUNT_BITS };
 use super::utils::calc::check_decomposition_le;
+use super::utils::commitment::expose_inputs;
 
-const BITS_IN_BYTE: usize = 8;
 
 #[derive(Clone)]
 pub struct OnchainWithdrawalCircuit<E: JubjubEngine + PoseidonEngine> {
     pub account_state: AccountState<E>,
     pub account_id: Option::<E::Fr>,
+    pub token_id: Option::<E::Fr>,
     pub amount: Option::<E::Fr>,
+    // padding withdrawals fill partial batches and are not accumulated
+    pub padding: Option::<bool>,
 }
 
 impl<E> OnchainWithdrawalCircuit<E>
     where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
 {
-    pub fn process<'a, CS: ConstraintSystem<E>> (
+    #[allow(clippy::too_many_arguments)]
+    pub fn process<CS: ConstraintSystem<E>> (
         &self,
         mut cs: CS,
         account_depth: usize,
-        hash_params: &'a <E as PoseidonEngine>::Params,
+        balance_depth: usize,
+        hash_params: &<E as PoseidonEngine>::Params,
         old_hash: &AllocatedNum<E>,
         old_root: &AllocatedNum<E>,
+        inputs: &mut Vec::<AllocatedNum<E>>,
     ) -> Result<(AllocatedNum<E>, AllocatedNum<E>), SynthesisError> {
         
         // allocate avariables --------------------------------------
@@ -49,6 +56,7 @@ impl<E> OnchainWithdrawalCircuit<E>
         let account_circuit = AccountCircuit::new(
             cs.namespace(|| "allocate account circuit"),
             account_depth,
+            balance_depth,
             hash_params,
             &self.account_state,
         )?;
@@ -57,13 +65,24 @@ impl<E> OnchainWithdrawalCircuit<E>
             cs.namespace(|| "allocate account id"),
             || self.account_id.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        account_id_alloc.inputize(cs.namespace(|| "input account id"))?;
+        inputs.push(account_id_alloc.clone());
+
+        let token_id_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate token id"),
+            || self.token_id.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+        inputs.push(token_id_alloc.clone());
 
         let amount_alloc = AllocatedNum::alloc(
             cs.namespace(|| "allocate amount"),
             || self.amount.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        amount_alloc.inputize(cs.namespace(|| "input amount"))?;
+        inputs.push(amount_alloc.clone());
+
+        let padding_alloc = Boolean::from(AllocatedBit::alloc(
+            cs.namespace(|| "allocate padding flag"),
+            self.padding,
+        )?);
 
         // check changes validity -----------------------------------
         
@@ -75,24 +94,60 @@ impl<E> OnchainWithdrawalCircuit<E>
             &account_circuit.accounts_tree.indices_alloc,
         )?;
 
+        check_decomposition_le(
+            cs.namespace(|| "token id consistence"),
+            &token_id_alloc,
+            &account_circuit.balances_tree.indices_alloc,
+        )?;
+
         // check amount
 
         cs.enforce(
             || "check amount withdrawal",
-            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[3].get_variable(),
+            |lc| lc + account_circuit.balances_tree.old_leaf_alloc[0].get_variable(),
             |lc| lc + CS::one(),
-            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[3].get_variable()
+            |lc| lc + account_circuit.balances_tree.new_leaf_alloc[0].get_variable()
                 + amount_alloc.get_variable(),
         );
 
-        // check balance for overflow
+        cs.enforce(
+            || "check padding amount is zero",
+            |lc| lc + amount_alloc.get_variable(),
+            |_| padding_alloc.lc(CS::one(), E::Fr::one()),
+            |lc| lc,
+        );
 
-        account_circuit.accounts_tree.new_leaf_alloc[3].limit_number_of_bits(
-            cs.namespace(|| "check buy balance overflow"),
-            mem::size_of::<usize>() * BITS_IN_BYTE,
+        // check amount range
+
+        amount_alloc.limit_number_of_bits(
+            cs.namespace(|| "check amount range"),
+            AMOUNT_BITS,
         )?;
 
-        // check nonce the same TODO ???
+        // onchain withdrawal takes all value, padding of an empty balance too
+
+        cs.enforce(
+            || "check withdrawal takes all value",
+            |lc| lc + account_circuit.balances_tree.new_leaf_alloc[0].get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc,
+        );
+
+        // check pubkey & nonce the same
+
+        cs.enforce(
+            || "check pubkey x the same",
+            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[0].get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[0].get_variable(),
+        );
+
+        cs.enforce(
+            || "check pubkey y the same",
+            |lc| lc + account_circuit.accounts_tree.old_leaf_alloc[1].get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[1].get_variable(),
+        );
 
         cs.enforce(
             || "check nonce the same",
@@ -101,20 +156,28 @@ impl<E> OnchainWithdrawalCircuit<E>
             |lc| lc + account_circuit.accounts_tree.new_leaf_alloc[2].get_variable(),
         );
 
-        // calculate new hash ---------------------------------------
+        // calculate new hash, padding keeps the old one ---------
 
-        let new_hash = {
+        let withdrawal_hash = {
             let hashes_vec = poseidon_hash(
                 cs.namespace(|| "calculate new accum hash"),
                 &[
                     old_hash.clone(),
                     account_id_alloc,
+                    token_id_alloc,
                 ],
                 hash_params,
             )?;
             hashes_vec[0].clone()
         };
 
+        let new_hash = AllocatedNum::conditionally_select(
+            cs.namespace(|| "select new accum hash"),
+            old_hash,
+            &withdrawal_hash,
+            &padding_alloc,
+        )?;
+
         // verify old root & calculate new root ---------------------
 
         account_circuit.accounts_tree.verify_old_root(
@@ -134,7 +197,9 @@ impl<E> OnchainWithdrawalCircuit<E>
 pub struct OnchainWithdrawalBatchCircuit<'a, E: JubjubEngine + PoseidonEngine> {
     pub batch_size: usize,
     pub account_depth: usize,
+    pub balance_depth: usize,
     pub hash_params: &'a <E as PoseidonEngine>::Params,
+    pub commit_inputs: bool,
 
     pub queue: Vec::<OnchainWithdrawalCircuit<E>>,
     pub old_accum_hash: Option::<E::Fr>,
@@ -152,37 +217,41 @@ impl<'a, E> Circuit<E> for OnchainWithdrawalBatchCircuit<'a, E>
     ) -> Result<(), SynthesisError> {
         assert_eq!(self.batch_size, self.queue.len());
 
+        let mut inputs = Vec::new();
+
         let mut prev_hash = AllocatedNum::alloc(
             cs.namespace(|| "allocate old accum hash"),
             || self.old_accum_hash.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        prev_hash.inputize(cs.namespace(|| "input old accum hash"))?;
+        inputs.push(prev_hash.clone());
 
         let new_hash = AllocatedNum::alloc(
             cs.namespace(|| "allocate new accum hash"),
             || self.new_accum_hash.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        new_hash.inputize(cs.namespace(|| "input new accum hash"))?;
+        inputs.push(new_hash.clone());
 
         let mut prev_root = AllocatedNum::alloc(
             cs.namespace(|| "allocate old root"),
             || self.old_account_root.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        prev_root.inputize(cs.namespace(|| "input old root"))?;
+        inputs.push(prev_root.clone());
 
         let new_root = AllocatedNum::alloc(
             cs.namespace(|| "allocate new root"),
             || self.new_account_root.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        new_root.inputize(cs.namespace(|| "input new root"))?;
+        inputs.push(new_root.clone());
 
         for (i, withdrawal) in self.queue.iter().enumerate() {
             let (hash, root) = withdrawal.process(
                 cs.namespace(|| format!("verify withdrawal {}", i)),
                 self.account_depth,
+                self.balance_depth,
                 self.hash_params,
                 &prev_hash,
                 &prev_root,
+                &mut inputs,
             )?;
 
             prev_hash = hash;
@@ -203,6 +272,12 @@ impl<'a, E> Circuit<E> for OnchainWithdrawalBatchCircuit<'a, E>
             |lc| lc + new_root.get_variable(),
         );
 
+        expose_inputs(
+            cs.namespace(|| "expose public inputs"),
+            &inputs,
+            self.commit_inputs,
+        )?;
+
         Ok(())
     }
 }
@@ -1,9 +1,19 @@
 use std::fmt;
 use std::io;
 use std::error::Error;
+use std::path::{ Path, PathBuf };
 
 #[allow(unused_imports)]
 use sapling_crypto_ce::{
+    eddsa::{
+        PrivateKey,
+        PublicKey,
+        Signature,
+    },
+    jubjub::{
+        JubjubEngine,
+        FixedGenerators,
+    },
     poseidon::{
         bn256::Bn256PoseidonParams,
         poseidon_hash,
@@ -36,50 +46,176 @@ use super::{
     data_structs::deposit::Deposit,
     data_structs::onchain_withdrawal::OnchainWithdrawal,
     data_structs::offchain_withdrawal::OffchainWithdrawal,
-    tree::account::AccountsTree,
+    tree::account::{ AccountsTree, TreeError },
+    tree::pending::PendingState,
+    tree::proof::AccountProof,
+    account::{ AccountState, NONCE_BITS },
+    fee_circuit::FeeCircuit,
 };
 
 use crate::utils::utils::{
     usize_to_fr,
-    fr_to_usize,
+    u128_to_fr,
 };
+use crate::utils::commitment::calc_commitment;
+use crate::verifier::{ VerificationError, verify_batch_proof };
+use crate::storage::{ self, StorageError, LogRecord, BatchRecord };
+use crate::keys::CircuitType;
+use crate::history::BlockHistory;
 
 use crate::{
     deposit_circuit::{ DepositCircuit, DepositBatchCircuit },
     onchain_withdrawal_circuit:: { OnchainWithdrawalCircuit, OnchainWithdrawalBatchCircuit },
     offchain_withdrawal_circuit:: { OffchainWithdrawalCircuit, OffchainWithdrawalBatchCircuit },
     transfer_circuit::{ TransferCircuit, TransferBatchCircuit },
+    block_circuit::{ OperationType, BlockOperationCircuit, BlockCircuit, padding_key },
 };
 
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1 << 16;
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub enum OperatorError {
     Unknown,
-    NotEnoughObjects,
-    InvalidSignature,
-    CircuitError(SynthesisError),
+    NotEnoughObjects { available: usize, required: usize },
+    QueueFull { capacity: usize },
+    InvalidSignature { account_id: usize },
+    UnknownAccount { account_id: usize },
+    InvalidRequest { account_id: usize },
+    UnknownToken { token_id: usize },
+    NonceMismatch { account_id: usize, expected: usize, actual: usize },
+    NonceOverflow { account_id: usize },
+    InsufficientBalance { account_id: usize, balance: u128, amount: u128 },
+    AmountOverflow { account_id: usize, balance: u128, amount: u128 },
+    BalanceMismatch { account_id: usize, balance: u128, amount: u128 },
+    RootMismatch { expected: bn256::Fr, found: bn256::Fr },
+    AccountAlreadyOwned { account_id: usize },
+    FeeNotAccepted { token_id: usize, fee: u128 },
+    ReservedAccount { account_id: usize },
+    TreeFull,
+    RequestEvicted { index: usize, error: Box<OperatorError> },
+    BlockCircuitNotSet,
+    ProvingFailed(SynthesisError),
+    VerificationFailed(VerificationError),
+    StorageFailed(StorageError),
     IoError(std::io::Error),
 }
 
+impl OperatorError {
+    // account the error refers to, if any
+    pub fn account_id(&self) -> Option<usize> {
+        match self {
+            OperatorError::InvalidSignature { account_id }
+            | OperatorError::UnknownAccount { account_id }
+            | OperatorError::InvalidRequest { account_id }
+            | OperatorError::NonceMismatch { account_id, .. }
+            | OperatorError::NonceOverflow { account_id }
+            | OperatorError::InsufficientBalance { account_id, .. }
+            | OperatorError::AmountOverflow { account_id, .. }
+            | OperatorError::BalanceMismatch { account_id, .. }
+            | OperatorError::AccountAlreadyOwned { account_id }
+            | OperatorError::ReservedAccount { account_id } => Some(*account_id),
+            OperatorError::RequestEvicted { error, .. } => error.account_id(),
+            _ => None,
+        }
+    }
+
+    // position of the failed request in its queue, if the error comes from a batch
+    pub fn request_index(&self) -> Option<usize> {
+        match self {
+            OperatorError::RequestEvicted { index, .. } => Some(*index),
+            _ => None,
+        }
+    }
+}
+
 impl Error for OperatorError {
-    fn description(&self) -> &str {
-        match *self {
-            OperatorError::Unknown => "Unknown error",
-            OperatorError::NotEnoughObjects => "Not enough objects for batch",
-            OperatorError::InvalidSignature => "Invalid order signature",
-            OperatorError::CircuitError(_) => "Encountered a circuit error",
-            OperatorError::IoError(_) => "Encountered an I/O error",
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            OperatorError::RequestEvicted { error, .. } => Some(error.as_ref()),
+            OperatorError::ProvingFailed(e) => Some(e),
+            OperatorError::VerificationFailed(e) => Some(e),
+            OperatorError::StorageFailed(e) => Some(e),
+            OperatorError::IoError(e) => Some(e),
+            _ => None,
         }
     }
 }
 
 impl fmt::Display for OperatorError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        if let &OperatorError::IoError(ref e) = self {
-            write!(f, "I/O error: ")?;
-            e.fmt(f)
-        } else {
-            write!(f, "{}", self.description())
+        match self {
+            OperatorError::Unknown => write!(f, "Unknown error"),
+            OperatorError::NotEnoughObjects { available, required } => {
+                write!(f, "Not enough objects for batch: {} of {}", available, required)
+            },
+            OperatorError::QueueFull { capacity } => {
+                write!(f, "Request queue is full ({} requests)", capacity)
+            },
+            OperatorError::InvalidSignature { account_id } => {
+                write!(f, "Invalid request signature for account {}", account_id)
+            },
+            OperatorError::UnknownAccount { account_id } => {
+                write!(f, "Account {} is out of tree range", account_id)
+            },
+            OperatorError::InvalidRequest { account_id } => {
+                write!(f, "Malformed request for account {}", account_id)
+            },
+            OperatorError::UnknownToken { token_id } => {
+                write!(f, "Token {} is out of balance tree range", token_id)
+            },
+            OperatorError::NonceMismatch { account_id, expected, actual } => {
+                write!(f, "Account {} nonce mismatch: expected {}, got {}", account_id, expected, actual)
+            },
+            OperatorError::NonceOverflow { account_id } => {
+                write!(f, "Account {} nonce is out of range", account_id)
+            },
+            OperatorError::InsufficientBalance { account_id, balance, amount } => {
+                write!(f, "Account {} balance {} is less than {}", account_id, balance, amount)
+            },
+            OperatorError::AmountOverflow { account_id, balance, amount } => {
+                write!(f, "Account {} balance {} overflows when adding {}", account_id, balance, amount)
+            },
+            OperatorError::BalanceMismatch { account_id, balance, amount } => {
+                write!(f, "Account {} balance {} doesn't match claimed {}", account_id, balance, amount)
+            },
+            OperatorError::RootMismatch { expected, found } => {
+                write!(f, "Claimed root {} doesn't match tree root {}", found, expected)
+            },
+            OperatorError::AccountAlreadyOwned { account_id } => {
+                write!(f, "Account {} is owned by another public key", account_id)
+            },
+            OperatorError::FeeNotAccepted { token_id, fee } => {
+                write!(f, "Fee {} in token {} is not accepted by the operator", fee, token_id)
+            },
+            OperatorError::ReservedAccount { account_id } => {
+                write!(f, "Account {} is reserved by the operator", account_id)
+            },
+            OperatorError::TreeFull => {
+                write!(f, "No empty account left in the tree")
+            },
+            OperatorError::RequestEvicted { index, error } => {
+                write!(f, "Request {} evicted from batch: {}", index, error)
+            },
+            OperatorError::BlockCircuitNotSet => {
+                write!(f, "Block circuit parameters are not set")
+            },
+            OperatorError::ProvingFailed(e) => {
+                write!(f, "Proving failed: ")?;
+                e.fmt(f)
+            },
+            OperatorError::VerificationFailed(e) => {
+                write!(f, "Proof verification failed: ")?;
+                e.fmt(f)
+            },
+            OperatorError::StorageFailed(e) => {
+                write!(f, "Storage failed: ")?;
+                e.fmt(f)
+            },
+            OperatorError::IoError(e) => {
+                write!(f, "I/O error: ")?;
+                e.fmt(f)
+            },
         }
     }
 }
@@ -90,9 +226,46 @@ impl From<io::Error> for OperatorError {
     }
 }
 
+impl From<VerificationError> for OperatorError {
+    fn from(err: VerificationError) -> Self {
+        OperatorError::VerificationFailed(err)
+    }
+}
+
+impl From<StorageError> for OperatorError {
+    fn from(err: StorageError) -> Self {
+        OperatorError::StorageFailed(err)
+    }
+}
+
+impl From<TreeError> for OperatorError {
+    fn from(err: TreeError) -> Self {
+        match err {
+            TreeError::UnknownAccount { account_id } => OperatorError::UnknownAccount { account_id },
+            TreeError::UnknownToken { token_id } => OperatorError::UnknownToken { token_id },
+            TreeError::InvalidRequest { account_id } => OperatorError::InvalidRequest { account_id },
+            TreeError::InvalidSignature { account_id } => OperatorError::InvalidSignature { account_id },
+            TreeError::NonceMismatch { account_id, expected, actual } => {
+                OperatorError::NonceMismatch { account_id, expected, actual }
+            },
+            TreeError::NonceOverflow { account_id } => OperatorError::NonceOverflow { account_id },
+            TreeError::InsufficientBalance { account_id, balance, amount } => {
+                OperatorError::InsufficientBalance { account_id, balance, amount }
+            },
+            TreeError::AmountOverflow { account_id, balance, amount } => {
+                OperatorError::AmountOverflow { account_id, balance, amount }
+            },
+            TreeError::BalanceMismatch { account_id, balance, amount } => {
+                OperatorError::BalanceMismatch { account_id, balance, amount }
+            },
+            TreeError::RootMismatch { expected, found } => OperatorError::RootMismatch { expected, found },
+        }
+    }
+}
+
 impl From<SynthesisError> for OperatorError {
     fn from(err: SynthesisError) -> Self {
-        OperatorError::CircuitError(err)
+        OperatorError::ProvingFailed(err)
     }
 }
 
@@ -107,24 +280,48 @@ pub struct Operator<'a> {
     pub onchain_withdrawal_queue: Vec<OnchainWithdrawal>,
     pub offchain_withdrawal_batch: usize,
     pub offchain_withdrawal_queue: Vec<OffchainWithdrawal>,
+    // queue of every queued request in admission order, the pending state
+    // is replayed in this order
+    pub admission_order: Vec<CircuitType>,
+    pub queue_capacity: usize,
+    pub block_size: usize,
+    // expose a single commitment instead of all public inputs,
+    // circuit parameters must be generated with the same setting
+    pub commit_inputs: bool,
 
     pub tree: AccountsTree<'a>,
+    pub pending: PendingState,
     pub deposit_accum_hash: bn256::Fr,
     pub withdrawal_accum_hash: bn256::Fr,
+    // number of committed batches and blocks
+    pub batch_number: usize,
+    // write-ahead log of admitted requests and executed batches
+    pub log_path: Option::<PathBuf>,
+    pub history: BlockHistory,
 
     pub account_depth: usize,
+    pub balance_depth: usize,
+    pub padding_account_id: usize,
+    pub padding_key: <Bn256 as JubjubEngine>::Fs,
+    // account credited with the fees of every batch, fees are only
+    // accepted in the fee token and rejected while no account is set
+    pub fee_account_id: Option::<usize>,
+    pub fee_token_id: usize,
     pub hash_params: &'a Bn256PoseidonParams,
     pub sign_params: &'a AltJubjubBn256,
     pub deposit_circuit_params: &'a Parameters::<Bn256>,
     pub onchain_withdrawal_circuit_params: &'a Parameters::<Bn256>,
     pub offchain_withdrawal_circuit_params: &'a Parameters::<Bn256>,
     pub transfer_circuit_params: &'a Parameters::<Bn256>,
+    pub block_circuit_params: Option::<&'a Parameters::<Bn256>>,
 }
 
 #[allow(dead_code)]
 impl<'a> Operator<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         account_depth: usize,
+        balance_depth: usize,
         deposit_batch: usize,
         transfer_batch: usize,
         offchain_withdrawal_batch: usize,
@@ -136,6 +333,27 @@ impl<'a> Operator<'a> {
         offchain_withdrawal_circuit_params: &'a Parameters::<Bn256>,
         onchain_withdrawal_circuit_params: &'a Parameters::<Bn256>,
     ) -> Self {
+        let padding_account_id = (1 << account_depth) - 1;
+        let padding_key = padding_key::<Bn256>();
+
+        let mut tree = AccountsTree::new(
+            account_depth,
+            balance_depth,
+            hash_params,
+            sign_params,
+        );
+
+        // genesis state: padding account is owned by the padding key
+        tree.update_account(
+            padding_account_id,
+            PublicKey::from_private(
+                &PrivateKey::<Bn256>(padding_key),
+                FixedGenerators::SpendingKeyGenerator,
+                sign_params,
+            ),
+            bn256::Fr::zero(),
+        ).expect("padding account is the last leaf");
+
         Operator {
             transfer_batch,
             transfer_queue: Vec::new(),
@@ -145,29 +363,201 @@ impl<'a> Operator<'a> {
             onchain_withdrawal_queue: Vec::new(),
             offchain_withdrawal_batch,
             offchain_withdrawal_queue: Vec::new(),
-            tree: AccountsTree::new(
-                account_depth,
-                hash_params,
-                sign_params,
-            ),
+            admission_order: Vec::new(),
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            block_size: 0,
+            commit_inputs: false,
+            tree,
+            pending: PendingState::new(),
             deposit_accum_hash: bn256::Fr::zero(),
             withdrawal_accum_hash: bn256::Fr::zero(),
+            batch_number: 0,
+            log_path: None,
+            history: BlockHistory::new(),
             account_depth,
+            balance_depth,
+            padding_account_id,
+            padding_key,
+            fee_account_id: None,
+            fee_token_id: 0,
             hash_params,
             sign_params,
             deposit_circuit_params,
             onchain_withdrawal_circuit_params,
             offchain_withdrawal_circuit_params,
             transfer_circuit_params,
+            block_circuit_params: None,
+        }
+    }
+
+    // saves the committed state and history, queued requests are not persisted
+    pub fn save_state<P: AsRef<Path>>(&self, path: P) -> Result<(), StorageError> {
+        storage::save_state(
+            path,
+            &self.tree,
+            &self.deposit_accum_hash,
+            &self.withdrawal_accum_hash,
+            self.batch_number,
+            &self.history,
+        )
+    }
+
+    // replaces the committed state with the saved one
+    pub fn load_state<P: AsRef<Path>>(&mut self, path: P) -> Result<(), StorageError> {
+        let state = storage::load_state(
+            path,
+            self.account_depth,
+            self.balance_depth,
+            self.hash_params,
+            self.sign_params,
+        )?;
+
+        self.tree = state.tree;
+        self.deposit_accum_hash = state.deposit_accum_hash;
+        self.withdrawal_accum_hash = state.withdrawal_accum_hash;
+        self.batch_number = state.batch_number;
+        self.history = state.history;
+        self.refresh_pending_state();
+
+        Ok(())
+    }
+
+    // appends admitted requests and executed batches to the log from now on
+    pub fn set_log<P: AsRef<Path>>(&mut self, path: P) {
+        self.log_path = Some(path.as_ref().to_path_buf());
+    }
+
+    fn append_log(&self, record: LogRecord) -> Result<(), OperatorError> {
+        if let Some(path) = &self.log_path {
+            storage::append_log_record(path, &record)?;
+        }
+
+        Ok(())
+    }
+
+    // Rebuilds the tree and queues from the log. The operator must be in the
+    // genesis state and configured as the one that wrote the log. Batches
+    // are re-executed with their recorded proofs instead of proving again,
+    // returns the number of replayed records.
+    pub fn replay_log<P: AsRef<Path>>(&mut self, path: P) -> Result<usize, OperatorError> {
+        let records = storage::load_log(path, self.sign_params)?;
+        let num_records = records.len();
+
+        // replayed records are already in the log
+        let log_path = self.log_path.take();
+        let result = records.into_iter().try_for_each(|record| self.replay_record(record));
+        self.log_path = log_path;
+        result?;
+
+        Ok(num_records)
+    }
+
+    fn replay_record(&mut self, record: LogRecord) -> Result<(), OperatorError> {
+        match record {
+            LogRecord::Deposit(deposit) => self.add_deposit(deposit)?,
+            LogRecord::Transfer(transfer) => self.add_transfer(transfer)?,
+            LogRecord::OffchainWithdrawal(withdrawal) => self.add_offchain_withdrawal(withdrawal)?,
+            LogRecord::OnchainWithdrawal(withdrawal) => self.add_onchain_withdrawal(withdrawal)?,
+            LogRecord::Eviction { queue, index } => {
+                let removed = match queue {
+                    CircuitType::Deposit if index < self.deposit_queue.len() => {
+                        self.deposit_queue.remove(index);
+                        true
+                    },
+                    CircuitType::Transfer if index < self.transfer_queue.len() => {
+                        self.transfer_queue.remove(index);
+                        true
+                    },
+                    CircuitType::OffchainWithdrawal if index < self.offchain_withdrawal_queue.len() => {
+                        self.offchain_withdrawal_queue.remove(index);
+                        true
+                    },
+                    CircuitType::OnchainWithdrawal if index < self.onchain_withdrawal_queue.len() => {
+                        self.onchain_withdrawal_queue.remove(index);
+                        true
+                    },
+                    _ => false,
+                };
+                if !removed {
+                    return Err(StorageError::InvalidData.into());
+                }
+
+                self.remove_admitted(queue, index, 1);
+                self.refresh_pending_state();
+            },
+            LogRecord::Batch(batch) => {
+                if self.tree.get_root() != batch.old_root {
+                    return Err(StorageError::RootMismatch {
+                        expected: batch.old_root,
+                        found: self.tree.get_root(),
+                    }.into());
+                }
+
+                let proof = Some(batch.proof);
+                match batch.batch_type {
+                    CircuitType::Deposit => self.process_deposit_batch(batch.padded, proof)?,
+                    CircuitType::Transfer => self.process_transfer_batch(batch.padded, proof)?,
+                    CircuitType::OffchainWithdrawal => self.process_offchain_withdrawal_batch(batch.padded, proof)?,
+                    CircuitType::OnchainWithdrawal => self.process_onchain_withdrawal_batch(batch.padded, proof)?,
+                    CircuitType::Block => self.process_block(proof)?,
+                    CircuitType::Exit => return Err(StorageError::InvalidData.into()),
+                };
+
+                if self.tree.get_root() != batch.new_root {
+                    return Err(StorageError::RootMismatch {
+                        expected: batch.new_root,
+                        found: self.tree.get_root(),
+                    }.into());
+                }
+            },
         }
+
+        Ok(())
+    }
+
+    // enables execute_block with a block circuit of the given size
+    pub fn set_block_circuit(
+        &mut self,
+        block_size: usize,
+        block_circuit_params: &'a Parameters::<Bn256>,
+    ) {
+        self.block_size = block_size;
+        self.block_circuit_params = Some(block_circuit_params);
+    }
+
+    pub fn set_fee_account(
+        &mut self,
+        account_id: usize,
+        token_id: usize,
+    ) -> Result<(), OperatorError> {
+        self.check_account_id(account_id)?;
+        Self::check_token_on_tree(&self.tree, token_id)?;
+
+        self.fee_account_id = Some(account_id);
+        self.fee_token_id = token_id;
+        self.refresh_pending_state();
+
+        Ok(())
     }
 
     pub fn add_deposit(
         &mut self,
         deposit: Deposit,
     ) -> Result<(), OperatorError> {
-        // TODO check deposit correctnes
+        self.check_queue_capacity(self.deposit_queue.len())?;
+        self.check_account_id(deposit.account_id)?;
+        Self::check_token_on_tree(&self.tree, deposit.token_id)?;
+        let pubkey = Self::deposit_pubkey(&deposit)?;
+        self.check_pending_owner(deposit.account_id, pubkey)?;
+        Self::check_credit(
+            deposit.account_id,
+            self.get_pending_balance(deposit.account_id, deposit.token_id)?,
+            deposit.amount,
+        )?;
+        self.append_log(LogRecord::Deposit(deposit.clone()))?;
+        self.pending.apply_deposit(&self.tree, &deposit, pubkey)?;
         self.deposit_queue.push(deposit);
+        self.admission_order.push(CircuitType::Deposit);
 
         Ok(())
     }
@@ -176,8 +566,13 @@ impl<'a> Operator<'a> {
         &mut self,
         withdrawal: OnchainWithdrawal,
     ) -> Result<(), OperatorError> {
-        // TODO check withdrawal correctnes
+        self.check_queue_capacity(self.onchain_withdrawal_queue.len())?;
+        self.check_account_id(withdrawal.account_id)?;
+        Self::check_token_on_tree(&self.tree, withdrawal.token_id)?;
+        self.append_log(LogRecord::OnchainWithdrawal(withdrawal.clone()))?;
+        self.pending.apply_onchain_withdrawal(&self.tree, &withdrawal)?;
         self.onchain_withdrawal_queue.push(withdrawal);
+        self.admission_order.push(CircuitType::OnchainWithdrawal);
 
         Ok(())
     }
@@ -186,79 +581,746 @@ impl<'a> Operator<'a> {
         &mut self,
         withdrawal: OffchainWithdrawal,
     ) -> Result<(), OperatorError> {
-        // TODO check withdrawal correctnes
+        self.check_queue_capacity(self.offchain_withdrawal_queue.len())?;
+        self.check_account_id(withdrawal.account_id)?;
+        Self::check_token_on_tree(&self.tree, withdrawal.token_id)?;
+        self.check_offchain_withdrawal_signature(
+            &withdrawal,
+            &self.pending.get_pubkey(&self.tree, withdrawal.account_id)?,
+        )?;
+        self.check_pending_nonce(withdrawal.account_id, withdrawal.nonce)?;
+        self.check_fee(withdrawal.token_id, withdrawal.fee)?;
+        self.check_pending_balance(
+            withdrawal.account_id,
+            withdrawal.token_id,
+            withdrawal.amount.saturating_add(withdrawal.fee),
+        )?;
+        self.check_pending_fee_credit(withdrawal.fee)?;
+        self.append_log(LogRecord::OffchainWithdrawal(withdrawal.clone()))?;
+        self.pending.apply_offchain_withdrawal(&self.tree, &withdrawal)?;
+        self.apply_pending_fee(withdrawal.fee)?;
         self.offchain_withdrawal_queue.push(withdrawal);
+        self.admission_order.push(CircuitType::OffchainWithdrawal);
 
         Ok(())
     }
 
+    // transfers by pubkey get the recipient account assigned on admission
     pub fn add_transfer(
         &mut self,
-        transfer: Transfer,
+        mut transfer: Transfer,
     ) -> Result<(), OperatorError> {
-        // TODO assert correctness - recheck matcher: orders not cancelled, enough balances, prices correspond, price integer
+        self.check_queue_capacity(self.transfer_queue.len())?;
+        if let Some(pubkey) = &transfer.pubkey_to {
+            transfer.account_id_to = self.find_recipient_account(pubkey)?;
+        }
+        self.check_account_id(transfer.account_id_from)?;
+        self.check_account_id(transfer.account_id_to)?;
+        Self::check_token_on_tree(&self.tree, transfer.token_id)?;
+        self.check_transfer_signature(
+            &transfer,
+            &self.pending.get_pubkey(&self.tree, transfer.account_id_from)?,
+        )?;
+        self.check_pending_nonce(transfer.account_id_from, transfer.nonce)?;
+        self.check_fee(transfer.token_id, transfer.fee)?;
+        self.check_pending_balance(
+            transfer.account_id_from,
+            transfer.token_id,
+            transfer.amount.saturating_add(transfer.fee),
+        )?;
+        Self::check_credit(
+            transfer.account_id_to,
+            self.get_pending_balance(transfer.account_id_to, transfer.token_id)?,
+            transfer.amount,
+        )?;
+        self.check_pending_fee_credit(transfer.fee)?;
+        self.append_log(LogRecord::Transfer(transfer.clone()))?;
+        self.pending.apply_transfer(&self.tree, &transfer)?;
+        self.apply_pending_fee(transfer.fee)?;
         self.transfer_queue.push(transfer);
+        self.admission_order.push(CircuitType::Transfer);
 
         Ok(())
     }
 
-    pub fn execute_deposit_batch(
+    // public inputs as the verifier expects them
+    fn expose_public_inputs(&self, public_inputs: Vec::<bn256::Fr>) -> Vec::<bn256::Fr> {
+        if self.commit_inputs {
+            return vec![calc_commitment(&public_inputs)];
+        }
+
+        public_inputs
+    }
+
+    // proof verification -----------------------------------------------------
+
+    pub fn verify_deposit_batch(
+        &self,
+        public_inputs: &[bn256::Fr],
+        proof: &Proof<Bn256>,
+    ) -> Result<(), OperatorError> {
+        Ok(verify_batch_proof(&self.deposit_circuit_params.vk, public_inputs, proof)?)
+    }
+
+    pub fn verify_transfer_batch(
+        &self,
+        public_inputs: &[bn256::Fr],
+        proof: &Proof<Bn256>,
+    ) -> Result<(), OperatorError> {
+        Ok(verify_batch_proof(&self.transfer_circuit_params.vk, public_inputs, proof)?)
+    }
+
+    pub fn verify_offchain_withdrawal_batch(
+        &self,
+        public_inputs: &[bn256::Fr],
+        proof: &Proof<Bn256>,
+    ) -> Result<(), OperatorError> {
+        Ok(verify_batch_proof(&self.offchain_withdrawal_circuit_params.vk, public_inputs, proof)?)
+    }
+
+    pub fn verify_onchain_withdrawal_batch(
+        &self,
+        public_inputs: &[bn256::Fr],
+        proof: &Proof<Bn256>,
+    ) -> Result<(), OperatorError> {
+        Ok(verify_batch_proof(&self.onchain_withdrawal_circuit_params.vk, public_inputs, proof)?)
+    }
+
+    pub fn verify_block(
+        &self,
+        public_inputs: &[bn256::Fr],
+        proof: &Proof<Bn256>,
+    ) -> Result<(), OperatorError> {
+        let block_circuit_params = self.block_circuit_params
+            .ok_or(OperatorError::BlockCircuitNotSet)?;
+
+        Ok(verify_batch_proof(&block_circuit_params.vk, public_inputs, proof)?)
+    }
+
+    // admission checks -------------------------------------------------------
+
+    fn check_queue_capacity(
+        &self,
+        queue_len: usize,
+    ) -> Result<(), OperatorError> {
+        if queue_len >= self.queue_capacity {
+            return Err(OperatorError::QueueFull { capacity: self.queue_capacity });
+        }
+
+        Ok(())
+    }
+
+    fn check_account_id(
+        &self,
+        account_id: usize,
+    ) -> Result<(), OperatorError> {
+        Self::check_account_on_tree(&self.tree, account_id)?;
+
+        if account_id == self.padding_account_id {
+            return Err(OperatorError::ReservedAccount { account_id });
+        }
+
+        Ok(())
+    }
+
+    // token balance the account will have once all admitted requests are batched
+    pub fn get_pending_balance(&self, account_id: usize, token_id: usize) -> Result<u128, OperatorError> {
+        Ok(self.pending.get_balance(&self.tree, account_id, token_id)?)
+    }
+
+    // inclusion proof of a committed balance, admitted requests are not included
+    pub fn get_account_proof(
+        &self,
+        account_id: usize,
+        token_id: usize,
+    ) -> Result<AccountProof, OperatorError> {
+        Self::check_account_on_tree(&self.tree, account_id)?;
+        Self::check_token_on_tree(&self.tree, token_id)?;
+
+        Ok(self.tree.get_account_proof(account_id, token_id)?)
+    }
+
+    // account holding the pubkey once admitted requests are batched, or the
+    // first empty account that a transfer to the pubkey would create
+    pub fn find_recipient_account(
+        &self,
+        pubkey: &PublicKey::<Bn256>,
+    ) -> Result<usize, OperatorError> {
+        // untouched accounts are empty, so only touched ones may hold the pubkey
+        let mut touched_accounts: Vec<_> = self.tree.accounts.keys()
+            .chain(self.pending.accounts.keys())
+            .cloned()
+            .collect();
+        touched_accounts.sort_unstable();
+        touched_accounts.dedup();
+
+        for account_id in touched_accounts {
+            if account_id != self.padding_account_id
+                && self.pending.get_pubkey(&self.tree, account_id)?.0 == pubkey.0
+            {
+                return Ok(account_id);
+            }
+        }
+
+        for account_id in 0..self.tree.num_accounts() {
+            if account_id != self.padding_account_id
+                && Some(account_id) != self.fee_account_id
+                && self.pending.is_empty_account(&self.tree, account_id, self.sign_params)?
+            {
+                return Ok(account_id);
+            }
+        }
+
+        Err(OperatorError::TreeFull)
+    }
+
+    // nonce the next signed request of the account is expected to carry
+    pub fn get_next_nonce(&self, account_id: usize) -> Result<usize, OperatorError> {
+        Ok(self.pending.get_nonce(&self.tree, account_id)? + 1)
+    }
+
+    // rebuild speculative state from the committed tree and remaining queues,
+    // requests are applied in the order they were admitted
+    fn refresh_pending_state(&mut self) {
+        self.pending.clear();
+
+        let mut next_index = [0usize; 4];
+        for i in 0..self.admission_order.len() {
+            let queue_type = self.admission_order[i];
+            let index = match queue_type {
+                CircuitType::Deposit => &mut next_index[0],
+                CircuitType::Transfer => &mut next_index[1],
+                CircuitType::OffchainWithdrawal => &mut next_index[2],
+                _ => &mut next_index[3],
+            };
+
+            // batches run in queue order, not admission order, so a request may
+            // no longer apply: it is left out and evicted when its batch runs
+            let _ = self.apply_pending_request(queue_type, *index);
+            *index += 1;
+        }
+    }
+
+    fn apply_pending_request(
         &mut self,
-    ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> { 
-        if self.deposit_queue.len() < self.deposit_batch {
-            return Err(OperatorError::NotEnoughObjects);
+        queue_type: CircuitType,
+        index: usize,
+    ) -> Result<(), TreeError> {
+        match queue_type {
+            CircuitType::Deposit => {
+                let deposit = &self.deposit_queue[index];
+                match &deposit.pubkey {
+                    Some(pubkey) => self.pending.apply_deposit(&self.tree, deposit, pubkey),
+                    // never admitted without a pubkey
+                    None => Ok(()),
+                }
+            },
+            CircuitType::Transfer => {
+                self.pending.apply_transfer(&self.tree, &self.transfer_queue[index])?;
+                self.apply_pending_fee(self.transfer_queue[index].fee)
+            },
+            CircuitType::OffchainWithdrawal => {
+                self.pending.apply_offchain_withdrawal(&self.tree, &self.offchain_withdrawal_queue[index])?;
+                self.apply_pending_fee(self.offchain_withdrawal_queue[index].fee)
+            },
+            _ => {
+                self.pending.apply_onchain_withdrawal(&self.tree, &self.onchain_withdrawal_queue[index])
+            },
+        }
+    }
+
+    // forgets `count` requests of the queue starting at `index` once they
+    // leave the queue, by batching or eviction
+    fn remove_admitted(&mut self, queue_type: CircuitType, index: usize, count: usize) {
+        let mut position = 0;
+        self.admission_order.retain(|admitted| {
+            if *admitted != queue_type {
+                return true;
+            }
+            position += 1;
+            position <= index || position > index + count
+        });
+    }
+
+    // circuits range check nonces to `NONCE_BITS`, a wider one can't be proven
+    fn check_nonce(
+        account_id: usize,
+        nonce: usize,
+        expected_nonce: usize,
+    ) -> Result<(), OperatorError> {
+        if nonce >= 1 << NONCE_BITS {
+            return Err(OperatorError::NonceOverflow { account_id });
+        }
+        if nonce != expected_nonce {
+            return Err(OperatorError::NonceMismatch {
+                account_id,
+                expected: expected_nonce,
+                actual: nonce,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn check_balance(
+        account_id: usize,
+        balance: u128,
+        amount: u128,
+    ) -> Result<(), OperatorError> {
+        if balance < amount {
+            return Err(OperatorError::InsufficientBalance { account_id, balance, amount });
+        }
+
+        Ok(())
+    }
+
+    fn check_credit(
+        account_id: usize,
+        balance: u128,
+        amount: u128,
+    ) -> Result<(), OperatorError> {
+        if balance.checked_add(amount).is_none() {
+            return Err(OperatorError::AmountOverflow { account_id, balance, amount });
+        }
+
+        Ok(())
+    }
+
+    // fees go to the fee account, so they are only paid in its token
+    fn check_fee(
+        &self,
+        token_id: usize,
+        fee: u128,
+    ) -> Result<(), OperatorError> {
+        if fee > 0 && (self.fee_account_id.is_none() || token_id != self.fee_token_id) {
+            return Err(OperatorError::FeeNotAccepted { token_id, fee });
+        }
+
+        Ok(())
+    }
+
+    fn check_pending_fee_credit(
+        &self,
+        fee: u128,
+    ) -> Result<(), OperatorError> {
+        match self.fee_account_id {
+            Some(account_id) => Self::check_credit(
+                account_id,
+                self.get_pending_balance(account_id, self.fee_token_id)?,
+                fee,
+            ),
+            None => Ok(()),
         }
+    }
+
+    fn apply_pending_fee(&mut self, fee: u128) -> Result<(), TreeError> {
+        match self.fee_account_id {
+            Some(account_id) => self.pending.apply_fee(&self.tree, account_id, self.fee_token_id, fee),
+            None => Ok(()),
+        }
+    }
+
+    // deposits carry the pubkey the account is assigned to
+    fn deposit_pubkey(
+        deposit: &Deposit,
+    ) -> Result<&PublicKey::<Bn256>, OperatorError> {
+        deposit.pubkey.as_ref().ok_or(
+            OperatorError::InvalidRequest { account_id: deposit.account_id }
+        )
+    }
+
+    // deposits go to an empty account or one owned by the same pubkey
+    fn check_pending_owner(
+        &self,
+        account_id: usize,
+        pubkey: &PublicKey::<Bn256>,
+    ) -> Result<(), OperatorError> {
+        if self.pending.get_pubkey(&self.tree, account_id)?.0 != pubkey.0
+            && !self.pending.is_empty_account(&self.tree, account_id, self.sign_params)?
+        {
+            return Err(OperatorError::AccountAlreadyOwned { account_id });
+        }
+
+        Ok(())
+    }
+
+    fn check_pending_nonce(
+        &self,
+        account_id: usize,
+        nonce: usize,
+    ) -> Result<(), OperatorError> {
+        Self::check_nonce(account_id, nonce, self.get_next_nonce(account_id)?)
+    }
+
+    fn check_pending_balance(
+        &self,
+        account_id: usize,
+        token_id: usize,
+        amount: u128,
+    ) -> Result<(), OperatorError> {
+        Self::check_balance(account_id, self.get_pending_balance(account_id, token_id)?, amount)
+    }
+
+    // execution checks -------------------------------------------------------
+
+    fn check_account_on_tree(
+        tree: &AccountsTree,
+        account_id: usize,
+    ) -> Result<(), OperatorError> {
+        if account_id >= tree.num_accounts() {
+            return Err(OperatorError::UnknownAccount { account_id });
+        }
+
+        Ok(())
+    }
+
+    fn check_token_on_tree(
+        tree: &AccountsTree,
+        token_id: usize,
+    ) -> Result<(), OperatorError> {
+        if token_id >= tree.num_tokens() {
+            return Err(OperatorError::UnknownToken { token_id });
+        }
+
+        Ok(())
+    }
+
+    fn check_transfer_on_tree(
+        &self,
+        tree: &AccountsTree,
+        transfer: &Transfer,
+    ) -> Result<(), OperatorError> {
+        Self::check_account_on_tree(tree, transfer.account_id_from)?;
+        Self::check_account_on_tree(tree, transfer.account_id_to)?;
+        if let Some(pubkey) = &transfer.pubkey_to {
+            let account = tree.get_account(transfer.account_id_to)?;
+            if account.pubkey.0 != pubkey.0 && !account.is_empty(self.sign_params) {
+                return Err(OperatorError::AccountAlreadyOwned { account_id: transfer.account_id_to });
+            }
+        }
+        Self::check_token_on_tree(tree, transfer.token_id)?;
+        self.check_transfer_signature(
+            transfer,
+            &tree.get_pubkey(transfer.account_id_from)?,
+        )?;
+        Self::check_nonce(
+            transfer.account_id_from,
+            transfer.nonce,
+            tree.get_nonce_value(transfer.account_id_from)? + 1,
+        )?;
+        self.check_fee(transfer.token_id, transfer.fee)?;
+        Self::check_balance(
+            transfer.account_id_from,
+            tree.get_balance(transfer.account_id_from, transfer.token_id)?,
+            transfer.amount.saturating_add(transfer.fee),
+        )?;
+        Self::check_credit(
+            transfer.account_id_to,
+            tree.get_balance(transfer.account_id_to, transfer.token_id)?,
+            transfer.amount,
+        )
+    }
+
+    fn check_offchain_withdrawal_on_tree(
+        &self,
+        tree: &AccountsTree,
+        withdrawal: &OffchainWithdrawal,
+    ) -> Result<(), OperatorError> {
+        Self::check_account_on_tree(tree, withdrawal.account_id)?;
+        Self::check_token_on_tree(tree, withdrawal.token_id)?;
+        self.check_offchain_withdrawal_signature(
+            withdrawal,
+            &tree.get_pubkey(withdrawal.account_id)?,
+        )?;
+        Self::check_nonce(
+            withdrawal.account_id,
+            withdrawal.nonce,
+            tree.get_nonce_value(withdrawal.account_id)? + 1,
+        )?;
+        self.check_fee(withdrawal.token_id, withdrawal.fee)?;
+        Self::check_balance(
+            withdrawal.account_id,
+            tree.get_balance(withdrawal.account_id, withdrawal.token_id)?,
+            withdrawal.amount.saturating_add(withdrawal.fee),
+        )
+    }
+
+    fn check_onchain_withdrawal_on_tree(
+        tree: &AccountsTree,
+        withdrawal: &OnchainWithdrawal,
+    ) -> Result<(), OperatorError> {
+        Self::check_account_on_tree(tree, withdrawal.account_id)?;
+        Self::check_token_on_tree(tree, withdrawal.token_id)
+    }
+
+    // returns the checked pubkey the account is assigned to
+    fn check_deposit_on_tree<'d>(
+        &self,
+        tree: &AccountsTree,
+        deposit: &'d Deposit,
+    ) -> Result<&'d PublicKey::<Bn256>, OperatorError> {
+        let pubkey = Self::deposit_pubkey(deposit)?;
+        Self::check_account_on_tree(tree, deposit.account_id)?;
+        Self::check_token_on_tree(tree, deposit.token_id)?;
+        let account = tree.get_account(deposit.account_id)?;
+        if account.pubkey.0 != pubkey.0 && !account.is_empty(self.sign_params) {
+            return Err(OperatorError::AccountAlreadyOwned { account_id: deposit.account_id });
+        }
+        Self::check_credit(
+            deposit.account_id,
+            tree.get_balance(deposit.account_id, deposit.token_id)?,
+            deposit.amount,
+        )?;
+
+        Ok(pubkey)
+    }
+
+    // batch execution --------------------------------------------------------
+    //
+    // Every batch is executed against a copy of the tree and accumulators.
+    // Operator state and queues are only updated once the proof is created;
+    // a request failing its checks is evicted from the queue and reported,
+    // leaving the rest of the state untouched.
+
+    // number of queued requests going into the next batch; with padding
+    // allowed a partial batch is accepted as long as it is not empty
+    fn batch_requests_count(
+        queue_len: usize,
+        batch_size: usize,
+        allow_padding: bool,
+    ) -> Result<usize, OperatorError> {
+        if queue_len >= batch_size || (allow_padding && queue_len > 0) {
+            return Ok(queue_len.min(batch_size));
+        }
+
+        Err(OperatorError::NotEnoughObjects {
+            available: queue_len,
+            required: batch_size,
+        })
+    }
+
+    fn padding_pubkey(&self) -> Result<PublicKey::<Bn256>, TreeError> {
+        self.tree.get_pubkey(self.padding_account_id)
+    }
+
+    fn padding_deposits(&self, count: usize) -> Result<Vec::<Deposit>, TreeError> {
+        Ok(vec![
+            Deposit {
+                pubkey: Some(self.padding_pubkey()?),
+                account_id: self.padding_account_id,
+                token_id: 0,
+                amount: 0,
+            };
+            count
+        ])
+    }
+
+    fn padding_onchain_withdrawals(&self, count: usize) -> Vec::<OnchainWithdrawal> {
+        vec![
+            OnchainWithdrawal {
+                account_id: self.padding_account_id,
+                token_id: 0,
+                amount: None,
+            };
+            count
+        ]
+    }
+
+    // padding account is never touched by user requests, so nonces of the
+    // padding requests follow directly from the committed tree
+    fn padding_offchain_withdrawals(&self, count: usize) -> Result<Vec::<OffchainWithdrawal>, OperatorError> {
+        let nonce = self.tree.get_nonce_value(self.padding_account_id)?;
+        let seckey = PrivateKey::<Bn256>(self.padding_key);
+
+        Ok((1..=count).map(|i| {
+            let mut withdrawal = OffchainWithdrawal {
+                account_id: self.padding_account_id,
+                token_id: 0,
+                amount: 0,
+                fee: 0,
+                nonce: nonce + i,
+                sign: None,
+            };
+            withdrawal.sign(&seckey, self.hash_params, self.sign_params);
+            withdrawal
+        }).collect())
+    }
+
+    fn padding_transfers(&self, count: usize) -> Result<Vec::<Transfer>, OperatorError> {
+        let nonce = self.tree.get_nonce_value(self.padding_account_id)?;
+        let seckey = PrivateKey::<Bn256>(self.padding_key);
+
+        Ok((1..=count).map(|i| {
+            let mut transfer = Transfer {
+                account_id_from: self.padding_account_id,
+                account_id_to: self.padding_account_id,
+                pubkey_to: None,
+                token_id: 0,
+                amount: 0,
+                fee: 0,
+                nonce: nonce + i,
+                sign: None,
+            };
+            transfer.sign(&seckey, self.hash_params, self.sign_params);
+            transfer
+        }).collect())
+    }
+
+    // total of the fees collected by a batch, all credited to the fee account
+    fn add_batch_fee(
+        &self,
+        fees: u128,
+        fee: u128,
+    ) -> Result<u128, OperatorError> {
+        fees.checked_add(fee).ok_or(OperatorError::AmountOverflow {
+            account_id: self.fee_account_id.unwrap_or(self.padding_account_id),
+            balance: fees,
+            amount: fee,
+        })
+    }
+
+    // credits the fees collected by a batch, without a fee account the
+    // padding account takes the (zero) fees
+    fn credit_fees(
+        &self,
+        tree: &mut AccountsTree,
+        fees: u128,
+    ) -> Result<FeeCircuit::<Bn256>, OperatorError> {
+        let account_id = self.fee_account_id.unwrap_or(self.padding_account_id);
+
+        Ok(FeeCircuit {
+            account_state: tree.credit_fees(account_id, self.fee_token_id, fees)?,
+            account_id: Some(usize_to_fr(account_id)),
+            token_id: Some(usize_to_fr(self.fee_token_id)),
+        })
+    }
+
+    // requests past `num_requests` are padding built by the operator, they are
+    // not queued so their error is returned without eviction
+    fn evict_request<T>(
+        &mut self,
+        queue_type: CircuitType,
+        queue: fn(&mut Self) -> &mut Vec::<T>,
+        index: usize,
+        num_requests: usize,
+        error: OperatorError,
+    ) -> OperatorError {
+        if index >= num_requests {
+            return error;
+        }
+
+        if let Err(log_error) = self.append_log(LogRecord::Eviction { queue: queue_type, index }) {
+            return log_error;
+        }
+
+        queue(self).remove(index);
+        self.remove_admitted(queue_type, index, 1);
+        self.refresh_pending_state();
+
+        OperatorError::RequestEvicted {
+            index,
+            error: Box::new(error),
+        }
+    }
+
+    pub fn execute_deposit_batch(
+        &mut self,
+    ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
+        self.process_deposit_batch(false, None)
+    }
+
+    // executes queued deposits even if the batch is not full,
+    // remaining slots are filled with no-op padding deposits
+    pub fn close_deposit_batch(
+        &mut self,
+    ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
+        self.process_deposit_batch(true, None)
+    }
+
+    // with a recorded proof the batch is replayed from the log without proving
+    fn process_deposit_batch(
+        &mut self,
+        allow_padding: bool,
+        recorded_proof: Option::<Proof<Bn256>>,
+    ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
+        let num_requests = Self::batch_requests_count(
+            self.deposit_queue.len(),
+            self.deposit_batch,
+            allow_padding,
+        )?;
 
         // update local tree --------------------------------------------------
 
-        let old_hash = self.deposit_accum_hash;
-        let old_root = self.tree.get_root();
+        let mut tree = self.tree.clone();
+        let mut accum_hash = self.deposit_accum_hash;
+        let old_hash = accum_hash;
+        let old_root = tree.get_root();
         let mut executed_deposits = Vec::new();
 
-        for _ in 0..self.deposit_batch {
-            let deposit = self.deposit_queue.remove(0);
+        let mut batch = self.deposit_queue[..num_requests].to_vec();
+        batch.append(&mut self.padding_deposits(self.deposit_batch - num_requests)?);
+
+        for (i, deposit) in batch.iter().enumerate() {
+            let padding = i >= num_requests;
+
+            let pubkey = match self.check_deposit_on_tree(&tree, deposit) {
+                Ok(pubkey) => pubkey,
+                Err(error) => {
+                    return Err(self.evict_request(CircuitType::Deposit, |oper| &mut oper.deposit_queue, i, num_requests, error));
+                },
+            };
+
             // update account
 
-            let pubkey = deposit.pubkey.clone().unwrap();
             let (pubkey_x, pubkey_y) = pubkey.0.into_xy();
 
             // update accumulate hash
 
-            self.deposit_accum_hash = {
-                let hashes_vec = poseidon_hash::<Bn256>(
-                    self.hash_params,
-                    &[
-                        self.deposit_accum_hash,
-                        pubkey_x,
-                        pubkey_y,
-                        usize_to_fr(deposit.account_id),
-                        usize_to_fr(deposit.amount),
-                    ],
-                );
-                hashes_vec[0]
-            };
+            if !padding {
+                accum_hash = {
+                    let hashes_vec = poseidon_hash::<Bn256>(
+                        self.hash_params,
+                        &[
+                            accum_hash,
+                            pubkey_x,
+                            pubkey_y,
+                            usize_to_fr(deposit.account_id),
+                            usize_to_fr(deposit.token_id),
+                            u128_to_fr(deposit.amount),
+                        ],
+                    );
+                    hashes_vec[0]
+                };
+            }
 
-            let account_state = deposit.update_tree_and_record_state(&mut self.tree);
+            let account_state = deposit.update_tree_and_record_state(&mut tree)?;
+            let new_account = account_state.old_pubkey != account_state.new_pubkey;
 
             let executed_deposit = DepositCircuit {
                 account_state,
-                pubkey: Some(pubkey.0),
+                pubkey: Some(pubkey.0.clone()),
                 account_id: Some(usize_to_fr(deposit.account_id)),
-                amount: Some(usize_to_fr(deposit.amount)),
+                token_id: Some(usize_to_fr(deposit.token_id)),
+                amount: Some(u128_to_fr(deposit.amount)),
+                new_account: Some(new_account),
+                padding: Some(padding),
             };
 
             executed_deposits.push(executed_deposit);
         }
 
-        let new_hash = self.deposit_accum_hash;
-        let new_root = self.tree.get_root();
+        let new_hash = accum_hash;
+        let new_root = tree.get_root();
 
         // prepare snark input
 
         let circuit = DepositBatchCircuit {
             deposit_batch: self.deposit_batch,
             account_depth: self.account_depth,
+            balance_depth: self.balance_depth,
             hash_params: self.hash_params,
+            sign_params: self.sign_params,
+            commit_inputs: self.commit_inputs,
 
             deposit_queue: executed_deposits,
             old_accum_hash: Some(old_hash),
@@ -285,10 +1347,45 @@ impl<'a> Operator<'a> {
         */
         // generate proof
 
-        let mut rng = thread_rng();
-        let proof = create_random_proof(circuit, self.deposit_circuit_params, &mut rng)?;
+        let proof = match recorded_proof {
+            Some(proof) => proof,
+            None => create_random_proof(circuit, self.deposit_circuit_params, &mut thread_rng())?,
+        };
         let public_inputs = vec![old_hash, new_hash, old_root, new_root];
 
+        // check proof before publishing it
+
+        let public_inputs = self.expose_public_inputs(public_inputs);
+        verify_batch_proof(&self.deposit_circuit_params.vk, &public_inputs, &proof)?;
+
+        // commit new state
+
+        let record = BatchRecord {
+            batch_type: CircuitType::Deposit,
+            padded: allow_padding,
+            batch_number: self.batch_number,
+            deposits: self.deposit_queue[..num_requests].to_vec(),
+            transfers: Vec::new(),
+            offchain_withdrawals: Vec::new(),
+            onchain_withdrawals: Vec::new(),
+            old_root,
+            new_root,
+            old_deposit_accum_hash: old_hash,
+            new_deposit_accum_hash: new_hash,
+            old_withdrawal_accum_hash: self.withdrawal_accum_hash,
+            new_withdrawal_accum_hash: self.withdrawal_accum_hash,
+            proof: proof.clone(),
+        };
+        self.append_log(LogRecord::Batch(Box::new(record.clone())))?;
+
+        self.tree = tree;
+        self.deposit_accum_hash = new_hash;
+        self.deposit_queue.drain(..num_requests);
+        self.remove_admitted(CircuitType::Deposit, 0, num_requests);
+        self.batch_number += 1;
+        self.refresh_pending_state();
+        self.history.push(record);
+
         // TODO send new state to smart contract
 
         Ok((public_inputs, proof))
@@ -297,57 +1394,93 @@ impl<'a> Operator<'a> {
     pub fn execute_onchain_withdrawal_batch(
         &mut self,
     ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
-        
-        if self.onchain_withdrawal_queue.len() < self.onchain_withdrawal_batch {
-            return Err(OperatorError::NotEnoughObjects);
-        }
+        self.process_onchain_withdrawal_batch(false, None)
+    }
+
+    pub fn close_onchain_withdrawal_batch(
+        &mut self,
+    ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
+        self.process_onchain_withdrawal_batch(true, None)
+    }
+
+    // with a recorded proof the batch is replayed from the log without proving
+    fn process_onchain_withdrawal_batch(
+        &mut self,
+        allow_padding: bool,
+        recorded_proof: Option::<Proof<Bn256>>,
+    ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
+        let num_requests = Self::batch_requests_count(
+            self.onchain_withdrawal_queue.len(),
+            self.onchain_withdrawal_batch,
+            allow_padding,
+        )?;
 
         // update local tree ----------------------------------------
 
-        let old_hash = self.withdrawal_accum_hash;
-        let old_root = self.tree.get_root();
+        let mut tree = self.tree.clone();
+        let mut accum_hash = self.withdrawal_accum_hash;
+        let old_hash = accum_hash;
+        let old_root = tree.get_root();
         let mut executed = Vec::new();
+        let mut withdrawals = Vec::new();
 
-        for _ in 0..self.onchain_withdrawal_batch {
-            let mut withdrawal = self.onchain_withdrawal_queue.remove(0);
+        let mut batch = self.onchain_withdrawal_queue[..num_requests].to_vec();
+        batch.append(&mut self.padding_onchain_withdrawals(
+            self.onchain_withdrawal_batch - num_requests,
+        ));
+
+        for (i, mut withdrawal) in batch.into_iter().enumerate() {
+            let padding = i >= num_requests;
+
+            if let Err(error) = Self::check_onchain_withdrawal_on_tree(&tree, &withdrawal) {
+                return Err(self.evict_request(CircuitType::OnchainWithdrawal, |oper| &mut oper.onchain_withdrawal_queue, i, num_requests, error));
+            }
 
             // update accumulate hash
-            self.withdrawal_accum_hash = {
-                let hashes_vec = poseidon_hash::<Bn256>(
-                    self.hash_params,
-                    &[
-                        self.withdrawal_accum_hash,
-                        usize_to_fr(withdrawal.account_id),
-                    ],
-                );
-                hashes_vec[0]
-            };
+            if !padding {
+                accum_hash = {
+                    let hashes_vec = poseidon_hash::<Bn256>(
+                        self.hash_params,
+                        &[
+                            accum_hash,
+                            usize_to_fr(withdrawal.account_id),
+                            usize_to_fr(withdrawal.token_id),
+                        ],
+                    );
+                    hashes_vec[0]
+                };
+            }
 
             // calculate withdrawal amount (onchain withdrawal takes all value)
-            withdrawal.amount = Some(fr_to_usize(
-                self.tree.get_balance(withdrawal.account_id)
-            ));
+            withdrawal.amount = Some(tree.get_balance(withdrawal.account_id, withdrawal.token_id)?);
+            if !padding {
+                withdrawals.push(withdrawal.clone());
+            }
 
-            let account_state = withdrawal.update_tree_and_record_state(&mut self.tree);
+            let account_state = withdrawal.update_tree_and_record_state(&mut tree)?;
 
             let executed_withdrawal = OnchainWithdrawalCircuit {
                 account_state,
                 account_id: Some(usize_to_fr(withdrawal.account_id)),
-                amount: Some(usize_to_fr(withdrawal.amount.unwrap())),
+                token_id: Some(usize_to_fr(withdrawal.token_id)),
+                amount: Some(u128_to_fr(withdrawal.amount.unwrap())),
+                padding: Some(padding),
             };
 
             executed.push(executed_withdrawal);
         }
 
-        let new_hash = self.withdrawal_accum_hash;
-        let new_root = self.tree.get_root();
+        let new_hash = accum_hash;
+        let new_root = tree.get_root();
 
         // prepare snark input
 
         let circuit = OnchainWithdrawalBatchCircuit {
             batch_size: self.onchain_withdrawal_batch,
             account_depth: self.account_depth,
+            balance_depth: self.balance_depth,
             hash_params: self.hash_params,
+            commit_inputs: self.commit_inputs,
             queue: executed.clone(),
             old_accum_hash: Some(old_hash),
             new_accum_hash: Some(new_hash),
@@ -373,18 +1506,54 @@ impl<'a> Operator<'a> {
         */
         // generate proof -------------------------------------------
 
-        let mut rng = thread_rng();
-        let proof = create_random_proof(circuit, self.onchain_withdrawal_circuit_params, &mut rng)?;
+        let proof = match recorded_proof {
+            Some(proof) => proof,
+            None => create_random_proof(circuit, self.onchain_withdrawal_circuit_params, &mut thread_rng())?,
+        };
         
         let mut public_inputs = vec![old_hash, new_hash, old_root, new_root];
-        for i in 0..self.onchain_withdrawal_batch {
+        for executed_withdrawal in executed.iter() {
             let mut inputs = vec![
-                executed[i].account_id.unwrap(), 
-                executed[i].amount.unwrap(),
+                executed_withdrawal.account_id.unwrap(),
+                executed_withdrawal.token_id.unwrap(),
+                executed_withdrawal.amount.unwrap(),
             ];
             public_inputs.append(&mut inputs);
         }
 
+        // check proof before publishing it
+
+        let public_inputs = self.expose_public_inputs(public_inputs);
+        verify_batch_proof(&self.onchain_withdrawal_circuit_params.vk, &public_inputs, &proof)?;
+
+        // commit new state -----------------------------------------
+
+        let record = BatchRecord {
+            batch_type: CircuitType::OnchainWithdrawal,
+            padded: allow_padding,
+            batch_number: self.batch_number,
+            deposits: Vec::new(),
+            transfers: Vec::new(),
+            offchain_withdrawals: Vec::new(),
+            onchain_withdrawals: withdrawals,
+            old_root,
+            new_root,
+            old_deposit_accum_hash: self.deposit_accum_hash,
+            new_deposit_accum_hash: self.deposit_accum_hash,
+            old_withdrawal_accum_hash: old_hash,
+            new_withdrawal_accum_hash: new_hash,
+            proof: proof.clone(),
+        };
+        self.append_log(LogRecord::Batch(Box::new(record.clone())))?;
+
+        self.tree = tree;
+        self.withdrawal_accum_hash = new_hash;
+        self.onchain_withdrawal_queue.drain(..num_requests);
+        self.remove_admitted(CircuitType::OnchainWithdrawal, 0, num_requests);
+        self.batch_number += 1;
+        self.refresh_pending_state();
+        self.history.push(record);
+
         // TODO send new state to smart contract --------------------
 
         Ok((public_inputs, proof))
@@ -393,66 +1562,132 @@ impl<'a> Operator<'a> {
     pub fn execute_offchain_withdrawal_batch(
         &mut self,
     ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
-        
-        if self.offchain_withdrawal_queue.len() < self.offchain_withdrawal_batch {
-            return Err(OperatorError::NotEnoughObjects);
-        }
+        self.process_offchain_withdrawal_batch(false, None)
+    }
+
+    pub fn close_offchain_withdrawal_batch(
+        &mut self,
+    ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
+        self.process_offchain_withdrawal_batch(true, None)
+    }
+
+    // with a recorded proof the batch is replayed from the log without proving
+    fn process_offchain_withdrawal_batch(
+        &mut self,
+        allow_padding: bool,
+        recorded_proof: Option::<Proof<Bn256>>,
+    ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
+        let num_requests = Self::batch_requests_count(
+            self.offchain_withdrawal_queue.len(),
+            self.offchain_withdrawal_batch,
+            allow_padding,
+        )?;
 
         // update local tree ----------------------------------------
 
-        let old_root = self.tree.get_root();
+        let mut tree = self.tree.clone();
+        let old_root = tree.get_root();
         let mut executed = Vec::new();
 
-        for _ in 0..self.offchain_withdrawal_batch {
-            let withdrawal = self.offchain_withdrawal_queue.remove(0);
+        let mut fees = 0;
 
-            self.check_offchain_withdrawal_signature(&withdrawal)?;
+        let mut batch = self.offchain_withdrawal_queue[..num_requests].to_vec();
+        batch.append(&mut self.padding_offchain_withdrawals(
+            self.offchain_withdrawal_batch - num_requests,
+        )?);
 
-            let account_state = withdrawal.update_tree_and_record_state(&mut self.tree);
+        for (i, withdrawal) in batch.iter().enumerate() {
+            if let Err(error) = self.check_offchain_withdrawal_on_tree(&tree, withdrawal) {
+                return Err(self.evict_request(CircuitType::OffchainWithdrawal, |oper| &mut oper.offchain_withdrawal_queue, i, num_requests, error));
+            }
 
-            let pubkey = self.tree.get_pubkey(withdrawal.account_id);
+            let account_state = withdrawal.update_tree_and_record_state(&mut tree)?;
+            fees = self.add_batch_fee(fees, withdrawal.fee)?;
+
+            let pubkey = tree.get_pubkey(withdrawal.account_id)?;
 
             let executed_withdrawal = OffchainWithdrawalCircuit {
                 account_state,
                 account_id: Some(usize_to_fr(withdrawal.account_id)),
-                amount: Some(usize_to_fr(withdrawal.amount)),
+                token_id: Some(usize_to_fr(withdrawal.token_id)),
+                amount: Some(u128_to_fr(withdrawal.amount)),
+                fee: Some(u128_to_fr(withdrawal.fee)),
                 nonce: Some(usize_to_fr(withdrawal.nonce)),
-                sign: Some(withdrawal.sign.unwrap()),
+                sign: Some(withdrawal.sign.clone().unwrap()),
                 pubkey: Some(pubkey.0),
             };
 
             executed.push(executed_withdrawal);
         }
 
-        let new_root = self.tree.get_root();
+        let fee = self.credit_fees(&mut tree, fees)?;
+        let new_root = tree.get_root();
 
         // prepare snark input
 
         let circuit = OffchainWithdrawalBatchCircuit {
             batch_size: self.offchain_withdrawal_batch,
             account_depth: self.account_depth,
+            balance_depth: self.balance_depth,
             hash_params: self.hash_params,
             sign_params: self.sign_params,
+            commit_inputs: self.commit_inputs,
 
             queue: executed.clone(),
+            fee,
             old_account_root: Some(old_root),
             new_account_root: Some(new_root),
         };
         
         // generate proof -------------------------------------------
 
-        let mut rng = thread_rng();
-        let proof = create_random_proof(circuit, self.offchain_withdrawal_circuit_params, &mut rng)?;
+        let proof = match recorded_proof {
+            Some(proof) => proof,
+            None => create_random_proof(circuit, self.offchain_withdrawal_circuit_params, &mut thread_rng())?,
+        };
         
         let mut public_inputs = vec![old_root, new_root];
-        for i in 0..self.offchain_withdrawal_batch {
+        for executed_withdrawal in executed.iter() {
             let mut inputs = vec![
-                executed[i].account_id.unwrap(),
-                executed[i].amount.unwrap(),
+                executed_withdrawal.account_id.unwrap(),
+                executed_withdrawal.token_id.unwrap(),
+                executed_withdrawal.amount.unwrap(),
             ];
             public_inputs.append(&mut inputs);
         }
 
+        // check proof before publishing it
+
+        let public_inputs = self.expose_public_inputs(public_inputs);
+        verify_batch_proof(&self.offchain_withdrawal_circuit_params.vk, &public_inputs, &proof)?;
+
+        // commit new state -----------------------------------------
+
+        let record = BatchRecord {
+            batch_type: CircuitType::OffchainWithdrawal,
+            padded: allow_padding,
+            batch_number: self.batch_number,
+            deposits: Vec::new(),
+            transfers: Vec::new(),
+            offchain_withdrawals: self.offchain_withdrawal_queue[..num_requests].to_vec(),
+            onchain_withdrawals: Vec::new(),
+            old_root,
+            new_root,
+            old_deposit_accum_hash: self.deposit_accum_hash,
+            new_deposit_accum_hash: self.deposit_accum_hash,
+            old_withdrawal_accum_hash: self.withdrawal_accum_hash,
+            new_withdrawal_accum_hash: self.withdrawal_accum_hash,
+            proof: proof.clone(),
+        };
+        self.append_log(LogRecord::Batch(Box::new(record.clone())))?;
+
+        self.tree = tree;
+        self.offchain_withdrawal_queue.drain(..num_requests);
+        self.remove_admitted(CircuitType::OffchainWithdrawal, 0, num_requests);
+        self.batch_number += 1;
+        self.refresh_pending_state();
+        self.history.push(record);
+
         // TODO send new state to smart contract --------------------
 
         Ok((public_inputs, proof))
@@ -460,16 +1695,17 @@ impl<'a> Operator<'a> {
 
     fn check_transfer_signature(
         &self,
-        transfer: &Transfer
+        transfer: &Transfer,
+        pubkey: &PublicKey::<Bn256>,
     ) -> Result<(), OperatorError> {
-        let pubkey = &self.tree.get_pubkey(transfer.account_id_from);
-
         if !transfer.verify_signature(
             pubkey,
             self.hash_params,
             self.sign_params,
         ) {
-            return Err(OperatorError::InvalidSignature);
+            return Err(OperatorError::InvalidSignature {
+                account_id: transfer.account_id_from,
+            });
         }
 
         Ok(())
@@ -477,16 +1713,17 @@ impl<'a> Operator<'a> {
 
     fn check_offchain_withdrawal_signature(
         &self,
-        withdrawal: &OffchainWithdrawal
+        withdrawal: &OffchainWithdrawal,
+        pubkey: &PublicKey::<Bn256>,
     ) -> Result<(), OperatorError> {
-        let pubkey = &self.tree.get_pubkey(withdrawal.account_id);
-
         if !withdrawal.verify_signature(
             pubkey,
             self.hash_params,
             self.sign_params,
         ) {
-            return Err(OperatorError::InvalidSignature);
+            return Err(OperatorError::InvalidSignature {
+                account_id: withdrawal.account_id,
+            });
         }
 
         Ok(())
@@ -495,61 +1732,469 @@ impl<'a> Operator<'a> {
     pub fn execute_transfer_batch(
         &mut self,
     ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
-        
-        if self.transfer_queue.len() < self.transfer_batch {
-            return Err(OperatorError::NotEnoughObjects);
-        }
+        self.process_transfer_batch(false, None)
+    }
+
+    pub fn close_transfer_batch(
+        &mut self,
+    ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
+        self.process_transfer_batch(true, None)
+    }
+
+    // with a recorded proof the batch is replayed from the log without proving
+    fn process_transfer_batch(
+        &mut self,
+        allow_padding: bool,
+        recorded_proof: Option::<Proof<Bn256>>,
+    ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
+        let num_requests = Self::batch_requests_count(
+            self.transfer_queue.len(),
+            self.transfer_batch,
+            allow_padding,
+        )?;
 
         // update local tree ----------------------------------------
 
-        let old_root = self.tree.get_root();
+        let mut tree = self.tree.clone();
+        let old_root = tree.get_root();
         let mut executed = Vec::new();
 
-        for _ in 0..self.transfer_batch {
-            let transfer = self.transfer_queue.remove(0);
+        let mut fees = 0;
 
-            self.check_transfer_signature(&transfer)?;
+        let mut batch = self.transfer_queue[..num_requests].to_vec();
+        batch.append(&mut self.padding_transfers(self.transfer_batch - num_requests)?);
 
-            let (account_state_from, account_state_to) = transfer.update_tree_and_record_state(&mut self.tree);
+        for (i, transfer) in batch.iter().enumerate() {
+            if let Err(error) = self.check_transfer_on_tree(&tree, transfer) {
+                return Err(self.evict_request(CircuitType::Transfer, |oper| &mut oper.transfer_queue, i, num_requests, error));
+            }
+
+            let (account_state_from, account_state_to) = transfer.update_tree_and_record_state(&mut tree)?;
+            fees = self.add_batch_fee(fees, transfer.fee)?;
 
-            let pubkey = self.tree.get_pubkey(transfer.account_id_from);
+            let pubkey = tree.get_pubkey(transfer.account_id_from)?;
+            let new_account = account_state_to.old_pubkey != account_state_to.new_pubkey;
 
             let executed_transfer = TransferCircuit {
                 account_state_from,
                 account_state_to,
                 account_id_from: Some(usize_to_fr(transfer.account_id_from)),
                 account_id_to: Some(usize_to_fr(transfer.account_id_to)),
-                amount: Some(usize_to_fr(transfer.amount)),
+                pubkey_to: Some(tree.get_pubkey(transfer.account_id_to)?.0),
+                by_pubkey: Some(transfer.pubkey_to.is_some()),
+                new_account: Some(new_account),
+                token_id: Some(usize_to_fr(transfer.token_id)),
+                amount: Some(u128_to_fr(transfer.amount)),
+                fee: Some(u128_to_fr(transfer.fee)),
                 nonce: Some(usize_to_fr(transfer.nonce)),
-                sign: Some(transfer.sign.unwrap()),
+                sign: Some(transfer.sign.clone().unwrap()),
                 pubkey: Some(pubkey.0),
             };
 
             executed.push(executed_transfer);
         }
 
-        let new_root = self.tree.get_root();
+        let fee = self.credit_fees(&mut tree, fees)?;
+        let new_root = tree.get_root();
 
         // prepare snark input
 
         let circuit = TransferBatchCircuit {
             batch_size: self.transfer_batch,
             account_depth: self.account_depth,
+            balance_depth: self.balance_depth,
             hash_params: self.hash_params,
             sign_params: self.sign_params,
+            commit_inputs: self.commit_inputs,
             queue: executed.clone(),
+            fee,
             old_account_root: Some(old_root),
             new_account_root: Some(new_root),
         };
         
         // generate proof -------------------------------------------
 
-        let mut rng = thread_rng();
-        let proof = create_random_proof(circuit, self.transfer_circuit_params, &mut rng)?;
+        let proof = match recorded_proof {
+            Some(proof) => proof,
+            None => create_random_proof(circuit, self.transfer_circuit_params, &mut thread_rng())?,
+        };
         let public_inputs = vec![old_root, new_root];
 
+        // check proof before publishing it
+
+        let public_inputs = self.expose_public_inputs(public_inputs);
+        verify_batch_proof(&self.transfer_circuit_params.vk, &public_inputs, &proof)?;
+
+        // commit new state -----------------------------------------
+
+        let record = BatchRecord {
+            batch_type: CircuitType::Transfer,
+            padded: allow_padding,
+            batch_number: self.batch_number,
+            deposits: Vec::new(),
+            transfers: self.transfer_queue[..num_requests].to_vec(),
+            offchain_withdrawals: Vec::new(),
+            onchain_withdrawals: Vec::new(),
+            old_root,
+            new_root,
+            old_deposit_accum_hash: self.deposit_accum_hash,
+            new_deposit_accum_hash: self.deposit_accum_hash,
+            old_withdrawal_accum_hash: self.withdrawal_accum_hash,
+            new_withdrawal_accum_hash: self.withdrawal_accum_hash,
+            proof: proof.clone(),
+        };
+        self.append_log(LogRecord::Batch(Box::new(record.clone())))?;
+
+        self.tree = tree;
+        self.transfer_queue.drain(..num_requests);
+        self.remove_admitted(CircuitType::Transfer, 0, num_requests);
+        self.batch_number += 1;
+        self.refresh_pending_state();
+        self.history.push(record);
+
         // TODO send new state to smart contract --------------------
 
         Ok((public_inputs, proof))
     }
+
+    // block execution --------------------------------------------------------
+    //
+    // A block takes queued requests in order deposits, transfers, offchain
+    // withdrawals, onchain withdrawals and fills remaining slots with no-ops.
+    // Execution is transactional in the same way as for batches.
+
+    // operations without a user signature are signed with the padding key
+    fn operator_signature(
+        &self,
+        account_id: usize,
+        token_id: usize,
+        amount: u128,
+    ) -> Signature::<Bn256> {
+        let mut message = OffchainWithdrawal {
+            account_id,
+            token_id,
+            amount,
+            fee: 0,
+            nonce: 0,
+            sign: None,
+        };
+        message.sign(&PrivateKey::<Bn256>(self.padding_key), self.hash_params, self.sign_params);
+        message.sign.unwrap()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn unsigned_operation(
+        &self,
+        tree: &AccountsTree,
+        op_type: OperationType,
+        account_state_from: AccountState::<Bn256>,
+        account_id: usize,
+        token_id: usize,
+        amount: u128,
+        deposit_pubkey: PublicKey::<Bn256>,
+    ) -> Result<BlockOperationCircuit::<Bn256>, TreeError> {
+        let new_account = account_state_from.old_pubkey != account_state_from.new_pubkey;
+
+        Ok(BlockOperationCircuit {
+            op_type: Some(op_type),
+            account_state_from,
+            account_state_to: tree.record_account_state(account_id, token_id)?,
+            account_id_from: Some(usize_to_fr(account_id)),
+            account_id_to: Some(usize_to_fr(account_id)),
+            pubkey_to: Some(tree.get_pubkey(account_id)?.0),
+            by_pubkey: Some(false),
+            new_account_from: Some(new_account),
+            new_account_to: Some(false),
+            token_id: Some(usize_to_fr(token_id)),
+            amount: Some(u128_to_fr(amount)),
+            fee: Some(bn256::Fr::zero()),
+            nonce: Some(bn256::Fr::zero()),
+            deposit_pubkey: Some(deposit_pubkey.0),
+            sign: Some(self.operator_signature(account_id, token_id, amount)),
+            pubkey: Some(self.padding_pubkey()?.0),
+        })
+    }
+
+    pub fn execute_block(
+        &mut self,
+    ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
+        self.process_block(None)
+    }
+
+    fn process_block(
+        &mut self,
+        recorded_proof: Option::<Proof<Bn256>>,
+    ) -> Result<(Vec::<bn256::Fr>, Proof<Bn256>), OperatorError> {
+        let block_circuit_params = self.block_circuit_params
+            .ok_or(OperatorError::BlockCircuitNotSet)?;
+
+        let mut free_slots = self.block_size;
+        let num_deposits = self.deposit_queue.len().min(free_slots);
+        free_slots -= num_deposits;
+        let num_transfers = self.transfer_queue.len().min(free_slots);
+        free_slots -= num_transfers;
+        let num_offchain_withdrawals = self.offchain_withdrawal_queue.len().min(free_slots);
+        free_slots -= num_offchain_withdrawals;
+        let num_onchain_withdrawals = self.onchain_withdrawal_queue.len().min(free_slots);
+        free_slots -= num_onchain_withdrawals;
+
+        if free_slots == self.block_size {
+            return Err(OperatorError::NotEnoughObjects {
+                available: 0,
+                required: 1,
+            });
+        }
+
+        // update local tree ----------------------------------------
+
+        let mut tree = self.tree.clone();
+        let old_root = tree.get_root();
+        let old_deposit_hash = self.deposit_accum_hash;
+        let old_withdrawal_hash = self.withdrawal_accum_hash;
+        let mut deposit_hash = old_deposit_hash;
+        let mut withdrawal_hash = old_withdrawal_hash;
+        let mut executed = Vec::new();
+        let mut fees = 0;
+
+        let deposits = self.deposit_queue[..num_deposits].to_vec();
+        for (i, deposit) in deposits.iter().enumerate() {
+            let pubkey = match self.check_deposit_on_tree(&tree, deposit) {
+                Ok(pubkey) => pubkey,
+                Err(error) => {
+                    return Err(self.evict_request(CircuitType::Deposit, |oper| &mut oper.deposit_queue, i, num_deposits, error));
+                },
+            };
+
+            let (pubkey_x, pubkey_y) = pubkey.0.into_xy();
+
+            deposit_hash = {
+                let hashes_vec = poseidon_hash::<Bn256>(
+                    self.hash_params,
+                    &[
+                        deposit_hash,
+                        pubkey_x,
+                        pubkey_y,
+                        usize_to_fr(deposit.account_id),
+                        usize_to_fr(deposit.token_id),
+                        u128_to_fr(deposit.amount),
+                    ],
+                );
+                hashes_vec[0]
+            };
+
+            let account_state = deposit.update_tree_and_record_state(&mut tree)?;
+
+            executed.push(self.unsigned_operation(
+                &tree,
+                OperationType::Deposit,
+                account_state,
+                deposit.account_id,
+                deposit.token_id,
+                deposit.amount,
+                pubkey.clone(),
+            )?);
+        }
+
+        let transfers = self.transfer_queue[..num_transfers].to_vec();
+        for (i, transfer) in transfers.iter().enumerate() {
+            if let Err(error) = self.check_transfer_on_tree(&tree, transfer) {
+                return Err(self.evict_request(CircuitType::Transfer, |oper| &mut oper.transfer_queue, i, num_transfers, error));
+            }
+
+            let (account_state_from, account_state_to) = transfer.update_tree_and_record_state(&mut tree)?;
+            fees = self.add_batch_fee(fees, transfer.fee)?;
+
+            let pubkey = tree.get_pubkey(transfer.account_id_from)?;
+            let new_account = account_state_to.old_pubkey != account_state_to.new_pubkey;
+
+            executed.push(BlockOperationCircuit {
+                op_type: Some(OperationType::Transfer),
+                account_state_from,
+                account_state_to,
+                account_id_from: Some(usize_to_fr(transfer.account_id_from)),
+                account_id_to: Some(usize_to_fr(transfer.account_id_to)),
+                pubkey_to: Some(tree.get_pubkey(transfer.account_id_to)?.0),
+                by_pubkey: Some(transfer.pubkey_to.is_some()),
+                new_account_from: Some(false),
+                new_account_to: Some(new_account),
+                token_id: Some(usize_to_fr(transfer.token_id)),
+                amount: Some(u128_to_fr(transfer.amount)),
+                fee: Some(u128_to_fr(transfer.fee)),
+                nonce: Some(usize_to_fr(transfer.nonce)),
+                deposit_pubkey: Some(pubkey.0.clone()),
+                sign: Some(transfer.sign.clone().unwrap()),
+                pubkey: Some(pubkey.0),
+            });
+        }
+
+        let offchain_withdrawals = self.offchain_withdrawal_queue[..num_offchain_withdrawals].to_vec();
+        for (i, withdrawal) in offchain_withdrawals.iter().enumerate() {
+            if let Err(error) = self.check_offchain_withdrawal_on_tree(&tree, withdrawal) {
+                return Err(self.evict_request(CircuitType::OffchainWithdrawal, |oper| &mut oper.offchain_withdrawal_queue, i, num_offchain_withdrawals, error));
+            }
+
+            let account_state_from = withdrawal.update_tree_and_record_state(&mut tree)?;
+            fees = self.add_batch_fee(fees, withdrawal.fee)?;
+
+            let pubkey = tree.get_pubkey(withdrawal.account_id)?;
+
+            executed.push(BlockOperationCircuit {
+                op_type: Some(OperationType::OffchainWithdrawal),
+                account_state_from,
+                account_state_to: tree.record_account_state(withdrawal.account_id, withdrawal.token_id)?,
+                account_id_from: Some(usize_to_fr(withdrawal.account_id)),
+                account_id_to: Some(usize_to_fr(withdrawal.account_id)),
+                pubkey_to: Some(pubkey.0.clone()),
+                by_pubkey: Some(false),
+                new_account_from: Some(false),
+                new_account_to: Some(false),
+                token_id: Some(usize_to_fr(withdrawal.token_id)),
+                amount: Some(u128_to_fr(withdrawal.amount)),
+                fee: Some(u128_to_fr(withdrawal.fee)),
+                nonce: Some(usize_to_fr(withdrawal.nonce)),
+                deposit_pubkey: Some(pubkey.0.clone()),
+                sign: Some(withdrawal.sign.clone().unwrap()),
+                pubkey: Some(pubkey.0),
+            });
+        }
+
+        let mut onchain_withdrawals = self.onchain_withdrawal_queue[..num_onchain_withdrawals].to_vec();
+        for (i, withdrawal) in onchain_withdrawals.iter_mut().enumerate() {
+            if let Err(error) = Self::check_onchain_withdrawal_on_tree(&tree, withdrawal) {
+                return Err(self.evict_request(CircuitType::OnchainWithdrawal, |oper| &mut oper.onchain_withdrawal_queue, i, num_onchain_withdrawals, error));
+            }
+
+            withdrawal_hash = {
+                let hashes_vec = poseidon_hash::<Bn256>(
+                    self.hash_params,
+                    &[
+                        withdrawal_hash,
+                        usize_to_fr(withdrawal.account_id),
+                        usize_to_fr(withdrawal.token_id),
+                    ],
+                );
+                hashes_vec[0]
+            };
+
+            // onchain withdrawal takes all value
+            let amount = tree.get_balance(withdrawal.account_id, withdrawal.token_id)?;
+            withdrawal.amount = Some(amount);
+
+            let account_state = withdrawal.update_tree_and_record_state(&mut tree)?;
+
+            executed.push(self.unsigned_operation(
+                &tree,
+                OperationType::OnchainWithdrawal,
+                account_state,
+                withdrawal.account_id,
+                withdrawal.token_id,
+                amount,
+                self.padding_pubkey()?,
+            )?);
+        }
+
+        for _ in 0..free_slots {
+            executed.push(self.unsigned_operation(
+                &tree,
+                OperationType::Noop,
+                tree.record_account_state(self.padding_account_id, 0)?,
+                self.padding_account_id,
+                0,
+                0,
+                self.padding_pubkey()?,
+            )?);
+        }
+
+        let fee = self.credit_fees(&mut tree, fees)?;
+        let new_root = tree.get_root();
+
+        // prepare snark input
+
+        let circuit = BlockCircuit {
+            block_size: self.block_size,
+            account_depth: self.account_depth,
+            balance_depth: self.balance_depth,
+            hash_params: self.hash_params,
+            sign_params: self.sign_params,
+            commit_inputs: self.commit_inputs,
+
+            operations: executed.clone(),
+            fee,
+            old_account_root: Some(old_root),
+            new_account_root: Some(new_root),
+            old_deposit_accum_hash: Some(old_deposit_hash),
+            new_deposit_accum_hash: Some(deposit_hash),
+            old_withdrawal_accum_hash: Some(old_withdrawal_hash),
+            new_withdrawal_accum_hash: Some(withdrawal_hash),
+        };
+
+        // generate proof -------------------------------------------
+
+        let proof = match recorded_proof {
+            Some(proof) => proof,
+            None => create_random_proof(circuit, block_circuit_params, &mut thread_rng())?,
+        };
+
+        let mut public_inputs = vec![
+            old_root,
+            new_root,
+            old_deposit_hash,
+            deposit_hash,
+            old_withdrawal_hash,
+            withdrawal_hash,
+        ];
+        for operation in executed.iter() {
+            let withdrawn = match operation.op_type {
+                Some(OperationType::OffchainWithdrawal) | Some(OperationType::OnchainWithdrawal) => vec![
+                    operation.account_id_from.unwrap(),
+                    operation.token_id.unwrap(),
+                    operation.amount.unwrap(),
+                ],
+                _ => vec![bn256::Fr::zero(); 3],
+            };
+            public_inputs.extend(withdrawn);
+        }
+
+        // check proof before publishing it
+
+        let public_inputs = self.expose_public_inputs(public_inputs);
+        verify_batch_proof(&block_circuit_params.vk, &public_inputs, &proof)?;
+
+        // commit new state -----------------------------------------
+
+        let record = BatchRecord {
+            batch_type: CircuitType::Block,
+            padded: true,
+            batch_number: self.batch_number,
+            deposits,
+            transfers,
+            offchain_withdrawals,
+            onchain_withdrawals,
+            old_root,
+            new_root,
+            old_deposit_accum_hash: old_deposit_hash,
+            new_deposit_accum_hash: deposit_hash,
+            old_withdrawal_accum_hash: old_withdrawal_hash,
+            new_withdrawal_accum_hash: withdrawal_hash,
+            proof: proof.clone(),
+        };
+        self.append_log(LogRecord::Batch(Box::new(record.clone())))?;
+
+        self.tree = tree;
+        self.deposit_accum_hash = deposit_hash;
+        self.withdrawal_accum_hash = withdrawal_hash;
+        self.deposit_queue.drain(..num_deposits);
+        self.transfer_queue.drain(..num_transfers);
+        self.offchain_withdrawal_queue.drain(..num_offchain_withdrawals);
+        self.onchain_withdrawal_queue.drain(..num_onchain_withdrawals);
+        self.remove_admitted(CircuitType::Deposit, 0, num_deposits);
+        self.remove_admitted(CircuitType::Transfer, 0, num_transfers);
+        self.remove_admitted(CircuitType::OffchainWithdrawal, 0, num_offchain_withdrawals);
+        self.remove_admitted(CircuitType::OnchainWithdrawal, 0, num_onchain_withdrawals);
+        self.batch_number += 1;
+        self.refresh_pending_state();
+        self.history.push(record);
+
+        Ok((public_inputs, proof))
+    }
 }
@@ -0,0 +1,149 @@
+use bellman_ce::groth16::VerifyingKey;
+
+use pairing_ce::bn256::{ Bn256, G1Affine, G2Affine };
+
+use crate::keys::KeyHeader;
+use crate::abi::{ g1_to_words, g2_to_words, word_to_hex };
+
+// Points are encoded the way `Verifier.sol` expects them, see `abi`,
+// each coordinate as a 0x-prefixed 32-byte hex number.
+
+// number of uints of alpha_g1, beta_g2, gamma_g2, delta_g2 in a flat key
+pub const VK_POINTS_LENGTH: usize = 14;
+
+pub fn g1_to_uints(point: &G1Affine) -> Vec::<String> {
+    g1_to_words(point).iter().map(word_to_hex).collect()
+}
+
+pub fn g2_to_uints(point: &G2Affine) -> Vec::<String> {
+    g2_to_words(point).iter().map(word_to_hex).collect()
+}
+
+// flat key as registered in `BlockVerifier.sol`: alpha, beta, gamma, delta, ic
+pub fn verifying_key_to_uints(vk: &VerifyingKey<Bn256>) -> Vec::<String> {
+    let mut uints = Vec::with_capacity(VK_POINTS_LENGTH + 2 * vk.ic.len());
+    uints.append(&mut g1_to_uints(&vk.alpha_g1));
+    uints.append(&mut g2_to_uints(&vk.beta_g2));
+    uints.append(&mut g2_to_uints(&vk.gamma_g2));
+    uints.append(&mut g2_to_uints(&vk.delta_g2));
+    for ic in vk.ic.iter() {
+        uints.append(&mut g1_to_uints(ic));
+    }
+
+    uints
+}
+
+fn json_array(values: &[String]) -> String {
+    let quoted: Vec<_> = values.iter().map(|value| format!("\"{}\"", value)).collect();
+    format!("[{}]", quoted.join(", "))
+}
+
+pub fn verifying_key_to_json(
+    header: &KeyHeader,
+    vk: &VerifyingKey<Bn256>,
+) -> String {
+    let ic: Vec<_> = vk.ic.iter().map(|point| json_array(&g1_to_uints(point))).collect();
+
+    let mut json = String::new();
+    json.push_str("{\n");
+    json.push_str(&format!("  \"circuit\": \"{}\",\n", header.circuit_type.name()));
+    json.push_str(&format!("  \"batch_size\": {},\n", header.batch_size));
+    json.push_str(&format!("  \"account_depth\": {},\n", header.account_depth));
+    json.push_str(&format!("  \"balance_depth\": {},\n", header.balance_depth));
+    json.push_str(&format!("  \"commit_inputs\": {},\n", header.commit_inputs));
+    json.push_str(&format!("  \"num_inputs\": {},\n", header.num_inputs()));
+    json.push_str(&format!("  \"alpha_g1\": {},\n", json_array(&g1_to_uints(&vk.alpha_g1))));
+    json.push_str(&format!("  \"beta_g2\": {},\n", json_array(&g2_to_uints(&vk.beta_g2))));
+    json.push_str(&format!("  \"gamma_g2\": {},\n", json_array(&g2_to_uints(&vk.gamma_g2))));
+    json.push_str(&format!("  \"delta_g2\": {},\n", json_array(&g2_to_uints(&vk.delta_g2))));
+    json.push_str(&format!("  \"ic\": [{}],\n", ic.join(", ")));
+    json.push_str(&format!("  \"vk\": {}\n", json_array(&verifying_key_to_uints(vk))));
+    json.push_str("}\n");
+
+    json
+}
+
+// default contract name, e.g. `DepositVerifierB2D24T8`
+pub fn verifier_contract_name(header: &KeyHeader) -> String {
+    let name: String = header.circuit_type.name().split('_').map(|word| {
+        let mut chars = word.chars();
+        match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+            None => String::new(),
+        }
+    }).collect();
+
+    format!(
+        "{}VerifierB{}D{}T{}{}",
+        name,
+        header.batch_size,
+        header.account_depth,
+        header.balance_depth,
+        if header.commit_inputs { "C" } else { "" },
+    )
+}
+
+// Solidity verifier with the key embedded, built on `lib/Verifier.sol`
+pub fn generate_verifier_contract(
+    header: &KeyHeader,
+    vk: &VerifyingKey<Bn256>,
+) -> String {
+    let uints = verifying_key_to_uints(vk);
+    let (points, gamma_abc) = uints.split_at(VK_POINTS_LENGTH);
+
+    let points: Vec<_> = points.iter().map(
+        |value| format!("            uint({})", value)
+    ).collect();
+
+    let gamma_abc: Vec<_> = gamma_abc.iter().enumerate().map(
+        |(i, value)| format!("        vkGammaAbc[{}] = {};", i, value)
+    ).collect();
+
+    format!(
+r#"pragma solidity ^0.5.16;
+
+import "./lib/Verifier.sol";
+
+
+// Generated for {header}, do not edit
+contract {name}
+{{
+    uint constant public NUM_INPUTS = {num_inputs};
+
+    function verificationKey()
+        internal
+        pure
+        returns (uint[14] memory vk, uint[] memory vkGammaAbc)
+    {{
+        vk = [
+{points}
+        ];
+
+        vkGammaAbc = new uint[]({gamma_abc_length});
+{gamma_abc}
+    }}
+
+    function verifyProof(
+        uint[] calldata publicInputs,
+        uint[] calldata proof
+        )
+        external
+        view
+        returns (bool)
+    {{
+        require(publicInputs.length == NUM_INPUTS, "INVALID_INPUTS_LENGTH");
+        require(proof.length == 8, "INVALID_PROOF_LENGTH");
+
+        (uint[14] memory vk, uint[] memory vkGammaAbc) = verificationKey();
+        return Verifier.Verify(vk, vkGammaAbc, proof, publicInputs);
+    }}
+}}
+"#,
+        header = header,
+        name = verifier_contract_name(header),
+        num_inputs = header.num_inputs(),
+        points = points.join(",\n"),
+        gamma_abc_length = gamma_abc.len(),
+        gamma_abc = gamma_abc.join("\n"),
+    )
+}
@@ -0,0 +1,767 @@
+use std::fmt;
+use std::io::{ self, Read, Write };
+use std::error::Error;
+use std::fs::{ self, File, OpenOptions };
+use std::path::Path;
+
+use sapling_crypto_ce::{
+    poseidon::bn256::Bn256PoseidonParams,
+    alt_babyjubjub::AltJubjubBn256,
+    eddsa::{ PublicKey, Signature },
+    jubjub::{ JubjubEngine, edwards::Point },
+};
+
+use bellman_ce::groth16::Proof;
+
+use ff_ce::{ Field, PrimeField, PrimeFieldRepr };
+
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use sha2::{ Sha256, Digest };
+
+use crate::tree::account::{ AccountsTree, TreeError };
+use crate::keys::CircuitType;
+use crate::history::BlockHistory;
+use crate::data_structs::{
+    deposit::Deposit,
+    transfer::Transfer,
+    offchain_withdrawal::OffchainWithdrawal,
+    onchain_withdrawal::OnchainWithdrawal,
+};
+
+// State files hold the committed operator state. Only stored accounts are
+// written, the tree nodes are rebuilt from them on load and the resulting
+// root must match the recorded one:
+//
+//   magic (4 bytes) | version (1) | account depth (u32 BE) | balance depth (u32 BE)
+//   | batch number (u64 BE) | deposit accum hash (32) | withdrawal accum hash (32)
+//   | root (32) | number of accounts (u64 BE) | accounts
+//   | number of blocks (u64 BE) | blocks
+//
+// each account is
+//
+//   account id (u64 BE) | pubkey (32) | nonce (32) | number of balances (u32 BE)
+//   | non-zero balances as token id (u32 BE) | balance (u128 BE)
+//
+// blocks are the operator history encoded as batch records of the log.
+
+const STATE_MAGIC: [u8; 4] = *b"OPST";
+const STATE_VERSION: u8 = 2;
+
+const LOG_MAGIC: [u8; 4] = *b"OPLG";
+const LOG_VERSION: u8 = 2;
+const LOG_HEADER_BYTES: usize = 5;
+const CHECKSUM_BYTES: usize = 32;
+
+#[derive(Clone)]
+pub struct OperatorState<'a> {
+    pub tree: AccountsTree<'a>,
+    pub deposit_accum_hash: bn256::Fr,
+    pub withdrawal_accum_hash: bn256::Fr,
+    pub batch_number: usize,
+    pub history: BlockHistory,
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    InvalidHeader,
+    InvalidData,
+    DepthMismatch { expected: (usize, usize), found: (usize, usize) },
+    RootMismatch { expected: bn256::Fr, found: bn256::Fr },
+    CorruptedLog { offset: usize },
+    IoError(io::Error),
+}
+
+impl Error for StorageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StorageError::IoError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            StorageError::InvalidHeader => write!(f, "Invalid state file header"),
+            StorageError::InvalidData => write!(f, "Invalid state file data"),
+            StorageError::DepthMismatch { expected, found } => {
+                write!(
+                    f,
+                    "State has account depth {} and balance depth {}, expected {} and {}",
+                    found.0, found.1, expected.0, expected.1,
+                )
+            },
+            StorageError::RootMismatch { expected, found } => {
+                write!(f, "Restored root {} doesn't match recorded root {}", found, expected)
+            },
+            StorageError::CorruptedLog { offset } => {
+                write!(f, "Log record at offset {} is corrupted", offset)
+            },
+            StorageError::IoError(e) => {
+                write!(f, "I/O error: ")?;
+                e.fmt(f)
+            },
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(err: io::Error) -> Self {
+        StorageError::IoError(err)
+    }
+}
+
+// stored ids out of the tree range
+impl From<TreeError> for StorageError {
+    fn from(_: TreeError) -> Self {
+        StorageError::InvalidData
+    }
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: usize) -> io::Result<()> {
+    writer.write_all(&(value as u32).to_be_bytes())
+}
+
+fn write_u64<W: Write>(writer: &mut W, value: usize) -> io::Result<()> {
+    writer.write_all(&(value as u64).to_be_bytes())
+}
+
+fn write_fr<W: Write>(writer: &mut W, value: &bn256::Fr) -> io::Result<()> {
+    value.into_repr().write_be(writer)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<usize> {
+    let mut word = [0u8; 4];
+    reader.read_exact(&mut word)?;
+    Ok(u32::from_be_bytes(word) as usize)
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<usize> {
+    let mut word = [0u8; 8];
+    reader.read_exact(&mut word)?;
+    Ok(u64::from_be_bytes(word) as usize)
+}
+
+fn read_u128<R: Read>(reader: &mut R) -> io::Result<u128> {
+    let mut word = [0u8; 16];
+    reader.read_exact(&mut word)?;
+    Ok(u128::from_be_bytes(word))
+}
+
+fn write_u128<W: Write>(writer: &mut W, value: u128) -> io::Result<()> {
+    writer.write_all(&value.to_be_bytes())
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut byte = [0u8; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_bool<R: Read>(reader: &mut R) -> Result<bool, StorageError> {
+    match read_u8(reader)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(StorageError::InvalidData),
+    }
+}
+
+fn read_fr<R: Read>(reader: &mut R) -> Result<bn256::Fr, StorageError> {
+    let mut repr = bn256::Fr::zero().into_repr();
+    repr.read_be(reader)?;
+    bn256::Fr::from_repr(repr).map_err(|_| StorageError::InvalidData)
+}
+
+// serialization ------------------------------------------------------------------------
+
+pub fn write_state<W: Write>(
+    mut writer: W,
+    tree: &AccountsTree,
+    deposit_accum_hash: &bn256::Fr,
+    withdrawal_accum_hash: &bn256::Fr,
+    batch_number: usize,
+    history: &BlockHistory,
+) -> Result<(), StorageError> {
+    writer.write_all(&STATE_MAGIC)?;
+    writer.write_all(&[STATE_VERSION])?;
+    write_u32(&mut writer, tree.account_depth)?;
+    write_u32(&mut writer, tree.balance_depth)?;
+    write_u64(&mut writer, batch_number)?;
+    write_fr(&mut writer, deposit_accum_hash)?;
+    write_fr(&mut writer, withdrawal_accum_hash)?;
+    write_fr(&mut writer, &tree.get_root())?;
+
+    let mut account_ids: Vec<_> = tree.accounts.keys().cloned().collect();
+    account_ids.sort_unstable();
+
+    write_u64(&mut writer, account_ids.len())?;
+    for account_id in account_ids {
+        let account = tree.get_account(account_id)?;
+        let balances: Vec<_> = account.balances.iter().enumerate()
+            .filter(|(_, balance)| **balance != 0)
+            .collect();
+
+        write_u64(&mut writer, account_id)?;
+        account.pubkey.write(&mut writer)?;
+        write_fr(&mut writer, &account.nonce)?;
+        write_u32(&mut writer, balances.len())?;
+        for (token_id, balance) in balances {
+            write_u32(&mut writer, token_id)?;
+            write_u128(&mut writer, *balance)?;
+        }
+    }
+
+    write_u64(&mut writer, history.len())?;
+    for block in history.blocks.iter() {
+        write_batch_record(&mut writer, block)?;
+    }
+
+    Ok(())
+}
+
+pub fn read_state<'a, R: Read>(
+    mut reader: R,
+    account_depth: usize,
+    balance_depth: usize,
+    hash_params: &'a Bn256PoseidonParams,
+    sign_params: &AltJubjubBn256,
+) -> Result<OperatorState<'a>, StorageError> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != STATE_MAGIC {
+        return Err(StorageError::InvalidHeader);
+    }
+
+    let mut version = [0u8; 1];
+    reader.read_exact(&mut version)?;
+    if version[0] != STATE_VERSION {
+        return Err(StorageError::InvalidHeader);
+    }
+
+    let found = (read_u32(&mut reader)?, read_u32(&mut reader)?);
+    if found != (account_depth, balance_depth) {
+        return Err(StorageError::DepthMismatch { expected: (account_depth, balance_depth), found });
+    }
+
+    let batch_number = read_u64(&mut reader)?;
+    let deposit_accum_hash = read_fr(&mut reader)?;
+    let withdrawal_accum_hash = read_fr(&mut reader)?;
+    let root = read_fr(&mut reader)?;
+
+    let mut tree = AccountsTree::new(account_depth, balance_depth, hash_params, sign_params);
+
+    let num_accounts = read_u64(&mut reader)?;
+    for _ in 0..num_accounts {
+        let account_id = read_u64(&mut reader)?;
+        if account_id >= tree.num_accounts() {
+            return Err(StorageError::InvalidData);
+        }
+
+        let pubkey = PublicKey::<Bn256>::read(&mut reader, sign_params)?;
+        let nonce = read_fr(&mut reader)?;
+        tree.update_account(account_id, pubkey, nonce)?;
+
+        let num_balances = read_u32(&mut reader)?;
+        for _ in 0..num_balances {
+            let token_id = read_u32(&mut reader)?;
+            if token_id >= tree.num_tokens() {
+                return Err(StorageError::InvalidData);
+            }
+            tree.update_balance(account_id, token_id, read_u128(&mut reader)?)?;
+        }
+    }
+
+    // integrity check, rebuilt tree must match the recorded root
+    if tree.get_root() != root {
+        return Err(StorageError::RootMismatch { expected: root, found: tree.get_root() });
+    }
+
+    let mut history = BlockHistory::new();
+    let num_blocks = read_u64(&mut reader)?;
+    for _ in 0..num_blocks {
+        history.push(read_batch_record(&mut reader, sign_params)?);
+    }
+
+    // history ends at the recorded state
+    if let Some(block) = history.latest() {
+        if block.new_root != root
+            || block.new_deposit_accum_hash != deposit_accum_hash
+            || block.new_withdrawal_accum_hash != withdrawal_accum_hash
+            || block.batch_number >= batch_number
+        {
+            return Err(StorageError::InvalidData);
+        }
+    }
+
+    Ok(OperatorState {
+        tree,
+        deposit_accum_hash,
+        withdrawal_accum_hash,
+        batch_number,
+        history,
+    })
+}
+
+// state is written to a temporary file first and renamed over the old one,
+// so a crash while saving keeps the previous state intact
+pub fn save_state<P: AsRef<Path>>(
+    path: P,
+    tree: &AccountsTree,
+    deposit_accum_hash: &bn256::Fr,
+    withdrawal_accum_hash: &bn256::Fr,
+    batch_number: usize,
+    history: &BlockHistory,
+) -> Result<(), StorageError> {
+    let path = path.as_ref();
+    let tmp_path = path.with_extension("tmp");
+
+    {
+        let mut writer = io::BufWriter::new(File::create(&tmp_path)?);
+        write_state(&mut writer, tree, deposit_accum_hash, withdrawal_accum_hash, batch_number, history)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+    }
+
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+pub fn load_state<'a, P: AsRef<Path>>(
+    path: P,
+    account_depth: usize,
+    balance_depth: usize,
+    hash_params: &'a Bn256PoseidonParams,
+    sign_params: &AltJubjubBn256,
+) -> Result<OperatorState<'a>, StorageError> {
+    let file = File::open(path)?;
+    read_state(io::BufReader::new(file), account_depth, balance_depth, hash_params, sign_params)
+}
+
+// write-ahead log ----------------------------------------------------------------------
+//
+// The log holds every admitted request and every executed batch in the order
+// they were accepted, so replaying it from the genesis state rebuilds the
+// tree and the queues. Records are appended after the header:
+//
+//   magic (4 bytes) | version (1) | records
+//
+// each record is
+//
+//   payload length (u32 BE) | payload | sha256 of payload (32)
+//
+// A crash while appending leaves a torn last record, it is dropped when the
+// log is loaded. A damaged record followed by other records is an error.
+
+#[derive(Clone)]
+pub enum LogRecord {
+    Deposit(Deposit),
+    Transfer(Transfer),
+    OffchainWithdrawal(OffchainWithdrawal),
+    OnchainWithdrawal(OnchainWithdrawal),
+    // request removed from its queue after failing in a batch
+    Eviction { queue: CircuitType, index: usize },
+    Batch(Box<BatchRecord>),
+}
+
+// executed batch or block, requests are in queue order without padding
+#[derive(Clone)]
+pub struct BatchRecord {
+    pub batch_type: CircuitType,
+    pub padded: bool,
+    pub batch_number: usize,
+    pub deposits: Vec<Deposit>,
+    pub transfers: Vec<Transfer>,
+    pub offchain_withdrawals: Vec<OffchainWithdrawal>,
+    pub onchain_withdrawals: Vec<OnchainWithdrawal>,
+    pub old_root: bn256::Fr,
+    pub new_root: bn256::Fr,
+    pub old_deposit_accum_hash: bn256::Fr,
+    pub new_deposit_accum_hash: bn256::Fr,
+    pub old_withdrawal_accum_hash: bn256::Fr,
+    pub new_withdrawal_accum_hash: bn256::Fr,
+    pub proof: Proof<Bn256>,
+}
+
+fn circuit_type_from_u8(value: u8) -> Result<CircuitType, StorageError> {
+    CircuitType::from_u8(value).ok_or(StorageError::InvalidData)
+}
+
+fn write_pubkey<W: Write>(writer: &mut W, pubkey: &Option<PublicKey<Bn256>>) -> io::Result<()> {
+    match pubkey {
+        Some(pubkey) => {
+            writer.write_all(&[1])?;
+            pubkey.write(writer)
+        },
+        None => writer.write_all(&[0]),
+    }
+}
+
+fn read_pubkey<R: Read>(
+    reader: &mut R,
+    sign_params: &AltJubjubBn256,
+) -> Result<Option<PublicKey<Bn256>>, StorageError> {
+    if !read_bool(reader)? {
+        return Ok(None);
+    }
+
+    Ok(Some(PublicKey::<Bn256>::read(reader, sign_params)?))
+}
+
+fn write_signature<W: Write>(writer: &mut W, sign: &Option<Signature<Bn256>>) -> io::Result<()> {
+    match sign {
+        Some(sign) => {
+            writer.write_all(&[1])?;
+            sign.r.write(&mut *writer)?;
+            sign.s.into_repr().write_le(writer)
+        },
+        None => writer.write_all(&[0]),
+    }
+}
+
+fn read_signature<R: Read>(
+    reader: &mut R,
+    sign_params: &AltJubjubBn256,
+) -> Result<Option<Signature<Bn256>>, StorageError> {
+    if !read_bool(reader)? {
+        return Ok(None);
+    }
+
+    let r = Point::read(&mut *reader, sign_params)?;
+    let mut repr = <Bn256 as JubjubEngine>::Fs::zero().into_repr();
+    repr.read_le(reader)?;
+    let s = <Bn256 as JubjubEngine>::Fs::from_repr(repr).map_err(|_| StorageError::InvalidData)?;
+
+    Ok(Some(Signature { r, s }))
+}
+
+fn write_deposit<W: Write>(writer: &mut W, deposit: &Deposit) -> io::Result<()> {
+    write_pubkey(writer, &deposit.pubkey)?;
+    write_u64(writer, deposit.account_id)?;
+    write_u32(writer, deposit.token_id)?;
+    write_u128(writer, deposit.amount)
+}
+
+fn read_deposit<R: Read>(
+    reader: &mut R,
+    sign_params: &AltJubjubBn256,
+) -> Result<Deposit, StorageError> {
+    Ok(Deposit {
+        pubkey: read_pubkey(reader, sign_params)?,
+        account_id: read_u64(reader)?,
+        token_id: read_u32(reader)?,
+        amount: read_u128(reader)?,
+    })
+}
+
+fn write_transfer<W: Write>(writer: &mut W, transfer: &Transfer) -> io::Result<()> {
+    write_u64(writer, transfer.account_id_from)?;
+    write_u64(writer, transfer.account_id_to)?;
+    write_pubkey(writer, &transfer.pubkey_to)?;
+    write_u32(writer, transfer.token_id)?;
+    write_u128(writer, transfer.amount)?;
+    write_u128(writer, transfer.fee)?;
+    write_u64(writer, transfer.nonce)?;
+    write_signature(writer, &transfer.sign)
+}
+
+fn read_transfer<R: Read>(
+    reader: &mut R,
+    sign_params: &AltJubjubBn256,
+) -> Result<Transfer, StorageError> {
+    Ok(Transfer {
+        account_id_from: read_u64(reader)?,
+        account_id_to: read_u64(reader)?,
+        pubkey_to: read_pubkey(reader, sign_params)?,
+        token_id: read_u32(reader)?,
+        amount: read_u128(reader)?,
+        fee: read_u128(reader)?,
+        nonce: read_u64(reader)?,
+        sign: read_signature(reader, sign_params)?,
+    })
+}
+
+fn write_offchain_withdrawal<W: Write>(
+    writer: &mut W,
+    withdrawal: &OffchainWithdrawal,
+) -> io::Result<()> {
+    write_u64(writer, withdrawal.account_id)?;
+    write_u32(writer, withdrawal.token_id)?;
+    write_u128(writer, withdrawal.amount)?;
+    write_u128(writer, withdrawal.fee)?;
+    write_u64(writer, withdrawal.nonce)?;
+    write_signature(writer, &withdrawal.sign)
+}
+
+fn read_offchain_withdrawal<R: Read>(
+    reader: &mut R,
+    sign_params: &AltJubjubBn256,
+) -> Result<OffchainWithdrawal, StorageError> {
+    Ok(OffchainWithdrawal {
+        account_id: read_u64(reader)?,
+        token_id: read_u32(reader)?,
+        amount: read_u128(reader)?,
+        fee: read_u128(reader)?,
+        nonce: read_u64(reader)?,
+        sign: read_signature(reader, sign_params)?,
+    })
+}
+
+fn write_onchain_withdrawal<W: Write>(
+    writer: &mut W,
+    withdrawal: &OnchainWithdrawal,
+) -> io::Result<()> {
+    write_u64(writer, withdrawal.account_id)?;
+    write_u32(writer, withdrawal.token_id)?;
+    match withdrawal.amount {
+        Some(amount) => {
+            writer.write_all(&[1])?;
+            write_u128(writer, amount)
+        },
+        None => writer.write_all(&[0]),
+    }
+}
+
+fn read_onchain_withdrawal<R: Read>(reader: &mut R) -> Result<OnchainWithdrawal, StorageError> {
+    Ok(OnchainWithdrawal {
+        account_id: read_u64(reader)?,
+        token_id: read_u32(reader)?,
+        amount: if read_bool(reader)? { Some(read_u128(reader)?) } else { None },
+    })
+}
+
+fn write_requests<W: Write, T>(
+    writer: &mut W,
+    requests: &[T],
+    write_request: fn(&mut W, &T) -> io::Result<()>,
+) -> io::Result<()> {
+    write_u32(writer, requests.len())?;
+    for request in requests.iter() {
+        write_request(writer, request)?;
+    }
+
+    Ok(())
+}
+
+fn read_requests<R: Read, T>(
+    reader: &mut R,
+    read_request: impl Fn(&mut R) -> Result<T, StorageError>,
+) -> Result<Vec<T>, StorageError> {
+    let count = read_u32(reader)?;
+    (0..count).map(|_| read_request(reader)).collect()
+}
+
+fn write_batch_record<W: Write>(writer: &mut W, batch: &BatchRecord) -> io::Result<()> {
+    writer.write_all(&[batch.batch_type.to_u8(), batch.padded as u8])?;
+    write_u64(writer, batch.batch_number)?;
+    write_requests(writer, &batch.deposits, write_deposit)?;
+    write_requests(writer, &batch.transfers, write_transfer)?;
+    write_requests(writer, &batch.offchain_withdrawals, write_offchain_withdrawal)?;
+    write_requests(writer, &batch.onchain_withdrawals, write_onchain_withdrawal)?;
+    write_fr(writer, &batch.old_root)?;
+    write_fr(writer, &batch.new_root)?;
+    write_fr(writer, &batch.old_deposit_accum_hash)?;
+    write_fr(writer, &batch.new_deposit_accum_hash)?;
+    write_fr(writer, &batch.old_withdrawal_accum_hash)?;
+    write_fr(writer, &batch.new_withdrawal_accum_hash)?;
+    batch.proof.write(writer)
+}
+
+fn read_batch_record<R: Read>(
+    reader: &mut R,
+    sign_params: &AltJubjubBn256,
+) -> Result<BatchRecord, StorageError> {
+    Ok(BatchRecord {
+        batch_type: circuit_type_from_u8(read_u8(reader)?)?,
+        padded: read_bool(reader)?,
+        batch_number: read_u64(reader)?,
+        deposits: read_requests(reader, |r| read_deposit(r, sign_params))?,
+        transfers: read_requests(reader, |r| read_transfer(r, sign_params))?,
+        offchain_withdrawals: read_requests(reader, |r| read_offchain_withdrawal(r, sign_params))?,
+        onchain_withdrawals: read_requests(reader, read_onchain_withdrawal)?,
+        old_root: read_fr(reader)?,
+        new_root: read_fr(reader)?,
+        old_deposit_accum_hash: read_fr(reader)?,
+        new_deposit_accum_hash: read_fr(reader)?,
+        old_withdrawal_accum_hash: read_fr(reader)?,
+        new_withdrawal_accum_hash: read_fr(reader)?,
+        proof: Proof::read(reader)?,
+    })
+}
+
+fn write_log_payload<W: Write>(writer: &mut W, record: &LogRecord) -> io::Result<()> {
+    match record {
+        LogRecord::Deposit(deposit) => {
+            writer.write_all(&[0])?;
+            write_deposit(writer, deposit)
+        },
+        LogRecord::Transfer(transfer) => {
+            writer.write_all(&[1])?;
+            write_transfer(writer, transfer)
+        },
+        LogRecord::OffchainWithdrawal(withdrawal) => {
+            writer.write_all(&[2])?;
+            write_offchain_withdrawal(writer, withdrawal)
+        },
+        LogRecord::OnchainWithdrawal(withdrawal) => {
+            writer.write_all(&[3])?;
+            write_onchain_withdrawal(writer, withdrawal)
+        },
+        LogRecord::Eviction { queue, index } => {
+            writer.write_all(&[4, queue.to_u8()])?;
+            write_u64(writer, *index)
+        },
+        LogRecord::Batch(batch) => {
+            writer.write_all(&[5])?;
+            write_batch_record(writer, batch)
+        },
+    }
+}
+
+fn read_log_payload<R: Read>(
+    reader: &mut R,
+    sign_params: &AltJubjubBn256,
+) -> Result<LogRecord, StorageError> {
+    let record = match read_u8(reader)? {
+        0 => LogRecord::Deposit(read_deposit(reader, sign_params)?),
+        1 => LogRecord::Transfer(read_transfer(reader, sign_params)?),
+        2 => LogRecord::OffchainWithdrawal(read_offchain_withdrawal(reader, sign_params)?),
+        3 => LogRecord::OnchainWithdrawal(read_onchain_withdrawal(reader)?),
+        4 => LogRecord::Eviction {
+            queue: circuit_type_from_u8(read_u8(reader)?)?,
+            index: read_u64(reader)?,
+        },
+        5 => LogRecord::Batch(Box::new(read_batch_record(reader, sign_params)?)),
+        _ => return Err(StorageError::InvalidData),
+    };
+
+    Ok(record)
+}
+
+fn checksum(payload: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(payload);
+    hasher.result().to_vec()
+}
+
+// writes the log header, once at the start of a new log
+pub fn write_log_header<W: Write>(mut writer: W) -> io::Result<()> {
+    writer.write_all(&LOG_MAGIC)?;
+    writer.write_all(&[LOG_VERSION])
+}
+
+pub fn write_log_record<W: Write>(mut writer: W, record: &LogRecord) -> io::Result<()> {
+    let mut payload = Vec::new();
+    write_log_payload(&mut payload, record)?;
+
+    let mut data = Vec::with_capacity(4 + payload.len() + CHECKSUM_BYTES);
+    write_u32(&mut data, payload.len())?;
+    data.extend_from_slice(&payload);
+    data.extend_from_slice(&checksum(&payload));
+
+    // single write so a record is never interleaved with another one
+    writer.write_all(&data)
+}
+
+// a torn append leaves a prefix of a single record at the end of the log. If
+// the length field at `offset` is corrupted instead, the records after it are
+// still there and the last one ends the log. Only frames ending the log are
+// hashed, so the scan reads one length per byte and stays linear
+fn has_record_after(data: &[u8], offset: usize) -> bool {
+    (offset + 1..data.len()).any(|start| {
+        if data.len() - start < 4 + CHECKSUM_BYTES {
+            return false;
+        }
+
+        let payload_len = match read_u32(&mut &data[start..start + 4]) {
+            Ok(payload_len) => payload_len,
+            Err(_) => return false,
+        };
+        let payload_end = start + 4 + payload_len;
+
+        payload_end + CHECKSUM_BYTES == data.len()
+            && checksum(&data[start + 4..payload_end])[..] == data[payload_end..]
+    })
+}
+
+// returns the records and the length of the log up to the last complete one
+pub fn read_log<R: Read>(
+    mut reader: R,
+    sign_params: &AltJubjubBn256,
+) -> Result<(Vec<LogRecord>, usize), StorageError> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    let mut header = LOG_MAGIC.to_vec();
+    header.push(LOG_VERSION);
+
+    // log with a torn header has no records yet
+    if data.len() < LOG_HEADER_BYTES && header.starts_with(&data) {
+        return Ok((Vec::new(), 0));
+    }
+    if !data.starts_with(&header) {
+        return Err(StorageError::InvalidHeader);
+    }
+
+    let mut records = Vec::new();
+    let mut offset = LOG_HEADER_BYTES;
+
+    while data.len() - offset >= 4 {
+        let payload_len = read_u32(&mut &data[offset..offset + 4])?;
+        let payload_start = offset + 4;
+        let payload_end = payload_start + payload_len;
+        let record_end = payload_end + CHECKSUM_BYTES;
+        if record_end > data.len() {
+            if has_record_after(&data, offset) {
+                return Err(StorageError::CorruptedLog { offset });
+            }
+            break;
+        }
+
+        let payload = &data[payload_start..payload_end];
+        if checksum(payload)[..] != data[payload_end..record_end] {
+            if record_end == data.len() {
+                break;
+            }
+            return Err(StorageError::CorruptedLog { offset });
+        }
+
+        records.push(read_log_payload(&mut &payload[..], sign_params)?);
+        offset = record_end;
+    }
+
+    Ok((records, offset))
+}
+
+// record is synced to disk before returning
+pub fn append_log_record<P: AsRef<Path>>(path: P, record: &LogRecord) -> Result<(), StorageError> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if file.metadata()?.len() == 0 {
+        write_log_header(&mut file)?;
+    }
+    write_log_record(&mut file, record)?;
+    file.sync_data()?;
+
+    Ok(())
+}
+
+// torn records at the end of the log are cut off so appending can go on
+pub fn load_log<P: AsRef<Path>>(
+    path: P,
+    sign_params: &AltJubjubBn256,
+) -> Result<Vec<LogRecord>, StorageError> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let (records, valid_len) = read_log(&mut file, sign_params)?;
+
+    if (valid_len as u64) < file.metadata()?.len() {
+        file.set_len(valid_len as u64)?;
+        file.sync_data()?;
+    }
+
+    Ok(records)
+}
@@ -1,5 +1,3 @@
-use std::mem;
-
 use bellman_ce::{
     Circuit,
     ConstraintSystem,
@@ -17,33 +15,39 @@ use sapling_crypto_ce::{
         QuinticSBox,
     },
     circuit::{
-        baby_eddsa::EddsaSignature,
         poseidon_hash::poseidon_hash,
         num::AllocatedNum,
-        boolean::{ Boolean, AllocatedBit },
         ecc::EdwardsPoint,
+        boolean::{ Boolean, AllocatedBit },
     },  
     eddsa::Signature,
 };
 
-use crate::utils::{
-    sign::verify_signature,
-    calc::boolean_to_allocated_num,
-};
+use crate::utils::sign::verify_signature;
 
-use super::account::{ AccountState, AccountCircuit };
+use super::account::{ AccountState, AccountCircuit, BALANCE_BITS, AMOUNT_BITS, NONCE_BITS };
 use super::utils::calc::check_decomposition_le;
+use super::utils::commitment::expose_inputs;
+use super::fee_circuit::{ FeeCircuit, check_fee_token };
 
-const BITS_IN_BYTE: usize = 8;
 const NUM_BYTES_TO_SIGN: usize = 31;
 
+// Transfer is addressed either to an account id, or to a recipient pubkey
+// when `by_pubkey` is set. In the latter case the signed message carries the
+// pubkey instead of the account id, and the operator may credit an empty
+// leaf creating the account (`new_account`).
 #[derive(Clone)]
 pub struct TransferCircuit<E: JubjubEngine + PoseidonEngine> {
     pub account_state_from: AccountState<E>,
     pub account_state_to: AccountState<E>,
     pub account_id_from: Option::<E::Fr>,
     pub account_id_to: Option::<E::Fr>,
+    pub pubkey_to: Option::<Point<E, Unknown>>,
+    pub by_pubkey: Option::<bool>,
+    pub new_account: Option::<bool>,
+    pub token_id: Option::<E::Fr>,
     pub amount: Option::<E::Fr>,
+    pub fee: Option::<E::Fr>,
     pub nonce: Option::<E::Fr>,
     pub sign: Option::<Signature<E>>,
     pub pubkey: Option::<Point<E, Unknown>>,
@@ -52,51 +56,94 @@ pub struct TransferCircuit<E: JubjubEngine + PoseidonEngine> {
 impl<E> TransferCircuit<E>
     where E: JubjubEngine + PoseidonEngine<SBox = QuinticSBox<E>>,
 {
+    #[allow(clippy::too_many_arguments)]
     pub fn process<'a, CS: ConstraintSystem<E>> (
         &self,
         mut cs: CS,
         account_depth: usize,
+        balance_depth: usize,
         hash_params: &'a <E as PoseidonEngine>::Params,
         sign_params: &'a <E as JubjubEngine>::Params,
+        fee_token_id: &AllocatedNum<E>,
         old_root: &AllocatedNum<E>,
-    ) -> Result<AllocatedNum<E>, SynthesisError> {
+    ) -> Result<(AllocatedNum<E>, AllocatedNum<E>), SynthesisError> {
         
         // allocate avariables ----------------------------------------------------------
         
         let account_circuit_from = AccountCircuit::new(
-            cs.namespace(|| "allocate account circuit"),
+            cs.namespace(|| "allocate account circuit from"),
             account_depth,
+            balance_depth,
             hash_params,
             &self.account_state_from,
         )?;
 
         let account_circuit_to = AccountCircuit::new(
-            cs.namespace(|| "allocate account circuit"),
+            cs.namespace(|| "allocate account circuit to"),
             account_depth,
+            balance_depth,
             hash_params,
             &self.account_state_to,
         )?;
 
         let account_id_alloc_from = AllocatedNum::alloc(
-            cs.namespace(|| "allocate account id"),
+            cs.namespace(|| "allocate account id from"),
             || self.account_id_from.ok_or(SynthesisError::AssignmentMissing),
         )?;
 
         let account_id_alloc_to = AllocatedNum::alloc(
-            cs.namespace(|| "allocate account id"),
+            cs.namespace(|| "allocate account id to"),
             || self.account_id_to.ok_or(SynthesisError::AssignmentMissing),
         )?;
 
+        let token_id_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate token id"),
+            || self.token_id.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
         let amount_alloc = AllocatedNum::alloc(
             cs.namespace(|| "allocate amount"),
             || self.amount.ok_or(SynthesisError::AssignmentMissing),
         )?;
 
+        let fee_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate fee"),
+            || self.fee.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
         let nonce_alloc = AllocatedNum::alloc(
             cs.namespace(|| "allocate nonce"),
             || self.nonce.ok_or(SynthesisError::AssignmentMissing),
         )?;
 
+        let (pubkey_to_x, pubkey_to_y) = match &self.pubkey_to {
+            Some(point) => {
+                let (x, y) = point.into_xy();
+                (Some(x), Some(y))
+            },
+            None => (None, None),
+        };
+
+        let pubkey_to_x_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate recipient pubkey x"),
+            || pubkey_to_x.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let pubkey_to_y_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate recipient pubkey y"),
+            || pubkey_to_y.ok_or(SynthesisError::AssignmentMissing),
+        )?;
+
+        let by_pubkey = AllocatedBit::alloc(
+            cs.namespace(|| "allocate by pubkey flag"),
+            self.by_pubkey,
+        )?;
+
+        let new_account = AllocatedBit::alloc(
+            cs.namespace(|| "allocate new account flag"),
+            self.new_account,
+        )?;
+
         // check signature --------------------------------------------------------------
 
         let transfer_hash = {
@@ -105,14 +152,40 @@ impl<E> TransferCircuit<E>
                 &[
                     account_id_alloc_from.clone(),
                     account_id_alloc_to.clone(),
+                    token_id_alloc.clone(),
+                    amount_alloc.clone(),
+                    fee_alloc.clone(),
+                    nonce_alloc.clone(),
+                ],
+                hash_params,
+            )?;
+            hash_vec[0].clone()
+        };
+
+        let pubkey_transfer_hash = {
+            let hash_vec = poseidon_hash(
+                cs.namespace(|| "calculate pubkey message hash"),
+                &[
+                    account_id_alloc_from.clone(),
+                    token_id_alloc.clone(),
                     amount_alloc.clone(),
+                    fee_alloc.clone(),
                     nonce_alloc.clone(),
+                    pubkey_to_x_alloc.clone(),
+                    pubkey_to_y_alloc.clone(),
                 ],
                 hash_params,
             )?;
             hash_vec[0].clone()
         };
 
+        let transfer_hash = AllocatedNum::conditionally_select(
+            cs.namespace(|| "select message hash"),
+            &pubkey_transfer_hash,
+            &transfer_hash,
+            &Boolean::from(by_pubkey.clone()),
+        )?;
+
         let sign_alloc = verify_signature(
             cs.namespace(|| "verify signature"),
             self.sign.clone(),
@@ -146,34 +219,65 @@ impl<E> TransferCircuit<E>
             &account_circuit_to.accounts_tree.indices_alloc,
         )?;
 
-        // check amount
+        // check token id consistency, both balances are of the same token
+
+        check_decomposition_le(
+            cs.namespace(|| "token id from consistence"),
+            &token_id_alloc,
+            &account_circuit_from.balances_tree.indices_alloc,
+        )?;
+
+        check_decomposition_le(
+            cs.namespace(|| "token id to consistence"),
+            &token_id_alloc,
+            &account_circuit_to.balances_tree.indices_alloc,
+        )?;
+
+        // check amount, sender pays the fee on top
 
         cs.enforce(
             || "check amount transfer from",
-            |lc| lc + account_circuit_from.accounts_tree.old_leaf_alloc[3].get_variable(),
+            |lc| lc + account_circuit_from.balances_tree.old_leaf_alloc[0].get_variable(),
             |lc| lc + CS::one(),
-            |lc| lc + account_circuit_from.accounts_tree.new_leaf_alloc[3].get_variable()
-                + amount_alloc.get_variable(),
+            |lc| lc + account_circuit_from.balances_tree.new_leaf_alloc[0].get_variable()
+                + amount_alloc.get_variable() + fee_alloc.get_variable(),
+        );
+
+        check_fee_token(
+            cs.namespace(|| "check fee token"),
+            &fee_alloc,
+            &token_id_alloc,
+            fee_token_id,
         );
 
         cs.enforce(
             || "check amount transfer to",
-            |lc| lc + account_circuit_to.accounts_tree.old_leaf_alloc[3].get_variable()
+            |lc| lc + account_circuit_to.balances_tree.old_leaf_alloc[0].get_variable()
                 + amount_alloc.get_variable(),
             |lc| lc + CS::one(),
-            |lc| lc + account_circuit_to.accounts_tree.new_leaf_alloc[3].get_variable(),
+            |lc| lc + account_circuit_to.balances_tree.new_leaf_alloc[0].get_variable(),
         );
 
-        // check balance for overflow
+        // check amounts range, balances for underflow and overflow
+
+        amount_alloc.limit_number_of_bits(
+            cs.namespace(|| "check amount range"),
+            AMOUNT_BITS,
+        )?;
+
+        fee_alloc.limit_number_of_bits(
+            cs.namespace(|| "check fee range"),
+            AMOUNT_BITS,
+        )?;
 
-        account_circuit_from.accounts_tree.new_leaf_alloc[3].limit_number_of_bits(
-            cs.namespace(|| "check from balance overflow"),
-            mem::size_of::<usize>() * BITS_IN_BYTE,
+        account_circuit_from.balances_tree.new_leaf_alloc[0].limit_number_of_bits(
+            cs.namespace(|| "check from balance underflow"),
+            BALANCE_BITS,
         )?;
 
-        account_circuit_to.accounts_tree.new_leaf_alloc[3].limit_number_of_bits(
+        account_circuit_to.balances_tree.new_leaf_alloc[0].limit_number_of_bits(
             cs.namespace(|| "check to balance overflow"),
-            mem::size_of::<usize>() * BITS_IN_BYTE,
+            BALANCE_BITS,
         )?;
 
         // check nonce
@@ -192,10 +296,38 @@ impl<E> TransferCircuit<E>
             |lc| lc + account_circuit_from.accounts_tree.new_leaf_alloc[2].get_variable(),
         );
 
-        // TODO check that to account hash the same
+        nonce_alloc.limit_number_of_bits(
+            cs.namespace(|| "check nonce range"),
+            NONCE_BITS,
+        )?;
+
+        // check recipient pubkey, it may create the to account
+
+        account_circuit_to.check_recipient(
+            cs.namespace(|| "recipient consistence"),
+            &pubkey_to_x_alloc,
+            &pubkey_to_y_alloc,
+            &Boolean::from(by_pubkey.clone()),
+            &new_account,
+            balance_depth,
+            hash_params,
+            sign_params,
+        );
+
+        // recipient nonce stays the same, only the sender signs
+
+        cs.enforce(
+            || "check to nonce the same",
+            |lc| lc + account_circuit_to.accounts_tree.old_leaf_alloc[2].get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + account_circuit_to.accounts_tree.new_leaf_alloc[2].get_variable(),
+        );
 
         // verify old root & calculate new root -----------------------------------------
 
+        // updates are chained, a self-transfer updates the same leaf twice with
+        // the to witness opening the leaf as left by the from update
+
         account_circuit_from.accounts_tree.verify_old_root(
             cs.namespace(|| "verify from old root"),
             old_root,
@@ -214,7 +346,7 @@ impl<E> TransferCircuit<E>
             cs.namespace(|| "calculate to new root"),
         )?;
 
-        Ok(new_root)
+        Ok((new_root, fee_alloc))
     }
 
     pub fn check_pubkey<CS: ConstraintSystem<E>> (
@@ -256,9 +388,12 @@ impl<E> TransferCircuit<E>
 pub struct TransferBatchCircuit<'a, E: JubjubEngine + PoseidonEngine> {
     pub batch_size: usize,
     pub account_depth: usize,
+    pub balance_depth: usize,
     pub hash_params: &'a <E as PoseidonEngine>::Params,
     pub sign_params: &'a <E as JubjubEngine>::Params,
+    pub commit_inputs: bool,
     pub queue: Vec::<TransferCircuit<E>>,
+    pub fee: FeeCircuit<E>,
     pub old_account_root: Option::<E::Fr>,
     pub new_account_root: Option::<E::Fr>,
 }
@@ -272,30 +407,50 @@ impl<'a, E> Circuit<E> for TransferBatchCircuit<'a, E>
     ) -> Result<(), SynthesisError> {
         assert_eq!(self.batch_size, self.queue.len());
 
+        let mut inputs = Vec::new();
+
         let mut prev_root = AllocatedNum::alloc(
             cs.namespace(|| "allocate old root"),
             || self.old_account_root.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        prev_root.inputize(cs.namespace(|| "input old root"))?;
+        inputs.push(prev_root.clone());
 
         let new_root = AllocatedNum::alloc(
             cs.namespace(|| "allocate new root"),
             || self.new_account_root.ok_or(SynthesisError::AssignmentMissing),
         )?;
-        new_root.inputize(cs.namespace(|| "input new root"))?;
+        inputs.push(new_root.clone());
+
+        let fee_token_id = self.fee.alloc_token_id(
+            cs.namespace(|| "allocate fee token id"),
+        )?;
+        let mut fees = Vec::with_capacity(self.batch_size);
 
-        for (i, withdrawal) in self.queue.iter().enumerate() {
-            let root = withdrawal.process(
-                cs.namespace(|| format!("verify withdrawal {}", i)),
+        for (i, transfer) in self.queue.iter().enumerate() {
+            let (root, fee) = transfer.process(
+                cs.namespace(|| format!("verify transfer {}", i)),
                 self.account_depth,
+                self.balance_depth,
                 self.hash_params,
                 self.sign_params,
+                &fee_token_id,
                 &prev_root,
             )?;
 
             prev_root = root;
+            fees.push(fee);
         }
 
+        prev_root = self.fee.process(
+            cs.namespace(|| "credit fees"),
+            self.account_depth,
+            self.balance_depth,
+            self.hash_params,
+            &fee_token_id,
+            &fees,
+            &prev_root,
+        )?;
+
         cs.enforce(
             || "enforce new root equivalence",
             |lc| lc + prev_root.get_variable(),
@@ -303,6 +458,12 @@ impl<'a, E> Circuit<E> for TransferBatchCircuit<'a, E>
             |lc| lc + new_root.get_variable(),
         );
 
+        expose_inputs(
+            cs.namespace(|| "expose public inputs"),
+            &inputs,
+            self.commit_inputs,
+        )?;
+
         Ok(())
     }
 }
@@ -1,11 +1,11 @@
+use std::fmt;
+use std::error::Error;
+use std::collections::HashMap;
+
 use sapling_crypto_ce::{
     poseidon::bn256::Bn256PoseidonParams,
     eddsa::PublicKey,
     alt_babyjubjub::AltJubjubBn256,
-    jubjub::{
-        edwards::Point,
-        Unknown,
-    },
 };
 
 use ff_ce::Field;
@@ -19,37 +19,116 @@ use super::{
     merkle_tree::PoseidonMerkleTree,
 };
 
+use crate::account::{ AccountState, empty_pubkey };
+use crate::utils::utils::{ optionalize, fr_to_usize, u128_to_fr };
+
+// Errors of requests applied to the tree, the operator maps them to its own
+// errors with the same context.
+#[derive(Debug)]
+pub enum TreeError {
+    UnknownAccount { account_id: usize },
+    UnknownToken { token_id: usize },
+    InvalidRequest { account_id: usize },
+    InvalidSignature { account_id: usize },
+    NonceMismatch { account_id: usize, expected: usize, actual: usize },
+    NonceOverflow { account_id: usize },
+    InsufficientBalance { account_id: usize, balance: u128, amount: u128 },
+    AmountOverflow { account_id: usize, balance: u128, amount: u128 },
+    BalanceMismatch { account_id: usize, balance: u128, amount: u128 },
+    RootMismatch { expected: bn256::Fr, found: bn256::Fr },
+}
+
+impl Error for TreeError {}
+
+impl fmt::Display for TreeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            TreeError::UnknownAccount { account_id } => {
+                write!(f, "Account {} is out of tree range", account_id)
+            },
+            TreeError::UnknownToken { token_id } => {
+                write!(f, "Token {} is out of balance tree range", token_id)
+            },
+            TreeError::InvalidRequest { account_id } => {
+                write!(f, "Malformed request for account {}", account_id)
+            },
+            TreeError::InvalidSignature { account_id } => {
+                write!(f, "Invalid request signature for account {}", account_id)
+            },
+            TreeError::NonceMismatch { account_id, expected, actual } => {
+                write!(f, "Account {} nonce mismatch: expected {}, got {}", account_id, expected, actual)
+            },
+            TreeError::NonceOverflow { account_id } => {
+                write!(f, "Account {} nonce is out of range", account_id)
+            },
+            TreeError::InsufficientBalance { account_id, balance, amount } => {
+                write!(f, "Account {} balance {} is less than {}", account_id, balance, amount)
+            },
+            TreeError::AmountOverflow { account_id, balance, amount } => {
+                write!(f, "Account {} balance {} overflows when adding {}", account_id, balance, amount)
+            },
+            TreeError::BalanceMismatch { account_id, balance, amount } => {
+                write!(f, "Account {} balance {} doesn't match claimed {}", account_id, balance, amount)
+            },
+            TreeError::RootMismatch { expected, found } => {
+                write!(f, "Claimed root {} doesn't match tree root {}", found, expected)
+            },
+        }
+    }
+}
+
+// Account owns a balance subtree, leaf `i` holds the balance of token `i`
 #[derive(Clone)]
-pub struct Account {
+pub struct Account<'a> {
     pub pubkey: PublicKey::<Bn256>,
     pub nonce: bn256::Fr,
-    pub balance: bn256::Fr,
+    pub balances: Vec::<u128>,
+    pub balances_tree: PoseidonMerkleTree::<'a, Bn256>,
 }
 
-impl Account {
+impl<'a> Account<'a> {
     pub fn new(
+        balance_depth: usize,
+        hash_params: &'a Bn256PoseidonParams,
         sign_params: &AltJubjubBn256,
     ) -> Self {        
-        let pubkey = PublicKey::<Bn256>(
-            Point::<Bn256, Unknown>::get_for_y(bn256::Fr::zero(), true, sign_params).unwrap()
+        let pubkey = PublicKey::<Bn256>(empty_pubkey::<Bn256>(sign_params));
+
+        let balances = vec![0; 1 << balance_depth];
+        let balances_tree = PoseidonMerkleTree::<'a, Bn256>::new(
+            balance_depth,
+            vec![u128_to_fr(0)],
+            hash_params,
         );
 
         Account {
             pubkey,
             nonce: bn256::Fr::zero(),
-            balance: bn256::Fr::zero(),
+            balances,
+            balances_tree,
         }
     }
 
+    // leaf that was never touched, a transfer by pubkey may take it
+    pub fn is_empty(&self, sign_params: &AltJubjubBn256) -> bool {
+        self.pubkey.0 == empty_pubkey::<Bn256>(sign_params)
+            && self.nonce.is_zero()
+            && self.balances.iter().all(|balance| *balance == 0)
+    }
+
     pub fn compress_to_leaf(&self) -> Vec::<bn256::Fr> {
         let (pubkey_x, pubkey_y) = self.pubkey.0.into_xy();
-        vec![pubkey_x, pubkey_y, self.nonce, self.balance]
+        vec![pubkey_x, pubkey_y, self.nonce, self.balances_tree.root()]
     }
 }
 
+// Only accounts that were ever updated are stored, all others are empty
 #[derive(Clone)]
 pub struct AccountsTree<'a> {
-    pub accounts: Vec::<Account>,
+    pub account_depth: usize,
+    pub balance_depth: usize,
+    pub accounts: HashMap::<usize, Account<'a>>,
+    pub empty_account: Account<'a>,
     pub accounts_tree: PoseidonMerkleTree::<'a, Bn256>,
 }
 
@@ -57,22 +136,66 @@ pub struct AccountsTree<'a> {
 impl<'a> AccountsTree<'a> {
     pub fn new(
         account_depth: usize,
+        balance_depth: usize,
         hash_params: &'a Bn256PoseidonParams,
         sign_params: &AltJubjubBn256,
     ) -> Self {
-        let num_accounts = 1 << account_depth;
-        let mut accounts = Vec::with_capacity(num_accounts);
-        accounts.resize_with(
-            num_accounts,
-            || Account::new(sign_params),
+        let empty_account = Account::new(balance_depth, hash_params, sign_params);
+        let accounts_tree = PoseidonMerkleTree::<'a, Bn256>::new(
+            account_depth,
+            empty_account.compress_to_leaf(),
+            hash_params,
         );
 
-        let leaves: Vec<_> = accounts.iter().map(
-            |account| account.compress_to_leaf()
-        ).collect();
-        let accounts_tree = PoseidonMerkleTree::<'a, Bn256>::new(leaves, hash_params);
+        AccountsTree {
+            account_depth,
+            balance_depth,
+            accounts: HashMap::new(),
+            empty_account,
+            accounts_tree,
+        }
+    }
+
+    pub fn num_accounts(&self) -> usize {
+        1 << self.account_depth
+    }
+
+    pub fn num_tokens(&self) -> usize {
+        1 << self.balance_depth
+    }
+
+    fn check_account_id(&self, account_id: usize) -> Result<(), TreeError> {
+        if account_id >= self.num_accounts() {
+            return Err(TreeError::UnknownAccount { account_id });
+        }
+
+        Ok(())
+    }
+
+    fn check_token_id(&self, token_id: usize) -> Result<(), TreeError> {
+        if token_id >= self.num_tokens() {
+            return Err(TreeError::UnknownToken { token_id });
+        }
+
+        Ok(())
+    }
+
+    pub fn get_account(&self, account_id: usize) -> Result<&Account<'a>, TreeError> {
+        self.check_account_id(account_id)?;
+        Ok(self.accounts.get(&account_id).unwrap_or(&self.empty_account))
+    }
+
+    fn get_account_mut(&mut self, account_id: usize) -> Result<&mut Account<'a>, TreeError> {
+        self.check_account_id(account_id)?;
+        let empty_account = &self.empty_account;
+        Ok(self.accounts.entry(account_id).or_insert_with(|| empty_account.clone()))
+    }
+
+    fn update_account_leaf(&mut self, account_id: usize) -> Result<(), TreeError> {
+        let leaf = self.get_account(account_id)?.compress_to_leaf();
+        self.accounts_tree.update_leaf(account_id, leaf);
 
-        AccountsTree { accounts, accounts_tree }
+        Ok(())
     }
 
     pub fn update_account(
@@ -80,64 +203,110 @@ impl<'a> AccountsTree<'a> {
         account_id: usize,
         pubkey: PublicKey::<Bn256>,
         nonce: bn256::Fr,
-    ) {
-        assert!(account_id < self.accounts.len());
+    ) -> Result<(), TreeError> {
+        let account = self.get_account_mut(account_id)?;
+        account.pubkey = pubkey;
+        account.nonce = nonce;
 
-        self.accounts[account_id].pubkey = pubkey;
-        self.accounts[account_id].nonce = nonce;
-
-        self.accounts_tree.update_leaf(
-            account_id,
-            self.accounts[account_id].compress_to_leaf(),
-        );
+        self.update_account_leaf(account_id)
     }
 
     pub fn update_nonce(
         &mut self,
         account_id: usize,
         nonce: bn256::Fr,
-    ) {
-        assert!(account_id < self.accounts.len());
-
-        self.accounts[account_id].nonce = nonce;
+    ) -> Result<(), TreeError> {
+        self.get_account_mut(account_id)?.nonce = nonce;
 
-        self.accounts_tree.update_leaf(
-            account_id,
-            self.accounts[account_id].compress_to_leaf(),
-        );
+        self.update_account_leaf(account_id)
     }
     
-    pub fn get_pubkey(&self, account_id: usize) -> PublicKey::<Bn256> {
-        assert!(account_id < self.accounts.len());        
-        self.accounts[account_id].pubkey.clone()
+    pub fn get_pubkey(&self, account_id: usize) -> Result<PublicKey::<Bn256>, TreeError> {
+        Ok(self.get_account(account_id)?.pubkey.clone())
+    }
+
+    pub fn get_nonce(&self, account_id: usize) -> Result<bn256::Fr, TreeError> {
+        Ok(self.get_account(account_id)?.nonce)
     }
 
-    pub fn get_nonce(&self, account_id: usize) -> bn256::Fr {
-        assert!(account_id < self.accounts.len());        
-        self.accounts[account_id].nonce
+    // nonce as a number, a nonce restored from outside data may not fit
+    pub fn get_nonce_value(&self, account_id: usize) -> Result<usize, TreeError> {
+        fr_to_usize(self.get_nonce(account_id)?).ok_or(TreeError::NonceOverflow { account_id })
     }
 
     pub fn update_balance(
         &mut self,
         account_id: usize,
-        new_balance: bn256::Fr,
-    ) {
-        assert!(account_id < self.accounts.len());
-        self.accounts[account_id].balance = new_balance;
-
-        self.accounts_tree.update_leaf(
-            account_id,
-            self.accounts[account_id].compress_to_leaf(),
-        );
+        token_id: usize,
+        new_balance: u128,
+    ) -> Result<(), TreeError> {
+        self.check_token_id(token_id)?;
+
+        let account = self.get_account_mut(account_id)?;
+        account.balances[token_id] = new_balance;
+        account.balances_tree.update_leaf(token_id, vec![u128_to_fr(new_balance)]);
+
+        self.update_account_leaf(account_id)
+    }
+
+    pub fn get_balance(&self, account_id: usize, token_id: usize) -> Result<u128, TreeError> {
+        self.check_token_id(token_id)?;
+        Ok(self.get_account(account_id)?.balances[token_id])
+    }
+
+    pub fn get_balance_path(&self, account_id: usize, token_id: usize) -> Result<Vec::<bn256::Fr>, TreeError> {
+        self.check_token_id(token_id)?;
+        Ok(self.get_account(account_id)?.balances_tree.get_leaf_path(token_id))
     }
 
-    pub fn get_balance(&mut self, account_id: usize) -> bn256::Fr {
-        assert!(account_id < self.accounts.len());
-        self.accounts[account_id].balance 
+    pub fn get_balance_indices(&self, account_id: usize, token_id: usize) -> Result<Vec::<bool>, TreeError> {
+        self.check_token_id(token_id)?;
+        Ok(self.get_account(account_id)?.balances_tree.get_leaf_indices(token_id))
     }
 
     pub fn get_root(&self) -> bn256::Fr {
         self.accounts_tree.root()
     }
-}
 
+    // credits collected fees to the fee account and records its state
+    pub fn credit_fees(
+        &mut self,
+        account_id: usize,
+        token_id: usize,
+        fees: u128,
+    ) -> Result<AccountState::<Bn256>, TreeError> {
+        let mut state = self.record_account_state(account_id, token_id)?;
+        let balance = self.get_balance(account_id, token_id)?;
+        let new_balance = balance.checked_add(fees).ok_or(
+            TreeError::AmountOverflow { account_id, balance, amount: fees }
+        )?;
+
+        self.update_balance(account_id, token_id, new_balance)?;
+        state.new_balance = Some(u128_to_fr(new_balance));
+
+        Ok(state)
+    }
+
+    // state of an account that is left unchanged by an operation
+    pub fn record_account_state(
+        &self,
+        account_id: usize,
+        token_id: usize,
+    ) -> Result<AccountState::<Bn256>, TreeError> {
+        let account = self.get_account(account_id)?;
+        let balance = u128_to_fr(self.get_balance(account_id, token_id)?);
+
+        Ok(AccountState::<Bn256> {
+            old_balance: Some(balance),
+            new_balance: Some(balance),
+            old_pubkey: Some(account.pubkey.0.clone()),
+            new_pubkey: Some(account.pubkey.0.clone()),
+            old_nonce: Some(account.nonce),
+            new_nonce: Some(account.nonce),
+            account_path: optionalize(self.accounts_tree.get_leaf_path(account_id)),
+            account_indices: optionalize(self.accounts_tree.get_leaf_indices(account_id)),
+            balance_path: optionalize(self.get_balance_path(account_id, token_id)?),
+            balance_indices: optionalize(self.get_balance_indices(account_id, token_id)?),
+        })
+    }
+}
@@ -1,4 +1,5 @@
 use std::fmt;
+use std::collections::HashMap;
 
 use sapling_crypto_ce::{
     poseidon::{
@@ -8,9 +9,12 @@ use sapling_crypto_ce::{
     }
 };
 
+// Sparse tree: all leaves start as `empty_leaf`, only nodes that differ from
+// the empty subtree hash of their level are stored. Level 0 holds leaf hashes.
 pub struct PoseidonMerkleTree<'a, E: PoseidonEngine> {
     params: &'a E::Params,
-    tree: Vec::<E::Fr>,
+    nodes: HashMap::<(usize, usize), E::Fr>,
+    empty_hashes: Vec::<E::Fr>,
     depth: usize,
 }
 
@@ -20,7 +24,7 @@ impl<'a, E> PoseidonMerkleTree<'a, E>
 {
     pub fn hash(&self, input: &[E::Fr]) -> E::Fr {
         let hash = poseidon_hash::<E>(self.params, input);
-        hash[0].clone()
+        hash[0]
     }
 
     pub fn num_leaves(&self) -> usize {
@@ -31,109 +35,82 @@ impl<'a, E> PoseidonMerkleTree<'a, E>
         self.depth
     }
 
-    pub fn new(leaves: Vec::<Vec::<E::Fr>>, params: &'a E::Params) -> Self {
+    pub fn new(depth: usize, empty_leaf: Vec::<E::Fr>, params: &'a E::Params) -> Self {
         let mut merkle_tree = PoseidonMerkleTree {
-            params: params,
-            tree: Vec::new(),
-            depth: 0,
+            params,
+            nodes: HashMap::new(),
+            empty_hashes: Vec::with_capacity(depth + 1),
+            depth,
         };
 
-        merkle_tree.tree = leaves
-            .iter().map(
-                |leaf| merkle_tree.hash(leaf)
-            ).collect();
-
-        let mut start_index = 0;
-        let mut end_index = leaves.len();
-
-        while end_index - start_index > 1 {
-            for i in (start_index..end_index).step_by(2) {
-                merkle_tree.tree.push(
-                    merkle_tree.hash(
-                        &merkle_tree.tree[i..i + 2]
-                    )
-                );
-            }
-
-            start_index = end_index;
-            end_index = merkle_tree.tree.len();
-            merkle_tree.depth += 1;
+        let mut empty_hash = merkle_tree.hash(&empty_leaf);
+        merkle_tree.empty_hashes.push(empty_hash);
+
+        for _ in 0..depth {
+            empty_hash = merkle_tree.hash(&[empty_hash, empty_hash]);
+            merkle_tree.empty_hashes.push(empty_hash);
         }
 
         merkle_tree
     }
 
+    // number of stored nodes, the rest are empty subtrees
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn get_node(&self, level: usize, index: usize) -> E::Fr {
+        match self.nodes.get(&(level, index)) {
+            Some(node) => *node,
+            None => self.empty_hashes[level],
+        }
+    }
+
+    fn set_node(&mut self, level: usize, index: usize, node: E::Fr) {
+        if node == self.empty_hashes[level] {
+            self.nodes.remove(&(level, index));
+        } else {
+            self.nodes.insert((level, index), node);
+        }
+    }
+
     pub fn get_leaf_indices(&self, leaf_index: usize) -> Vec::<bool> {
         assert!(leaf_index < self.num_leaves());
 
-        let bin_str = format!("{:0w$b}", leaf_index, w=self.depth());
-        let mut bin_array: Vec<_> = bin_str.chars()
-            .into_iter().map(
-                |x| x == '1'
-            ).collect();
-        bin_array.reverse();
-        bin_array
+        (0..self.depth).map(|level| (leaf_index >> level) & 1 == 1).collect()
     }
 
     pub fn get_leaf_path(&self, leaf_index: usize) -> Vec::<E::Fr> {
         assert!(leaf_index < self.num_leaves());
 
-        let mut path = Vec::new();
-        let mut level_start = 0;
-        let mut level_nodes = self.num_leaves();
-        let mut level_shift = leaf_index;
-
-        while level_nodes > 1 {
-            let neighbor_index = {
-                let node_index = level_start + level_shift;
-                if node_index % 2 == 0 {
-                    node_index + 1
-                } else {
-                    node_index - 1
-                }
-            };
-
-            path.push(self.tree[neighbor_index].clone());
-
-            level_shift /= 2;
-            level_start += level_nodes;
-            level_nodes /= 2;
-        }
-
-        path
+        (0..self.depth).map(
+            |level| self.get_node(level, (leaf_index >> level) ^ 1)
+        ).collect()
     }
 
     pub fn update_leaf(&mut self, leaf_index: usize, new_leaf: Vec::<E::Fr>) {
         assert!(leaf_index < self.num_leaves());
 
-        self.tree[leaf_index] = self.hash(
-            &new_leaf
-        );
+        let mut node = self.hash(&new_leaf);
+        self.set_node(0, leaf_index, node);
 
-        let mut level_start = 0;
-        let mut level_nodes = self.num_leaves();
-        let mut level_shift = leaf_index;
+        let mut node_index = leaf_index;
 
-        while level_nodes > 1 { 
-            let parent_index = level_start + level_nodes + level_shift / 2;
-            let even_node_index = {
-                let node_index = level_start + level_shift;
-                node_index - (node_index % 2)
+        for level in 0..self.depth {
+            let neighbor = self.get_node(level, node_index ^ 1);
+            node = if node_index.is_multiple_of(2) {
+                self.hash(&[node, neighbor])
+            } else {
+                self.hash(&[neighbor, node])
             };
 
-            self.tree[parent_index] = self.hash(
-                &self.tree[even_node_index..even_node_index + 2]
-            );
-
-            level_shift /= 2;
-            level_start += level_nodes;
-            level_nodes /= 2;
+            node_index /= 2;
+            self.set_node(level + 1, node_index, node);
         }
     }
 
     pub fn root(&self) -> E::Fr {
-        let root_index = self.tree.len() - 1;
-        self.tree[root_index].clone()
+        self.get_node(self.depth, 0)
     }
 }
 
@@ -143,7 +120,8 @@ impl<'a, E> Clone for PoseidonMerkleTree<'a, E>
     fn clone(&self) -> Self {
         PoseidonMerkleTree {
             params: self.params,
-            tree: self.tree.clone(),
+            nodes: self.nodes.clone(),
+            empty_hashes: self.empty_hashes.clone(),
             depth: self.depth,
         }
     }
@@ -153,20 +131,14 @@ impl<'a, E> fmt::Debug for PoseidonMerkleTree<'a, E>
     where E: PoseidonEngine<SBox = QuinticSBox<E>>
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut nodes: Vec<_> = self.nodes.iter().collect();
+        nodes.sort_by_key(|(position, _)| **position);
 
-        let mut level_end = self.num_leaves();
-        let mut level_size = self.num_leaves();
-
-        write!(f, "tree: [\n")?;
-        for (i, node) in self.tree.clone().into_iter().enumerate() {
-            if i == level_end {
-                write!(f, "\n")?;
-                level_size /= 2;
-                level_end += level_size;
-            }
-            write!(f, "    {:?},\n", node)?;
+        writeln!(f, "tree (depth {}, root {:?}): [", self.depth, self.root())?;
+        for ((level, index), node) in nodes {
+            writeln!(f, "    ({}, {}): {:?},", level, index, node)?;
         }
-        write!(f, "]\n")?;
+        writeln!(f, "]")?;
 
         Ok(())
     }
@@ -1,2 +1,4 @@
 pub mod account;
 pub mod merkle_tree;
+pub mod pending;
+pub mod proof;
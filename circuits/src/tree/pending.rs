@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+
+use sapling_crypto_ce::{
+    eddsa::PublicKey,
+    alt_babyjubjub::AltJubjubBn256,
+};
+
+use ff_ce::Field;
+
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use super::account::{ AccountsTree, TreeError };
+
+use crate::data_structs::{
+    deposit::Deposit,
+    transfer::Transfer,
+    onchain_withdrawal::OnchainWithdrawal,
+    offchain_withdrawal::OffchainWithdrawal,
+};
+
+use crate::account::empty_pubkey;
+use crate::utils::utils::{ fr_to_usize, usize_to_fr };
+
+#[derive(Clone)]
+pub struct PendingAccount {
+    pub pubkey: PublicKey::<Bn256>,
+    pub nonce: bn256::Fr,
+    // balances of touched tokens, other tokens are as on the tree
+    pub balances: HashMap::<usize, u128>,
+}
+
+impl PendingAccount {
+    pub fn get_balance(
+        &self,
+        tree: &AccountsTree,
+        account_id: usize,
+        token_id: usize,
+    ) -> Result<u128, TreeError> {
+        match self.balances.get(&token_id) {
+            Some(balance) => Ok(*balance),
+            None => tree.get_balance(account_id, token_id),
+        }
+    }
+}
+
+// Speculative view of the accounts: committed tree state with admitted but
+// not yet batched requests applied on top. Requests are applied in the
+// order they are passed in, callers are expected to validate them first.
+// A request that doesn't apply is an error and leaves the state unchanged.
+#[derive(Clone, Default)]
+pub struct PendingState {
+    pub accounts: HashMap::<usize, PendingAccount>,
+}
+
+impl PendingState {
+    pub fn new() -> Self {
+        PendingState {
+            accounts: HashMap::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.accounts.clear();
+    }
+
+    pub fn get_account(
+        &self,
+        tree: &AccountsTree,
+        account_id: usize,
+    ) -> Result<PendingAccount, TreeError> {
+        match self.accounts.get(&account_id) {
+            Some(account) => Ok(account.clone()),
+            None => Ok(PendingAccount {
+                pubkey: tree.get_pubkey(account_id)?,
+                nonce: tree.get_nonce(account_id)?,
+                balances: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn get_pubkey(&self, tree: &AccountsTree, account_id: usize) -> Result<PublicKey::<Bn256>, TreeError> {
+        Ok(self.get_account(tree, account_id)?.pubkey)
+    }
+
+    pub fn get_nonce(&self, tree: &AccountsTree, account_id: usize) -> Result<usize, TreeError> {
+        fr_to_usize(self.get_account(tree, account_id)?.nonce)
+            .ok_or(TreeError::NonceOverflow { account_id })
+    }
+
+    pub fn get_balance(
+        &self,
+        tree: &AccountsTree,
+        account_id: usize,
+        token_id: usize,
+    ) -> Result<u128, TreeError> {
+        self.get_account(tree, account_id)?.get_balance(tree, account_id, token_id)
+    }
+
+    // leaf a transfer by pubkey may take to create an account
+    pub fn is_empty_account(
+        &self,
+        tree: &AccountsTree,
+        account_id: usize,
+        sign_params: &AltJubjubBn256,
+    ) -> Result<bool, TreeError> {
+        let account = self.get_account(tree, account_id)?;
+
+        if account.pubkey.0 != empty_pubkey::<Bn256>(sign_params) || !account.nonce.is_zero() {
+            return Ok(false);
+        }
+
+        for token_id in 0..tree.num_tokens() {
+            if account.get_balance(tree, account_id, token_id)? != 0 {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    pub fn apply_deposit(
+        &mut self,
+        tree: &AccountsTree,
+        deposit: &Deposit,
+        pubkey: &PublicKey::<Bn256>,
+    ) -> Result<(), TreeError> {
+        let mut account = self.get_account(tree, deposit.account_id)?;
+        let balance = account.get_balance(tree, deposit.account_id, deposit.token_id)?;
+        let new_balance = balance.checked_add(deposit.amount).ok_or(TreeError::AmountOverflow {
+            account_id: deposit.account_id,
+            balance,
+            amount: deposit.amount,
+        })?;
+        account.pubkey = pubkey.clone();
+        account.balances.insert(deposit.token_id, new_balance);
+
+        self.accounts.insert(deposit.account_id, account);
+
+        Ok(())
+    }
+
+    pub fn apply_transfer(
+        &mut self,
+        tree: &AccountsTree,
+        transfer: &Transfer,
+    ) -> Result<(), TreeError> {
+        let mut account_from = self.get_account(tree, transfer.account_id_from)?;
+        let balance = account_from.get_balance(tree, transfer.account_id_from, transfer.token_id)?;
+        let new_balance = transfer.amount.checked_add(transfer.fee)
+            .and_then(|amount| balance.checked_sub(amount))
+            .ok_or(TreeError::InsufficientBalance {
+                account_id: transfer.account_id_from,
+                balance,
+                amount: transfer.amount.saturating_add(transfer.fee),
+            })?;
+        account_from.balances.insert(transfer.token_id, new_balance);
+        account_from.nonce = usize_to_fr(transfer.nonce);
+
+        // a self-transfer credits the debited account
+        let mut account_to = if transfer.account_id_to == transfer.account_id_from {
+            account_from.clone()
+        } else {
+            self.get_account(tree, transfer.account_id_to)?
+        };
+        if let Some(pubkey) = &transfer.pubkey_to {
+            account_to.pubkey = pubkey.clone();
+        }
+        let balance = account_to.get_balance(tree, transfer.account_id_to, transfer.token_id)?;
+        let new_balance = balance.checked_add(transfer.amount).ok_or(TreeError::AmountOverflow {
+            account_id: transfer.account_id_to,
+            balance,
+            amount: transfer.amount,
+        })?;
+        account_to.balances.insert(transfer.token_id, new_balance);
+
+        self.accounts.insert(transfer.account_id_from, account_from);
+        self.accounts.insert(transfer.account_id_to, account_to);
+
+        Ok(())
+    }
+
+    pub fn apply_offchain_withdrawal(
+        &mut self,
+        tree: &AccountsTree,
+        withdrawal: &OffchainWithdrawal,
+    ) -> Result<(), TreeError> {
+        let mut account = self.get_account(tree, withdrawal.account_id)?;
+        let balance = account.get_balance(tree, withdrawal.account_id, withdrawal.token_id)?;
+        let new_balance = withdrawal.amount.checked_add(withdrawal.fee)
+            .and_then(|amount| balance.checked_sub(amount))
+            .ok_or(TreeError::InsufficientBalance {
+                account_id: withdrawal.account_id,
+                balance,
+                amount: withdrawal.amount.saturating_add(withdrawal.fee),
+            })?;
+        account.balances.insert(withdrawal.token_id, new_balance);
+        account.nonce = usize_to_fr(withdrawal.nonce);
+
+        self.accounts.insert(withdrawal.account_id, account);
+
+        Ok(())
+    }
+
+    pub fn apply_fee(
+        &mut self,
+        tree: &AccountsTree,
+        account_id: usize,
+        token_id: usize,
+        fee: u128,
+    ) -> Result<(), TreeError> {
+        let mut account = self.get_account(tree, account_id)?;
+        let balance = account.get_balance(tree, account_id, token_id)?;
+        let new_balance = balance.checked_add(fee).ok_or(
+            TreeError::AmountOverflow { account_id, balance, amount: fee }
+        )?;
+        account.balances.insert(token_id, new_balance);
+
+        self.accounts.insert(account_id, account);
+
+        Ok(())
+    }
+
+    pub fn apply_onchain_withdrawal(
+        &mut self,
+        tree: &AccountsTree,
+        withdrawal: &OnchainWithdrawal,
+    ) -> Result<(), TreeError> {
+        // onchain withdrawal takes all asset's value
+        let mut account = self.get_account(tree, withdrawal.account_id)?;
+        account.get_balance(tree, withdrawal.account_id, withdrawal.token_id)?;
+        account.balances.insert(withdrawal.token_id, 0);
+
+        self.accounts.insert(withdrawal.account_id, account);
+
+        Ok(())
+    }
+}
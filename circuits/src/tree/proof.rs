@@ -0,0 +1,127 @@
+use sapling_crypto_ce::{
+    poseidon::{
+        bn256::Bn256PoseidonParams,
+        poseidon_hash,
+    },
+    eddsa::PublicKey,
+};
+
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use super::account::{ AccountsTree, TreeError };
+
+use crate::utils::utils::u128_to_fr;
+
+// Self-contained proof that an account with the given leaf fields holds
+// `balance` of `token_id` under `root`. It is checked with hashes only, so a
+// wallet can verify its balance against a root posted on-chain without
+// trusting the operator.
+#[derive(Clone)]
+pub struct AccountProof {
+    pub account_id: usize,
+    pub pubkey: PublicKey::<Bn256>,
+    pub nonce: bn256::Fr,
+    pub balance_root: bn256::Fr,
+    pub account_path: Vec::<bn256::Fr>,
+    pub account_indices: Vec::<bool>,
+
+    pub token_id: usize,
+    pub balance: u128,
+    pub balance_path: Vec::<bn256::Fr>,
+    pub balance_indices: Vec::<bool>,
+
+    pub root: bn256::Fr,
+}
+
+impl<'a> AccountsTree<'a> {
+    pub fn get_account_proof(&self, account_id: usize, token_id: usize) -> Result<AccountProof, TreeError> {
+        let account = self.get_account(account_id)?;
+
+        Ok(AccountProof {
+            account_id,
+            pubkey: account.pubkey.clone(),
+            nonce: account.nonce,
+            balance_root: account.balances_tree.root(),
+            account_path: self.accounts_tree.get_leaf_path(account_id),
+            account_indices: self.accounts_tree.get_leaf_indices(account_id),
+            token_id,
+            balance: self.get_balance(account_id, token_id)?,
+            balance_path: self.get_balance_path(account_id, token_id)?,
+            balance_indices: self.get_balance_indices(account_id, token_id)?,
+            root: self.get_root(),
+        })
+    }
+}
+
+fn hash(hash_params: &Bn256PoseidonParams, input: &[bn256::Fr]) -> bn256::Fr {
+    poseidon_hash::<Bn256>(hash_params, input)[0]
+}
+
+// indices must be the bits of the leaf index, otherwise the path would prove
+// a leaf at another position
+fn check_indices(leaf_index: usize, indices: &[bool]) -> bool {
+    indices.len() < usize::BITS as usize
+        && leaf_index >> indices.len() == 0
+        && indices.iter().enumerate().all(|(level, bit)| (leaf_index >> level) & 1 == *bit as usize)
+}
+
+fn calc_root(
+    hash_params: &Bn256PoseidonParams,
+    leaf: &[bn256::Fr],
+    path: &[bn256::Fr],
+    indices: &[bool],
+) -> bn256::Fr {
+    let mut node = hash(hash_params, leaf);
+    for (neighbor, is_right) in path.iter().zip(indices.iter()) {
+        node = if *is_right {
+            hash(hash_params, &[*neighbor, node])
+        } else {
+            hash(hash_params, &[node, *neighbor])
+        };
+    }
+
+    node
+}
+
+// checks the proof against a trusted root and the depths of its tree, a
+// shorter path would prove an inner node, `proof.root` is not used
+pub fn verify_account_proof(
+    proof: &AccountProof,
+    root: &bn256::Fr,
+    account_depth: usize,
+    balance_depth: usize,
+    hash_params: &Bn256PoseidonParams,
+) -> bool {
+    if proof.account_path.len() != account_depth
+        || proof.account_indices.len() != account_depth
+        || proof.balance_path.len() != balance_depth
+        || proof.balance_indices.len() != balance_depth
+        || !check_indices(proof.account_id, &proof.account_indices)
+        || !check_indices(proof.token_id, &proof.balance_indices)
+    {
+        return false;
+    }
+
+    let balance_root = calc_root(
+        hash_params,
+        &[u128_to_fr(proof.balance)],
+        &proof.balance_path,
+        &proof.balance_indices,
+    );
+    if balance_root != proof.balance_root {
+        return false;
+    }
+
+    let (pubkey_x, pubkey_y) = proof.pubkey.0.into_xy();
+    let account_root = calc_root(
+        hash_params,
+        &[pubkey_x, pubkey_y, proof.nonce, proof.balance_root],
+        &proof.account_path,
+        &proof.account_indices,
+    );
+
+    account_root == *root
+}
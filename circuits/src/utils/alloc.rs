@@ -16,7 +16,7 @@ use sapling_crypto_ce::{
 
 pub fn alloc_nums<E, CS> (
     mut cs: CS,
-    array: &Vec::<Option<E::Fr>>,
+    array: &[Option<E::Fr>],
 ) -> Result<Vec::<AllocatedNum<E>>, SynthesisError>
     where E: JubjubEngine,
           CS: ConstraintSystem<E>,
@@ -36,18 +36,18 @@ pub fn alloc_nums<E, CS> (
 
 pub fn alloc_bits<E, CS> (
     mut cs: CS,
-    array: &Vec::<Option<bool>>,
+    array: &[Option<bool>],
 ) -> Result<Vec::<Boolean>, SynthesisError>
     where E: JubjubEngine,
           CS: ConstraintSystem<E>,
 {
     let mut allocated_array = Vec::with_capacity(array.len());
 
-    for (i, x) in array.into_iter().enumerate() {
+    for (i, x) in array.iter().enumerate() {
         let value = Boolean::from(
             AllocatedBit::alloc(
                 cs.namespace(|| format!("allocate bit {}", i)),
-                x.clone(),
+                *x,
             )?
         );
         allocated_array.push(value);
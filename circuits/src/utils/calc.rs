@@ -160,7 +160,7 @@ pub fn boolean_to_allocated_num<E, CS> (
 pub fn check_decomposition_le<E, CS> (
     mut cs: CS,
     num: &AllocatedNum<E>,
-    bits: &Vec::<Boolean>,
+    bits: &[Boolean],
 ) -> Result<(), SynthesisError>
     where E: JubjubEngine,
           CS: ConstraintSystem<E>,
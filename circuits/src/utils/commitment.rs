@@ -0,0 +1,135 @@
+use bellman_ce::{
+    ConstraintSystem,
+    SynthesisError,
+    LinearCombination,
+};
+
+use sapling_crypto_ce::{
+    jubjub::JubjubEngine,
+    circuit::{
+        num::AllocatedNum,
+        boolean::Boolean,
+        sha256::sha256,
+    },
+};
+
+use pairing_ce::bn256;
+
+use ff_ce::{
+    Field,
+    PrimeField,
+    PrimeFieldRepr,
+};
+
+use sha2::{ Sha256, Digest };
+
+// Public inputs commitment: sha256 over 32-byte big-endian encodings of the
+// inputs, truncated to its lowest 253 bits so the result fits the field.
+// Exposing the commitment instead of the inputs keeps the number of public
+// inputs at one regardless of the batch size.
+
+pub const COMMITMENT_BITS: usize = 253;
+
+const INPUT_BITS: usize = 256;
+
+pub fn calc_commitment(inputs: &[bn256::Fr]) -> bn256::Fr {
+    let mut hasher = Sha256::new();
+    for input in inputs.iter() {
+        let mut input_bytes = Vec::with_capacity(INPUT_BITS / 8);
+        input.into_repr().write_be(&mut input_bytes).expect("writing to vector never fails");
+        hasher.input(&input_bytes);
+    }
+
+    let mut digest = hasher.result().to_vec();
+    digest[0] &= 0xff >> (INPUT_BITS - COMMITMENT_BITS);
+
+    let mut repr = <bn256::Fr as PrimeField>::Repr::default();
+    repr.read_be(&digest[..]).expect("digest is 32 bytes long");
+
+    bn256::Fr::from_repr(repr).expect("commitment is less than modulus")
+}
+
+pub fn commit_inputs<E, CS> (
+    mut cs: CS,
+    inputs: &[AllocatedNum<E>],
+) -> Result<AllocatedNum<E>, SynthesisError>
+    where E: JubjubEngine,
+          CS: ConstraintSystem<E>,
+{
+    // encode inputs as big-endian bits
+    let mut preimage = Vec::with_capacity(inputs.len() * INPUT_BITS);
+    for (i, input) in inputs.iter().enumerate() {
+        let mut bits = input.into_bits_le_strict(
+            cs.namespace(|| format!("decompose input {}", i)),
+        )?;
+        bits.resize(INPUT_BITS, Boolean::constant(false));
+        bits.reverse();
+
+        preimage.append(&mut bits);
+    }
+
+    let digest = sha256(
+        cs.namespace(|| "calculate sha256"),
+        &preimage,
+    )?;
+
+    // pack lowest bits of the digest
+    let mut value = Some(E::Fr::zero());
+    let mut coeff = E::Fr::one();
+    let mut packed = LinearCombination::<E>::zero();
+
+    for bit in digest.iter().rev().take(COMMITMENT_BITS) {
+        packed = packed + &bit.lc(CS::one(), coeff);
+
+        value = match (value, bit.get_value()) {
+            (Some(mut value), Some(bit)) => {
+                if bit {
+                    value.add_assign(&coeff);
+                }
+                Some(value)
+            },
+            _ => None,
+        };
+
+        coeff.double();
+    }
+
+    let commitment = AllocatedNum::alloc(
+        cs.namespace(|| "allocate commitment"),
+        || value.ok_or(SynthesisError::AssignmentMissing),
+    )?;
+
+    cs.enforce(
+        || "enforce commitment packing",
+        |_| packed,
+        |lc| lc + CS::one(),
+        |lc| lc + commitment.get_variable(),
+    );
+
+    Ok(commitment)
+}
+
+// inputizes values one by one or their single commitment
+pub fn expose_inputs<E, CS> (
+    mut cs: CS,
+    inputs: &[AllocatedNum<E>],
+    commit: bool,
+) -> Result<(), SynthesisError>
+    where E: JubjubEngine,
+          CS: ConstraintSystem<E>,
+{
+    if commit {
+        let commitment = commit_inputs(
+            cs.namespace(|| "commit inputs"),
+            inputs,
+        )?;
+
+        return commitment.inputize(cs.namespace(|| "input commitment"));
+    }
+
+    for (i, input) in inputs.iter().enumerate() {
+        input.inputize(cs.namespace(|| format!("input {}", i)))?;
+    }
+
+    Ok(())
+}
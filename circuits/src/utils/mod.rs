@@ -2,5 +2,7 @@ pub mod alloc;
 pub mod tree;
 pub mod sign;
 pub mod calc;
+pub mod commitment;
+#[allow(clippy::module_inception)]
 pub mod utils;
 
@@ -62,14 +62,39 @@ impl<'a, E> TreeCircuit<'a, E>
             &tree_state.new_leaf,
         )?;
 
+        Self::with_leaves(
+            cs,
+            tree_depth,
+            params,
+            old_leaf_alloc,
+            new_leaf_alloc,
+            &tree_state.path,
+            &tree_state.indices,
+        )
+    }
+
+    // tree over leaves computed in circuit, e.g. nested tree roots
+    pub fn with_leaves<CS: ConstraintSystem<E>> (
+        mut cs: CS,
+        tree_depth: usize,
+        params: &'a <E as PoseidonEngine>::Params,
+        old_leaf_alloc: Vec::<AllocatedNum<E>>,
+        new_leaf_alloc: Vec::<AllocatedNum<E>>,
+        path: &[Option<E::Fr>],
+        indices: &[Option<bool>],
+    ) -> Result<Self, SynthesisError> {
+        assert_eq!(old_leaf_alloc.len(), new_leaf_alloc.len());
+        assert_eq!(indices.len(), tree_depth);
+        assert_eq!(path.len(), tree_depth);
+
         let path_alloc = alloc_nums(
             cs.namespace(|| "allocate leaf path"),
-            &tree_state.path,
+            path,
         )?;
 
         let indices_alloc = alloc_bits(
             cs.namespace(|| "allocate leaf path indices"),
-            &tree_state.indices,
+            indices,
         )?;
 
         let tree = TreeCircuit {
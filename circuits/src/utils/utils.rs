@@ -14,15 +14,26 @@ use ff_ce::{
 
 const BITS_IN_BYTE: usize = 8;
 
-pub fn fr_to_usize(fr_a: bn256::Fr) -> usize {
+// ids and nonces wider than a usize don't fit
+pub fn fr_to_usize(fr_a: bn256::Fr) -> Option<usize> {
     let a = fr_a.to_hex();
-    usize::from_str_radix(a.as_str(), 16).expect("Failed to parse hex")
+    usize::from_str_radix(a.as_str(), 16).ok()
 }
 
 pub fn usize_to_fr(a: usize) -> bn256::Fr {
     bn256::Fr::from_hex(&format!("{:#066x}", a)).expect("Failed to parse hex")
 }
 
+// amounts and balances are 128 bits wide, wider values don't fit
+pub fn fr_to_u128(fr_a: bn256::Fr) -> Option<u128> {
+    let a = fr_a.to_hex();
+    u128::from_str_radix(a.as_str(), 16).ok()
+}
+
+pub fn u128_to_fr(a: u128) -> bn256::Fr {
+    bn256::Fr::from_hex(&format!("{:#066x}", a)).expect("Failed to parse hex")
+}
+
 pub fn bool_to_fr(cond: bool) -> bn256::Fr {
     if cond {
         bn256::Fr::one()
@@ -50,7 +61,7 @@ pub fn fr_to_bytes_le(value: bn256::Fr, bytes_len: usize) -> Vec::<u8> {
     let mut value_bytes = Vec::with_capacity(bytes_len);
     for (i, byte_chunk) in value_bits.chunks(BITS_IN_BYTE).enumerate() {
         let mut byte = 0u8;
-        for (j, bit) in byte_chunk.into_iter().enumerate() {
+        for (j, bit) in byte_chunk.iter().enumerate() {
             if *bit {
                 byte += 1 << j;
             }
@@ -96,15 +107,15 @@ pub fn write_formatted_vector<T: fmt::Debug>(
     output: Vec::<Vec::<T>>,
     annotation: &str,
 ) -> fmt::Result {
-    write!(f, "{}: [\n", annotation)?;
+    writeln!(f, "{}: [", annotation)?;
     for subvector in output.iter() {
-        write!(f, "    [\n")?;
-        for elem in subvector.into_iter() {
-            write!(f, "        {:?},\n", elem)?;
+        writeln!(f, "    [")?;
+        for elem in subvector.iter() {
+            writeln!(f, "        {:?},", elem)?;
         }
-        write!(f, "    ],\n")?;
+        writeln!(f, "    ],")?;
     }
-    write!(f, "]\n")?;
+    writeln!(f, "]")?;
 
     Ok(())
 }
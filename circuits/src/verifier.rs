@@ -0,0 +1,129 @@
+use std::fmt;
+use std::error::Error;
+use std::path::Path;
+
+use bellman_ce::{
+    SynthesisError,
+    groth16::{
+        Proof,
+        VerifyingKey,
+        PreparedVerifyingKey,
+        prepare_verifying_key,
+        verify_proof,
+    },
+};
+
+use pairing_ce::{
+    bn256,
+    bn256::Bn256,
+};
+
+use crate::keys::{
+    KeyHeader,
+    KeyError,
+    load_verifying_key,
+};
+
+#[derive(Debug)]
+pub enum VerificationError {
+    InputsMismatch { expected: usize, actual: usize },
+    InvalidProof,
+    MalformedKey(SynthesisError),
+}
+
+impl Error for VerificationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VerificationError::MalformedKey(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for VerificationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            VerificationError::InputsMismatch { expected, actual } => {
+                write!(f, "Expected {} public inputs, got {}", expected, actual)
+            },
+            VerificationError::InvalidProof => write!(f, "Proof is invalid"),
+            VerificationError::MalformedKey(e) => {
+                write!(f, "Malformed verifying key: ")?;
+                e.fmt(f)
+            },
+        }
+    }
+}
+
+impl From<SynthesisError> for VerificationError {
+    fn from(err: SynthesisError) -> Self {
+        VerificationError::MalformedKey(err)
+    }
+}
+
+fn verify_prepared(
+    pvk: &PreparedVerifyingKey<Bn256>,
+    expected: usize,
+    public_inputs: &[bn256::Fr],
+    proof: &Proof<Bn256>,
+) -> Result<(), VerificationError> {
+    if public_inputs.len() != expected {
+        return Err(VerificationError::InputsMismatch { expected, actual: public_inputs.len() });
+    }
+
+    if !verify_proof(pvk, proof, public_inputs)? {
+        return Err(VerificationError::InvalidProof);
+    }
+
+    Ok(())
+}
+
+// checks a batch or block proof against the public inputs
+// returned by the operator (or the single commitment, if enabled)
+pub fn verify_batch_proof(
+    vk: &VerifyingKey<Bn256>,
+    public_inputs: &[bn256::Fr],
+    proof: &Proof<Bn256>,
+) -> Result<(), VerificationError> {
+    // first ic element is the constant term
+    verify_prepared(&prepare_verifying_key(vk), vk.ic.len() - 1, public_inputs, proof)
+}
+
+// Verifier for published proofs of one circuit, needs the verifying key only
+pub struct BatchVerifier {
+    pub header: KeyHeader,
+    pvk: PreparedVerifyingKey<Bn256>,
+}
+
+impl BatchVerifier {
+    pub fn new(
+        header: KeyHeader,
+        vk: &VerifyingKey<Bn256>,
+    ) -> Result<Self, KeyError> {
+        let found = vk.ic.len() - 1;
+        if found != header.num_inputs() {
+            return Err(KeyError::InputsMismatch { expected: header.num_inputs(), found });
+        }
+
+        Ok(BatchVerifier {
+            header,
+            pvk: prepare_verifying_key(vk),
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        header: KeyHeader,
+    ) -> Result<Self, KeyError> {
+        let vk = load_verifying_key(path, &header)?;
+        Self::new(header, &vk)
+    }
+
+    pub fn verify(
+        &self,
+        public_inputs: &[bn256::Fr],
+        proof: &Proof<Bn256>,
+    ) -> Result<(), VerificationError> {
+        verify_prepared(&self.pvk, self.header.num_inputs(), public_inputs, proof)
+    }
+}
@@ -4,217 +4,81 @@ use openplasma_circuits::{
         deposit::Deposit,
         onchain_withdrawal::OnchainWithdrawal,
         offchain_withdrawal::OffchainWithdrawal,
+        exit::Exit,
+    },
+    operator::{ Operator, OperatorError, DEFAULT_QUEUE_CAPACITY },
+    utils::utils::{ fr_to_usize, usize_to_fr, fr_to_u128, u128_to_fr },
+    utils::commitment::calc_commitment,
+    tree::account::{ AccountsTree, TreeError },
+    tree::proof::verify_account_proof,
+    transfer_circuit::TransferCircuit,
+    deposit_circuit::DepositCircuit,
+    onchain_withdrawal_circuit::OnchainWithdrawalCircuit,
+    exit_circuit::{ ExitCircuit, prove_exit, prove_exit_from_state },
+    block_circuit::padding_pubkey,
+    keys::{
+        CircuitType,
+        KeyHeader,
+        KeyError,
+        setup_deposit_circuit,
+        setup_onchain_withdraw_circuit,
+        setup_offchain_withdraw_circuit,
+        setup_transfer_circuit,
+        setup_block_circuit,
+        setup_exit_circuit,
+        save_parameters,
+        load_parameters,
+        save_verifying_key,
+        load_verifying_key,
+    },
+    abi::{
+        AbiError,
+        encode_call,
+        decode_call,
+        to_hex,
+        from_hex,
+        to_json,
+        fr_to_word,
+        word_to_hex,
+    },
+    verifier::{ BatchVerifier, VerificationError },
+    storage::{ StorageError, write_state, read_state, save_state, load_state, read_log },
+    history::BlockHistory,
+    solidity::{
+        verifying_key_to_uints,
+        verifying_key_to_json,
+        generate_verifier_contract,
+        verifier_contract_name,
     },
-    operator::Operator,
-    utils::utils::{fr_to_usize, usize_to_fr},
-    account::AccountState,
-    deposit_circuit::{ DepositCircuit, DepositBatchCircuit },
-    onchain_withdrawal_circuit::{ OnchainWithdrawalCircuit, OnchainWithdrawalBatchCircuit },
-    offchain_withdrawal_circuit::{ OffchainWithdrawalCircuit, OffchainWithdrawalBatchCircuit },
-    transfer_circuit::{ TransferCircuit, TransferBatchCircuit },
 };
 
 use bellman_ce::{
-    SynthesisError,
+    Circuit,
+    ConstraintSystem,
     groth16::{
-        Parameters,
-        generate_random_parameters,
+        Proof,
         prepare_verifying_key,
         verify_proof,
     },
 };
 
 use sapling_crypto_ce::{
-    poseidon::{bn256::Bn256PoseidonParams, poseidon_hash},
+    poseidon::{ bn256::Bn256PoseidonParams, poseidon_hash },
     group_hash::BlakeHasher,
     jubjub::FixedGenerators,
     alt_babyjubjub::AltJubjubBn256,
     eddsa::{ PublicKey, PrivateKey },
+    circuit::{ num::AllocatedNum, test::TestConstraintSystem },
 };
 
-use pairing_ce::bn256::Bn256;
-
-use rand::{ Rng, thread_rng };
-
-// circuit params generation ------------------------------------------------------------
-// --------------------------------------------------------------------------------------
-
-fn setup_deposit_circuit<'a>(
-    deposit_batch: usize,
-    account_depth: usize,
-    hash_params: &'a Bn256PoseidonParams,
-) -> Result<Parameters<Bn256>, SynthesisError> {
-    let account_state = AccountState::<Bn256> {
-        old_balance: None,
-        new_balance: None,
-        old_pubkey: None,
-        old_nonce: None,
-        new_pubkey: None,
-        new_nonce: None,
-        account_path: vec![None; account_depth],
-        account_indices: vec![None; account_depth],
-    };
-
-    let deposit_gen = || {
-        DepositCircuit::<Bn256> {
-            account_state: account_state.clone(),
-            pubkey: None,
-            account_id: None,
-            amount: None,
-        }
-    };
-
-    let mut deposit_queue = Vec::with_capacity(deposit_batch);
-    deposit_queue.resize_with(deposit_batch, deposit_gen);
-
-    let circuit = DepositBatchCircuit {
-        deposit_batch,
-        account_depth,
-        hash_params,
-        deposit_queue,
-        old_accum_hash: None,
-        new_accum_hash: None,
-        old_account_root: None,
-        new_account_root: None,
-    };
-
-    let mut rng = thread_rng();
-    generate_random_parameters(circuit, &mut rng)
-}
-
-fn setup_onchain_withdraw_circuit<'a>(
-    batch_size: usize,
-    account_depth: usize,
-    hash_params: &'a Bn256PoseidonParams,
-) -> Result<Parameters<Bn256>, SynthesisError> {
-    let account_state = AccountState::<Bn256> {
-        old_balance: None,
-        new_balance: None,
-        old_pubkey: None,
-        old_nonce: None,
-        new_pubkey: None,
-        new_nonce: None,
-        account_path: vec![None; account_depth],
-        account_indices: vec![None; account_depth],
-    };
-
-    let withdrawal_gen = || {
-        OnchainWithdrawalCircuit::<Bn256> {
-            account_state: account_state.clone(),
-            account_id: None,
-            amount: None,
-        }
-    };
-
-    let mut queue = Vec::with_capacity(batch_size);
-    queue.resize_with(batch_size, withdrawal_gen);
-
-    let circuit = OnchainWithdrawalBatchCircuit {
-        batch_size,
-        account_depth,
-        hash_params,
-        queue,
-        old_accum_hash: None,
-        new_accum_hash: None,
-        old_account_root: None,
-        new_account_root: None,
-    };
-
-    let mut rng = thread_rng();
-    generate_random_parameters(circuit, &mut rng)
-}
-
-fn setup_offchain_withdraw_circuit<'a>(
-    batch_size: usize,
-    account_depth: usize,
-    hash_params: &'a Bn256PoseidonParams,
-    sign_params: &'a AltJubjubBn256,
-) -> Result<Parameters<Bn256>, SynthesisError> {
-    let account_state = AccountState::<Bn256> {
-        old_balance: None,
-        new_balance: None,
-        old_pubkey: None,
-        old_nonce: None,
-        new_pubkey: None,
-        new_nonce: None,
-        account_path: vec![None; account_depth],
-        account_indices: vec![None; account_depth],
-    };
-
-    let withdrawal_gen = || {
-        OffchainWithdrawalCircuit::<Bn256> {
-            account_state: account_state.clone(),
-            account_id: None,
-            amount: None,
-            nonce: None,
-            sign: None,
-            pubkey: None,
-        }
-    };
-
-    let mut queue = Vec::with_capacity(batch_size);
-    queue.resize_with(batch_size, withdrawal_gen);
-
-    let circuit = OffchainWithdrawalBatchCircuit {
-        batch_size,
-        account_depth,
-        hash_params,
-        sign_params,
-        queue,
-        old_account_root: None,
-        new_account_root: None,
-    };
-
-    let mut rng = thread_rng();
-    generate_random_parameters(circuit, &mut rng)
-}
-
-fn setup_transfer_circuit<'a>(
-    batch_size: usize,
-    account_depth: usize,
-    hash_params: &'a Bn256PoseidonParams,
-    sign_params: &'a AltJubjubBn256,
-) -> Result<Parameters<Bn256>, SynthesisError> {
-    let account_state = AccountState::<Bn256> {
-        old_balance: None,
-        new_balance: None,
-        old_pubkey: None,
-        old_nonce: None,
-        new_pubkey: None,
-        new_nonce: None,
-        account_path: vec![None; account_depth],
-        account_indices: vec![None; account_depth],
-    };
-
-    let transfer_gen = || {
-        TransferCircuit::<Bn256> {
-            account_state_from: account_state.clone(),
-            account_state_to: account_state.clone(),
-            account_id_from: None,
-            account_id_to: None,
-            amount: None,
-            nonce: None,
-            sign: None,
-            pubkey: None,
-        }
-    };
-
-    let mut queue = Vec::with_capacity(batch_size);
-    queue.resize_with(batch_size, transfer_gen);
+use pairing_ce::{
+    EncodedPoint,
+    bn256::{ self, Bn256, G1Uncompressed },
+};
 
-    let circuit = TransferBatchCircuit {
-        batch_size,
-        account_depth,
-        hash_params,
-        sign_params,
-        queue,
-        old_account_root: None,
-        new_account_root: None,
-    };
+use ff_ce::Field;
 
-    let mut rng = thread_rng();
-    generate_random_parameters(circuit, &mut rng)
-}
+use rand::{ Rng, thread_rng };
 
 // tests --------------------------------------------------------------------------------
 // --------------------------------------------------------------------------------------
@@ -224,12 +88,12 @@ pub fn happy_path() {
     let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
     let sign_params = AltJubjubBn256::new();
 
-    let dep_params = setup_deposit_circuit(2, 2, &hash_params).unwrap();
-    let transfer_params = setup_transfer_circuit(1, 2, &hash_params, &sign_params).unwrap();
-    let of_w_params = setup_offchain_withdraw_circuit(1, 2, &hash_params, &sign_params).unwrap();
-    let on_w_params = setup_onchain_withdraw_circuit(2, 2, &hash_params).unwrap();
+    let dep_params = setup_deposit_circuit(2, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let transfer_params = setup_transfer_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let of_w_params = setup_offchain_withdraw_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let on_w_params = setup_onchain_withdraw_circuit(2, 2, 1, &hash_params, false).unwrap();
 
-    let mut oper = Operator::new(2, 2, 1, 1, 2, &hash_params, &sign_params, 
+    let mut oper = Operator::new(2, 1, 2, 1, 1, 2, &hash_params, &sign_params, 
         &dep_params, &transfer_params, &of_w_params, &on_w_params);
     
     let mut rng = thread_rng();
@@ -253,6 +117,7 @@ pub fn happy_path() {
     let deposit_maker = Deposit {
         pubkey: Some(pubkey_maker.clone()),
         account_id: 0,
+        token_id: 0,
         amount: 100,
     };
     oper.add_deposit(deposit_maker.clone()).unwrap();
@@ -260,6 +125,7 @@ pub fn happy_path() {
     let deposit_taker = Deposit {
         pubkey: Some(pubkey_taker.clone()),
         account_id: 1,
+        token_id: 0,
         amount: 100,
     };
     oper.add_deposit(deposit_taker.clone()).unwrap();
@@ -292,15 +158,18 @@ pub fn happy_path() {
 
     // check after deposit execution
 
-    assert_eq!(fr_to_usize(oper.tree.get_balance(0)), 100);
-    assert_eq!(fr_to_usize(oper.tree.get_balance(1)), 100);
+    assert_eq!(oper.tree.get_balance(0, 0).unwrap(), 100);
+    assert_eq!(oper.tree.get_balance(1, 0).unwrap(), 100);
 
     // check transfer execution ------------------------------------------------------------
 
     let mut transfer = Transfer {
         account_id_from: 0,
         account_id_to: 1,
+        pubkey_to: None,
+        token_id: 0,
         amount: 1,
+        fee: 0,
         nonce: 1,
         sign: None,
     };
@@ -334,14 +203,16 @@ pub fn happy_path() {
 
     assert_eq!(oper.transfer_queue.len(), 0);
 
-    assert_eq!(fr_to_usize(oper.tree.get_balance(0)), 99);
-    assert_eq!(fr_to_usize(oper.tree.get_balance(1)), 101);
+    assert_eq!(oper.tree.get_balance(0, 0).unwrap(), 99);
+    assert_eq!(oper.tree.get_balance(1, 0).unwrap(), 101);
 
     // check offchain withdrawal execution ----------------------------------------------
 
     let mut withdrawal = OffchainWithdrawal {
         account_id: 0,
+        token_id: 0,
         amount: 10,
+        fee: 0,
         nonce: 2,
         sign: None,
     };
@@ -371,12 +242,13 @@ pub fn happy_path() {
 
     // check withdrawal execution
 
-    assert_eq!(fr_to_usize(oper.tree.get_balance(0)), 89);
+    assert_eq!(oper.tree.get_balance(0, 0).unwrap(), 89);
 
     // check onchain withdrawal ---------------------------------------------------------
 
     let mut withdrawal = OnchainWithdrawal {
         account_id: 0,
+        token_id: 0,
         amount: None,
     };
     oper.add_onchain_withdrawal(withdrawal.clone()).unwrap();
@@ -408,6 +280,2569 @@ pub fn happy_path() {
 
     // check withdrawal execution
 
-    assert_eq!(fr_to_usize(oper.tree.get_balance(0)), 0);
-    assert_eq!(fr_to_usize(oper.tree.get_balance(1)), 0);
+    assert_eq!(oper.tree.get_balance(0, 0).unwrap(), 0);
+    assert_eq!(oper.tree.get_balance(1, 0).unwrap(), 0);
+}
+
+#[test]
+pub fn admission_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let dep_params = setup_deposit_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let transfer_params = setup_transfer_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let of_w_params = setup_offchain_withdraw_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let on_w_params = setup_onchain_withdraw_circuit(1, 2, 1, &hash_params, false).unwrap();
+
+    let mut oper = Operator::new(2, 1, 1, 1, 1, 1, &hash_params, &sign_params,
+        &dep_params, &transfer_params, &of_w_params, &on_w_params);
+
+    let mut rng = thread_rng();
+
+    let seckey_maker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_maker = PublicKey::from_private(
+        &seckey_maker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let seckey_taker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_taker = PublicKey::from_private(
+        &seckey_taker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    // admission checks run against the admitted deposits
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_maker.clone()),
+        account_id: 0,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_taker.clone()),
+        account_id: 1,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+
+    let mut bad_transfer = Transfer {
+        account_id_from: 0,
+        account_id_to: 4,
+        pubkey_to: None,
+        token_id: 0,
+        amount: 1,
+        fee: 0,
+        nonce: 1,
+        sign: None,
+    };
+    bad_transfer.sign(&seckey_maker, &hash_params, &sign_params);
+    assert!(matches!(oper.add_transfer(bad_transfer.clone()), Err(OperatorError::UnknownAccount { account_id: 4 })));
+
+    bad_transfer.account_id_to = 1;
+    bad_transfer.sign(&seckey_taker, &hash_params, &sign_params);
+    assert!(matches!(oper.add_transfer(bad_transfer.clone()), Err(OperatorError::InvalidSignature { account_id: 0 })));
+
+    bad_transfer.nonce = 2;
+    bad_transfer.sign(&seckey_maker, &hash_params, &sign_params);
+    assert!(matches!(
+        oper.add_transfer(bad_transfer.clone()),
+        Err(OperatorError::NonceMismatch { account_id: 0, expected: 1, actual: 2 })
+    ));
+
+    bad_transfer.nonce = 1;
+    bad_transfer.amount = 101;
+    bad_transfer.sign(&seckey_maker, &hash_params, &sign_params);
+    assert!(matches!(oper.add_transfer(bad_transfer), Err(OperatorError::InsufficientBalance { account_id: 0, balance: 100, amount: 101 })));
+
+    assert_eq!(oper.transfer_queue.len(), 0);
+}
+
+#[test]
+pub fn queue_capacity_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let dep_params = setup_deposit_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let transfer_params = setup_transfer_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let of_w_params = setup_offchain_withdraw_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let on_w_params = setup_onchain_withdraw_circuit(1, 2, 1, &hash_params, false).unwrap();
+
+    let mut oper = Operator::new(2, 1, 1, 1, 1, 1, &hash_params, &sign_params,
+        &dep_params, &transfer_params, &of_w_params, &on_w_params);
+
+    let mut rng = thread_rng();
+
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let deposit = Deposit {
+        pubkey: Some(pubkey.clone()),
+        account_id: 0,
+        token_id: 0,
+        amount: 100,
+    };
+
+    // full queue refuses requests until it drains or grows
+
+    oper.queue_capacity = 1;
+    oper.add_deposit(deposit.clone()).unwrap();
+    assert!(matches!(oper.add_deposit(deposit.clone()), Err(OperatorError::QueueFull { capacity: 1 })));
+    assert_eq!(oper.deposit_queue.len(), 1);
+    assert_eq!(oper.get_pending_balance(0, 0).unwrap(), 100);
+
+    // queues are bounded separately
+    oper.add_onchain_withdrawal(OnchainWithdrawal {
+        account_id: 0,
+        token_id: 0,
+        amount: None,
+    }).unwrap();
+
+    oper.queue_capacity = DEFAULT_QUEUE_CAPACITY;
+    oper.add_deposit(deposit).unwrap();
+    assert_eq!(oper.deposit_queue.len(), 2);
+}
+
+#[test]
+pub fn pending_state_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let dep_params = setup_deposit_circuit(2, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let transfer_params = setup_transfer_circuit(2, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let of_w_params = setup_offchain_withdraw_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let on_w_params = setup_onchain_withdraw_circuit(1, 2, 1, &hash_params, false).unwrap();
+
+    let mut oper = Operator::new(2, 1, 2, 2, 1, 1, &hash_params, &sign_params,
+        &dep_params, &transfer_params, &of_w_params, &on_w_params);
+
+    let mut rng = thread_rng();
+
+    let seckey_maker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_maker = PublicKey::from_private(
+        &seckey_maker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let seckey_taker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_taker = PublicKey::from_private(
+        &seckey_taker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    // queued deposits are visible before they are batched
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_maker.clone()),
+        account_id: 0,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_taker.clone()),
+        account_id: 1,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+
+    assert_eq!(oper.get_pending_balance(0, 0).unwrap(), 100);
+    assert_eq!(oper.tree.get_balance(0, 0).unwrap(), 0);
+
+    oper.execute_deposit_batch().unwrap();
+
+    // queued transfers are validated against each other
+
+    let mut transfer = Transfer {
+        account_id_from: 0,
+        account_id_to: 1,
+        pubkey_to: None,
+        token_id: 0,
+        amount: 10,
+        fee: 0,
+        nonce: 1,
+        sign: None,
+    };
+    transfer.sign(&seckey_maker, &hash_params, &sign_params);
+    oper.add_transfer(transfer.clone()).unwrap();
+
+    assert_eq!(oper.get_next_nonce(0).unwrap(), 2);
+    assert_eq!(oper.get_pending_balance(0, 0).unwrap(), 90);
+    assert_eq!(oper.get_pending_balance(1, 0).unwrap(), 110);
+    assert!(matches!(
+        oper.add_transfer(transfer.clone()),
+        Err(OperatorError::NonceMismatch { account_id: 0, expected: 2, actual: 1 })
+    ));
+
+    transfer.nonce = 2;
+    transfer.amount = 91;
+    transfer.sign(&seckey_maker, &hash_params, &sign_params);
+    assert!(matches!(
+        oper.add_transfer(transfer.clone()),
+        Err(OperatorError::InsufficientBalance { account_id: 0, balance: 90, amount: 91 })
+    ));
+
+    transfer.amount = 90;
+    transfer.sign(&seckey_maker, &hash_params, &sign_params);
+    oper.add_transfer(transfer).unwrap();
+
+    assert_eq!(oper.get_next_nonce(0).unwrap(), 3);
+    assert_eq!(oper.get_pending_balance(0, 0).unwrap(), 0);
+    assert_eq!(oper.get_pending_balance(1, 0).unwrap(), 200);
+
+    // batched state matches the pending view
+
+    oper.execute_transfer_batch().unwrap();
+
+    assert_eq!(oper.tree.get_balance(0, 0).unwrap(), 0);
+    assert_eq!(oper.tree.get_balance(1, 0).unwrap(), 200);
+    assert_eq!(fr_to_usize(oper.tree.get_nonce(0).unwrap()), Some(2));
+    assert_eq!(oper.get_next_nonce(0).unwrap(), 3);
+    assert_eq!(oper.get_pending_balance(1, 0).unwrap(), 200);
+
+    // nonces past the circuit width are refused at admission
+
+    oper.tree.update_nonce(1, usize_to_fr((1 << 32) - 1)).unwrap();
+    assert_eq!(oper.get_next_nonce(1).unwrap(), 1 << 32);
+
+    let mut transfer = Transfer {
+        account_id_from: 1,
+        account_id_to: 0,
+        pubkey_to: None,
+        token_id: 0,
+        amount: 1,
+        fee: 0,
+        nonce: 1 << 32,
+        sign: None,
+    };
+    transfer.sign(&seckey_taker, &hash_params, &sign_params);
+    assert!(matches!(
+        oper.add_transfer(transfer),
+        Err(OperatorError::NonceOverflow { account_id: 1 })
+    ));
+
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: 1,
+        token_id: 0,
+        amount: 1,
+        fee: 0,
+        nonce: 1 << 32,
+        sign: None,
+    };
+    withdrawal.sign(&seckey_taker, &hash_params, &sign_params);
+    assert!(matches!(
+        oper.add_offchain_withdrawal(withdrawal),
+        Err(OperatorError::NonceOverflow { account_id: 1 })
+    ));
+    assert_eq!(oper.transfer_queue.len(), 0);
+    assert_eq!(oper.offchain_withdrawal_queue.len(), 0);
+
+    // pending state is rebuilt in admission order, not queue by queue
+
+    oper.add_onchain_withdrawal(OnchainWithdrawal {
+        account_id: 0,
+        token_id: 0,
+        amount: None,
+    }).unwrap();
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_maker.clone()),
+        account_id: 0,
+        token_id: 0,
+        amount: 50,
+    }).unwrap();
+    assert_eq!(oper.get_pending_balance(0, 0).unwrap(), 50);
+
+    oper.set_fee_account(1, 0).unwrap();
+    assert_eq!(oper.get_pending_balance(0, 0).unwrap(), 50);
+
+    oper.execute_onchain_withdrawal_batch().unwrap();
+    oper.close_deposit_batch().unwrap();
+    assert_eq!(oper.tree.get_balance(0, 0).unwrap(), 50);
+    assert_eq!(oper.admission_order.len(), 0);
+
+    // request invalidated by a batch run out of admission order is left out
+
+    let mut transfer = Transfer {
+        account_id_from: 0,
+        account_id_to: 1,
+        pubkey_to: None,
+        token_id: 0,
+        amount: 50,
+        fee: 0,
+        nonce: 3,
+        sign: None,
+    };
+    transfer.sign(&seckey_maker, &hash_params, &sign_params);
+    oper.add_transfer(transfer).unwrap();
+
+    oper.add_onchain_withdrawal(OnchainWithdrawal {
+        account_id: 0,
+        token_id: 0,
+        amount: None,
+    }).unwrap();
+
+    oper.execute_onchain_withdrawal_batch().unwrap();
+    assert_eq!(oper.tree.get_balance(0, 0).unwrap(), 0);
+    assert_eq!(oper.get_pending_balance(0, 0).unwrap(), 0);
+    assert_eq!(oper.get_pending_balance(1, 0).unwrap(), 200);
+    assert_eq!(oper.get_next_nonce(0).unwrap(), 3);
+
+    match oper.close_transfer_batch() {
+        Err(OperatorError::RequestEvicted { index: 0, error }) => {
+            assert!(matches!(*error, OperatorError::InsufficientBalance { account_id: 0, balance: 0, amount: 50 }));
+        },
+        _ => panic!("transfer must be evicted"),
+    }
+    assert_eq!(oper.transfer_queue.len(), 0);
+    assert_eq!(oper.admission_order.len(), 0);
+}
+
+#[test]
+pub fn eviction_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let dep_params = setup_deposit_circuit(2, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let transfer_params = setup_transfer_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let of_w_params = setup_offchain_withdraw_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let on_w_params = setup_onchain_withdraw_circuit(1, 2, 1, &hash_params, false).unwrap();
+
+    let mut oper = Operator::new(2, 1, 2, 1, 1, 1, &hash_params, &sign_params,
+        &dep_params, &transfer_params, &of_w_params, &on_w_params);
+
+    let mut rng = thread_rng();
+
+    let seckey_maker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_maker = PublicKey::from_private(
+        &seckey_maker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let seckey_taker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_taker = PublicKey::from_private(
+        &seckey_taker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_maker.clone()),
+        account_id: 0,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_taker.clone()),
+        account_id: 1,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+
+    // transfer relying on queued deposits fails if executed first and is evicted
+
+    let mut early_transfer = Transfer {
+        account_id_from: 0,
+        account_id_to: 1,
+        pubkey_to: None,
+        token_id: 0,
+        amount: 1,
+        fee: 0,
+        nonce: 1,
+        sign: None,
+    };
+    early_transfer.sign(&seckey_maker, &hash_params, &sign_params);
+    oper.add_transfer(early_transfer).unwrap();
+
+    let root_before = oper.tree.get_root();
+    match oper.execute_transfer_batch() {
+        Err(err @ OperatorError::RequestEvicted { .. }) => {
+            if let OperatorError::RequestEvicted { error, .. } = &err {
+                assert!(matches!(**error, OperatorError::InvalidSignature { account_id: 0 }));
+            }
+            assert_eq!(err.request_index(), Some(0));
+            assert_eq!(err.account_id(), Some(0));
+        },
+        _ => panic!("transfer must be evicted"),
+    }
+    assert_eq!(oper.tree.get_root(), root_before);
+    assert_eq!(oper.transfer_queue.len(), 0);
+    assert_eq!(oper.deposit_queue.len(), 2);
+
+    // evicted request does not hold back the other queues
+
+    oper.execute_deposit_batch().unwrap();
+    assert_eq!(oper.tree.get_balance(0, 0).unwrap(), 100);
+    assert_eq!(oper.tree.get_balance(1, 0).unwrap(), 100);
+    assert_eq!(oper.get_next_nonce(0).unwrap(), 1);
+
+    // failing padding is the operator's own error, nothing is evicted
+
+    let transfer_params = setup_transfer_circuit(2, 2, 1, &hash_params, &sign_params, false).unwrap();
+
+    let mut oper = Operator::new(2, 1, 2, 2, 1, 1, &hash_params, &sign_params,
+        &dep_params, &transfer_params, &of_w_params, &on_w_params);
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_maker.clone()),
+        account_id: 0,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_taker.clone()),
+        account_id: 1,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+    oper.execute_deposit_batch().unwrap();
+
+    let mut transfer = Transfer {
+        account_id_from: 1,
+        account_id_to: 0,
+        pubkey_to: None,
+        token_id: 0,
+        amount: 1,
+        fee: 0,
+        nonce: 1,
+        sign: None,
+    };
+    transfer.sign(&seckey_taker, &hash_params, &sign_params);
+    oper.add_transfer(transfer).unwrap();
+
+    // padding signed with the padding key for an account it doesn't own
+    oper.padding_account_id = 0;
+
+    let root_before = oper.tree.get_root();
+    assert!(matches!(
+        oper.close_transfer_batch(),
+        Err(OperatorError::InvalidSignature { account_id: 0 })
+    ));
+    assert_eq!(oper.tree.get_root(), root_before);
+    assert_eq!(oper.transfer_queue.len(), 1);
+}
+
+#[test]
+pub fn padding_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let dep_params = setup_deposit_circuit(2, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let transfer_params = setup_transfer_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let of_w_params = setup_offchain_withdraw_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let on_w_params = setup_onchain_withdraw_circuit(1, 2, 1, &hash_params, false).unwrap();
+
+    let mut oper = Operator::new(2, 1, 2, 1, 1, 1, &hash_params, &sign_params,
+        &dep_params, &transfer_params, &of_w_params, &on_w_params);
+
+    let mut rng = thread_rng();
+
+    let seckey_taker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_taker = PublicKey::from_private(
+        &seckey_taker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    // partial deposit batch closed with padding
+
+    assert!(matches!(
+        oper.execute_deposit_batch(),
+        Err(OperatorError::NotEnoughObjects { available: 0, required: 2 })
+    ));
+
+    let padding_deposit = Deposit {
+        pubkey: Some(pubkey_taker.clone()),
+        account_id: oper.padding_account_id,
+        token_id: 0,
+        amount: 1,
+    };
+    assert!(matches!(oper.add_deposit(padding_deposit), Err(OperatorError::ReservedAccount { .. })));
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_taker.clone()),
+        account_id: 1,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+    assert!(matches!(oper.execute_deposit_batch(), Err(OperatorError::NotEnoughObjects { .. })));
+
+    let old_hash = oper.deposit_accum_hash;
+    let (public_inputs, proof) = oper.close_deposit_batch().unwrap();
+
+    let verifying_key = prepare_verifying_key(&dep_params.vk);
+    let is_valid = verify_proof(&verifying_key, &proof, &public_inputs).unwrap();
+    assert!(is_valid);
+
+    // padding deposit is not accumulated
+
+    let (pubkey_x, pubkey_y) = pubkey_taker.0.into_xy();
+    let expected_hash = poseidon_hash::<Bn256>(
+        &hash_params,
+        &[old_hash, pubkey_x, pubkey_y, usize_to_fr(1), usize_to_fr(0), usize_to_fr(100)],
+    )[0];
+    assert_eq!(oper.deposit_accum_hash, expected_hash);
+    assert_eq!(oper.deposit_queue.len(), 0);
+    assert_eq!(oper.tree.get_balance(1, 0).unwrap(), 100);
+    assert_eq!(oper.tree.get_balance(oper.padding_account_id, 0).unwrap(), 0);
+
+    // onchain withdrawal keeps the pubkey and empties the balance, padding included
+
+    let synthesize = |circuit: &OnchainWithdrawalCircuit<Bn256>, old_root| {
+        let mut cs = TestConstraintSystem::<Bn256>::new();
+        let old_hash_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate old hash"),
+            || Ok(usize_to_fr(0)),
+        ).unwrap();
+        let old_root_alloc = AllocatedNum::alloc(
+            cs.namespace(|| "allocate old root"),
+            || Ok(old_root),
+        ).unwrap();
+        circuit.process(
+            cs.namespace(|| "withdrawal"),
+            2,
+            1,
+            &hash_params,
+            &old_hash_alloc,
+            &old_root_alloc,
+            &mut Vec::new(),
+        ).unwrap();
+        cs
+    };
+
+    let mut tree = oper.tree.clone();
+    let old_root = tree.get_root();
+    let withdrawal = OnchainWithdrawal {
+        account_id: oper.padding_account_id,
+        token_id: 0,
+        amount: None,
+    };
+    let mut circuit = OnchainWithdrawalCircuit::<Bn256> {
+        account_state: withdrawal.update_tree_and_record_state(&mut tree).unwrap(),
+        account_id: Some(usize_to_fr(oper.padding_account_id)),
+        token_id: Some(usize_to_fr(0)),
+        amount: Some(usize_to_fr(0)),
+        padding: Some(true),
+    };
+    assert!(synthesize(&circuit, old_root).is_satisfied());
+
+    circuit.account_state.new_pubkey = Some(pubkey_taker.0.clone());
+    assert_eq!(
+        synthesize(&circuit, old_root).which_is_unsatisfied(),
+        Some("withdrawal/check pubkey x the same"),
+    );
+
+    let mut tree = oper.tree.clone();
+    let withdrawal = OnchainWithdrawal {
+        account_id: 1,
+        token_id: 0,
+        amount: None,
+    };
+    let mut circuit = OnchainWithdrawalCircuit::<Bn256> {
+        account_state: withdrawal.update_tree_and_record_state(&mut tree).unwrap(),
+        account_id: Some(usize_to_fr(1)),
+        token_id: Some(usize_to_fr(0)),
+        amount: Some(usize_to_fr(40)),
+        padding: Some(false),
+    };
+    circuit.account_state.new_balance = Some(usize_to_fr(60));
+    assert_eq!(
+        synthesize(&circuit, old_root).which_is_unsatisfied(),
+        Some("withdrawal/check withdrawal takes all value"),
+    );
+}
+
+#[test]
+pub fn fee_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let dep_params = setup_deposit_circuit(2, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let transfer_params = setup_transfer_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let of_w_params = setup_offchain_withdraw_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let on_w_params = setup_onchain_withdraw_circuit(1, 2, 1, &hash_params, false).unwrap();
+
+    let mut oper = Operator::new(2, 1, 2, 1, 1, 1, &hash_params, &sign_params,
+        &dep_params, &transfer_params, &of_w_params, &on_w_params);
+
+    let mut rng = thread_rng();
+
+    let seckey_maker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_maker = PublicKey::from_private(
+        &seckey_maker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let seckey_taker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_taker = PublicKey::from_private(
+        &seckey_taker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_maker.clone()),
+        account_id: 0,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_taker.clone()),
+        account_id: 1,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+
+    oper.execute_deposit_batch().unwrap();
+
+    // fees are rejected until a fee account is set, then only in its token
+
+    let mut transfer = Transfer {
+        account_id_from: 0,
+        account_id_to: 1,
+        pubkey_to: None,
+        token_id: 0,
+        amount: 1,
+        fee: 2,
+        nonce: 1,
+        sign: None,
+    };
+    transfer.sign(&seckey_maker, &hash_params, &sign_params);
+    assert!(matches!(oper.add_transfer(transfer.clone()), Err(OperatorError::FeeNotAccepted { token_id: 0, fee: 2 })));
+
+    assert!(matches!(oper.set_fee_account(oper.padding_account_id, 0), Err(OperatorError::ReservedAccount { .. })));
+    oper.set_fee_account(2, 0).unwrap();
+
+    transfer.token_id = 1;
+    transfer.sign(&seckey_maker, &hash_params, &sign_params);
+    assert!(matches!(oper.add_transfer(transfer.clone()), Err(OperatorError::FeeNotAccepted { token_id: 1, fee: 2 })));
+
+    // sender pays amount and fee
+
+    transfer.token_id = 0;
+    transfer.amount = 99;
+    transfer.sign(&seckey_maker, &hash_params, &sign_params);
+    assert!(matches!(oper.add_transfer(transfer.clone()), Err(OperatorError::InsufficientBalance { account_id: 0, balance: 100, amount: 101 })));
+
+    transfer.amount = 1;
+    transfer.sign(&seckey_maker, &hash_params, &sign_params);
+    oper.add_transfer(transfer).unwrap();
+
+    assert_eq!(oper.get_pending_balance(0, 0).unwrap(), 97);
+    assert_eq!(oper.get_pending_balance(1, 0).unwrap(), 101);
+    assert_eq!(oper.get_pending_balance(2, 0).unwrap(), 2);
+
+    // fees are credited to the fee account in the same proof
+
+    let (public_inputs, proof) = oper.execute_transfer_batch().unwrap();
+    oper.verify_transfer_batch(&public_inputs, &proof).unwrap();
+
+    assert_eq!(oper.tree.get_balance(0, 0).unwrap(), 97);
+    assert_eq!(oper.tree.get_balance(1, 0).unwrap(), 101);
+    assert_eq!(oper.tree.get_balance(2, 0).unwrap(), 2);
+
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: 0,
+        token_id: 0,
+        amount: 10,
+        fee: 1,
+        nonce: 2,
+        sign: None,
+    };
+    withdrawal.sign(&seckey_maker, &hash_params, &sign_params);
+    oper.add_offchain_withdrawal(withdrawal).unwrap();
+
+    let (public_inputs, proof) = oper.execute_offchain_withdrawal_batch().unwrap();
+    oper.verify_offchain_withdrawal_batch(&public_inputs, &proof).unwrap();
+
+    assert_eq!(oper.tree.get_balance(0, 0).unwrap(), 86);
+    assert_eq!(oper.tree.get_balance(2, 0).unwrap(), 3);
+}
+
+#[test]
+pub fn block_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let dep_params = setup_deposit_circuit(1, 3, 1, &hash_params, &sign_params, false).unwrap();
+    let transfer_params = setup_transfer_circuit(1, 3, 1, &hash_params, &sign_params, false).unwrap();
+    let of_w_params = setup_offchain_withdraw_circuit(1, 3, 1, &hash_params, &sign_params, false).unwrap();
+    let on_w_params = setup_onchain_withdraw_circuit(1, 3, 1, &hash_params, false).unwrap();
+    let block_params = setup_block_circuit(4, 3, 1, &hash_params, &sign_params, false).unwrap();
+
+    let mut oper = Operator::new(3, 1, 1, 1, 1, 1, &hash_params, &sign_params,
+        &dep_params, &transfer_params, &of_w_params, &on_w_params);
+
+    // unsigned operations are signed by the padding key fixed in the block circuit
+    assert_eq!(
+        oper.tree.get_pubkey(oper.padding_account_id).unwrap().0.into_xy(),
+        padding_pubkey::<Bn256>(&sign_params).0.into_xy(),
+    );
+
+    assert!(matches!(oper.execute_block(), Err(OperatorError::BlockCircuitNotSet)));
+    oper.set_block_circuit(4, &block_params);
+    assert!(matches!(oper.execute_block(), Err(OperatorError::NotEnoughObjects { .. })));
+    oper.set_fee_account(2, 0).unwrap();
+
+    let verifying_key = prepare_verifying_key(&block_params.vk);
+    let mut rng = thread_rng();
+
+    let seckey_maker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_maker = PublicKey::from_private(
+        &seckey_maker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let seckey_taker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_taker = PublicKey::from_private(
+        &seckey_taker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    // full block of mixed operations ---------------------------------------------------
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_maker.clone()),
+        account_id: 0,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_taker.clone()),
+        account_id: 1,
+        token_id: 1,
+        amount: 50,
+    }).unwrap();
+
+    // token ids are limited by the balance subtree depth
+    assert!(matches!(
+        oper.add_deposit(Deposit {
+            pubkey: Some(pubkey_taker.clone()),
+            account_id: 1,
+            token_id: 2,
+            amount: 50,
+        }),
+        Err(OperatorError::UnknownToken { token_id: 2 })
+    ));
+
+    let mut transfer = Transfer {
+        account_id_from: 0,
+        account_id_to: 1,
+        pubkey_to: None,
+        token_id: 0,
+        amount: 30,
+        fee: 4,
+        nonce: 1,
+        sign: None,
+    };
+    transfer.sign(&seckey_maker, &hash_params, &sign_params);
+    oper.add_transfer(transfer).unwrap();
+
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: 1,
+        token_id: 1,
+        amount: 20,
+        fee: 0,
+        nonce: 1,
+        sign: None,
+    };
+    withdrawal.sign(&seckey_taker, &hash_params, &sign_params);
+    oper.add_offchain_withdrawal(withdrawal).unwrap();
+
+    oper.add_onchain_withdrawal(OnchainWithdrawal {
+        account_id: 0,
+        token_id: 0,
+        amount: None,
+    }).unwrap();
+
+    let (public_inputs, proof) = oper.execute_block().unwrap();
+
+    let is_valid = verify_proof(&verifying_key, &proof, &public_inputs).unwrap();
+    assert!(is_valid);
+
+    // onchain withdrawal does not fit and stays queued
+    assert_eq!(oper.onchain_withdrawal_queue.len(), 1);
+    assert_eq!(oper.tree.get_balance(0, 0).unwrap(), 66);
+    assert_eq!(oper.tree.get_balance(0, 1).unwrap(), 0);
+    assert_eq!(oper.tree.get_balance(1, 0).unwrap(), 30);
+    assert_eq!(oper.tree.get_balance(1, 1).unwrap(), 30);
+    assert_eq!(oper.tree.get_balance(2, 0).unwrap(), 4);
+
+    // offchain withdrawal is exposed in its slot, other slots are zero
+    assert_eq!(public_inputs.len(), 6 + 3 * 4);
+    assert_eq!(public_inputs[6 + 3 * 3], usize_to_fr(1));
+    assert_eq!(public_inputs[6 + 3 * 3 + 1], usize_to_fr(1));
+    assert_eq!(public_inputs[6 + 3 * 3 + 2], usize_to_fr(20));
+    assert_eq!(public_inputs[6], usize_to_fr(0));
+
+    // partial block filled with no-ops -------------------------------------------------
+
+    let old_hash = oper.withdrawal_accum_hash;
+    let (public_inputs, proof) = oper.execute_block().unwrap();
+
+    let is_valid = verify_proof(&verifying_key, &proof, &public_inputs).unwrap();
+    assert!(is_valid);
+
+    let expected_hash = poseidon_hash::<Bn256>(&hash_params, &[old_hash, usize_to_fr(0), usize_to_fr(0)])[0];
+    assert_eq!(oper.withdrawal_accum_hash, expected_hash);
+    assert_eq!(public_inputs[6 + 2], usize_to_fr(66));
+    assert_eq!(oper.tree.get_balance(0, 0).unwrap(), 0);
+    assert_eq!(oper.onchain_withdrawal_queue.len(), 0);
+}
+
+#[test]
+pub fn verify_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let dep_params = setup_deposit_circuit(2, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let transfer_params = setup_transfer_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let of_w_params = setup_offchain_withdraw_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let on_w_params = setup_onchain_withdraw_circuit(1, 2, 1, &hash_params, false).unwrap();
+    let block_params = setup_block_circuit(2, 2, 1, &hash_params, &sign_params, false).unwrap();
+
+    let mut oper = Operator::new(2, 1, 2, 1, 1, 1, &hash_params, &sign_params,
+        &dep_params, &transfer_params, &of_w_params, &on_w_params);
+
+    let mut rng = thread_rng();
+
+    let seckey_maker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_maker = PublicKey::from_private(
+        &seckey_maker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let pubkey_taker = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    // deposit batch
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_maker.clone()),
+        account_id: 0,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_taker.clone()),
+        account_id: 1,
+        token_id: 0,
+        amount: 50,
+    }).unwrap();
+
+    let (public_inputs, proof) = oper.execute_deposit_batch().unwrap();
+    check_verification(|inputs, proof| oper.verify_deposit_batch(inputs, proof), &public_inputs, &proof);
+
+    // proof of one circuit is refused by the others
+    assert!(matches!(
+        oper.verify_transfer_batch(&public_inputs, &proof),
+        Err(OperatorError::VerificationFailed(VerificationError::InputsMismatch { .. }))
+    ));
+
+    // transfer batch
+
+    let mut transfer = Transfer {
+        account_id_from: 0,
+        account_id_to: 1,
+        pubkey_to: None,
+        token_id: 0,
+        amount: 10,
+        fee: 0,
+        nonce: 1,
+        sign: None,
+    };
+    transfer.sign(&seckey_maker, &hash_params, &sign_params);
+    oper.add_transfer(transfer).unwrap();
+
+    let (public_inputs, proof) = oper.execute_transfer_batch().unwrap();
+    check_verification(|inputs, proof| oper.verify_transfer_batch(inputs, proof), &public_inputs, &proof);
+
+    // offchain withdrawal batch
+
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: 0,
+        token_id: 0,
+        amount: 20,
+        fee: 0,
+        nonce: 2,
+        sign: None,
+    };
+    withdrawal.sign(&seckey_maker, &hash_params, &sign_params);
+    oper.add_offchain_withdrawal(withdrawal).unwrap();
+
+    let (public_inputs, proof) = oper.execute_offchain_withdrawal_batch().unwrap();
+    check_verification(|inputs, proof| oper.verify_offchain_withdrawal_batch(inputs, proof), &public_inputs, &proof);
+
+    // onchain withdrawal batch
+
+    oper.add_onchain_withdrawal(OnchainWithdrawal {
+        account_id: 1,
+        token_id: 0,
+        amount: None,
+    }).unwrap();
+
+    let (public_inputs, proof) = oper.execute_onchain_withdrawal_batch().unwrap();
+    check_verification(|inputs, proof| oper.verify_onchain_withdrawal_batch(inputs, proof), &public_inputs, &proof);
+
+    // block
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_taker),
+        account_id: 1,
+        token_id: 0,
+        amount: 5,
+    }).unwrap();
+
+    assert!(matches!(oper.verify_block(&public_inputs, &proof), Err(OperatorError::BlockCircuitNotSet)));
+    oper.set_block_circuit(2, &block_params);
+
+    let (public_inputs, proof) = oper.execute_block().unwrap();
+    check_verification(|inputs, proof| oper.verify_block(inputs, proof), &public_inputs, &proof);
+
+    // published block is checked with the verifying key only
+
+    let verifier = BatchVerifier::new(
+        KeyHeader::new(CircuitType::Block, 2, 2, 1, false),
+        &block_params.vk,
+    ).unwrap();
+    verifier.verify(&public_inputs, &proof).unwrap();
+
+    let mut wrong_inputs = public_inputs.clone();
+    wrong_inputs[1] = usize_to_fr(1);
+    assert!(matches!(verifier.verify(&wrong_inputs, &proof), Err(VerificationError::InvalidProof)));
+    assert!(matches!(
+        verifier.verify(&public_inputs[1..], &proof),
+        Err(VerificationError::InputsMismatch { expected: 12, actual: 11 })
+    ));
+
+    assert!(matches!(
+        BatchVerifier::new(KeyHeader::new(CircuitType::Block, 4, 2, 1, false), &block_params.vk),
+        Err(KeyError::InputsMismatch { expected: 18, found: 12 })
+    ));
+}
+
+#[test]
+pub fn transfer_to_pubkey_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let dep_params = setup_deposit_circuit(2, 3, 1, &hash_params, &sign_params, false).unwrap();
+    let transfer_params = setup_transfer_circuit(1, 3, 1, &hash_params, &sign_params, false).unwrap();
+    let of_w_params = setup_offchain_withdraw_circuit(1, 3, 1, &hash_params, &sign_params, false).unwrap();
+    let on_w_params = setup_onchain_withdraw_circuit(1, 3, 1, &hash_params, false).unwrap();
+
+    let mut oper = Operator::new(3, 1, 2, 1, 1, 1, &hash_params, &sign_params,
+        &dep_params, &transfer_params, &of_w_params, &on_w_params);
+    oper.set_fee_account(2, 0).unwrap();
+
+    let mut rng = thread_rng();
+
+    let seckey_maker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_maker = PublicKey::from_private(
+        &seckey_maker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let seckey_taker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_taker = PublicKey::from_private(
+        &seckey_taker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let seckey_new = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_new = PublicKey::from_private(
+        &seckey_new,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_maker.clone()),
+        account_id: 0,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_taker.clone()),
+        account_id: 1,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+
+    oper.execute_deposit_batch().unwrap();
+
+    // owned accounts are found by pubkey, fee and padding accounts are never taken
+    assert_eq!(oper.find_recipient_account(&pubkey_maker).unwrap(), 0);
+    assert_eq!(oper.find_recipient_account(&pubkey_new).unwrap(), 3);
+
+    let mut transfer = Transfer {
+        account_id_from: 1,
+        account_id_to: 0,
+        pubkey_to: Some(pubkey_new.clone()),
+        token_id: 0,
+        amount: 10,
+        fee: 0,
+        nonce: 1,
+        sign: None,
+    };
+    transfer.sign(&seckey_taker, &hash_params, &sign_params);
+    oper.add_transfer(transfer).unwrap();
+    assert_eq!(oper.transfer_queue[0].account_id_to, 3);
+    assert_eq!(oper.find_recipient_account(&pubkey_new).unwrap(), 3);
+
+    // transfer by pubkey creates the recipient account
+
+    let (public_inputs, proof) = oper.execute_transfer_batch().unwrap();
+    oper.verify_transfer_batch(&public_inputs, &proof).unwrap();
+
+    assert!(oper.tree.get_pubkey(3).unwrap().0 == pubkey_new.0);
+    assert_eq!(oper.tree.get_balance(3, 0).unwrap(), 10);
+    assert_eq!(oper.tree.get_balance(1, 0).unwrap(), 90);
+    assert_eq!(oper.find_recipient_account(&pubkey_new).unwrap(), 3);
+}
+
+#[test]
+pub fn commitment_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let dep_params = setup_deposit_circuit(1, 2, 1, &hash_params, &sign_params, true).unwrap();
+    let transfer_params = setup_transfer_circuit(1, 2, 1, &hash_params, &sign_params, true).unwrap();
+    let of_w_params = setup_offchain_withdraw_circuit(1, 2, 1, &hash_params, &sign_params, true).unwrap();
+    let on_w_params = setup_onchain_withdraw_circuit(1, 2, 1, &hash_params, true).unwrap();
+
+    let mut oper = Operator::new(2, 1, 1, 1, 1, 1, &hash_params, &sign_params,
+        &dep_params, &transfer_params, &of_w_params, &on_w_params);
+    oper.commit_inputs = true;
+
+    let mut rng = thread_rng();
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey),
+        account_id: 0,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+
+    let (public_inputs, proof) = oper.execute_deposit_batch().unwrap();
+    assert_eq!(public_inputs.len(), 1);
+
+    let verifying_key = prepare_verifying_key(&dep_params.vk);
+    assert!(verify_proof(&verifying_key, &proof, &public_inputs).unwrap());
+
+    // withdrawal list is part of the commitment
+
+    let old_hash = oper.withdrawal_accum_hash;
+    let old_root = oper.tree.get_root();
+
+    oper.add_onchain_withdrawal(OnchainWithdrawal {
+        account_id: 0,
+        token_id: 0,
+        amount: None,
+    }).unwrap();
+
+    let (public_inputs, proof) = oper.execute_onchain_withdrawal_batch().unwrap();
+
+    let expected_commitment = calc_commitment(&[
+        old_hash,
+        oper.withdrawal_accum_hash,
+        old_root,
+        oper.tree.get_root(),
+        usize_to_fr(0),
+        usize_to_fr(0),
+        usize_to_fr(100),
+    ]);
+    assert_eq!(public_inputs, vec![expected_commitment]);
+
+    let verifying_key = prepare_verifying_key(&on_w_params.vk);
+    assert!(verify_proof(&verifying_key, &proof, &public_inputs).unwrap());
+
+    let mut wrong_inputs = public_inputs.clone();
+    wrong_inputs[0] = calc_commitment(&[old_hash]);
+    assert!(!verify_proof(&verifying_key, &proof, &wrong_inputs).unwrap());
+}
+
+#[test]
+pub fn abi_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let dep_params = setup_deposit_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let transfer_params = setup_transfer_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let of_w_params = setup_offchain_withdraw_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let on_w_params = setup_onchain_withdraw_circuit(1, 2, 1, &hash_params, false).unwrap();
+
+    let mut oper = Operator::new(2, 1, 1, 1, 1, 1, &hash_params, &sign_params,
+        &dep_params, &transfer_params, &of_w_params, &on_w_params);
+
+    let mut rng = thread_rng();
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey),
+        account_id: 0,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+
+    let (public_inputs, proof) = oper.execute_deposit_batch().unwrap();
+    let verifying_key = prepare_verifying_key(&dep_params.vk);
+
+    // abi encoding round trip
+    let calldata = encode_call(&public_inputs, &proof);
+    assert_eq!(calldata.len(), 4 + 32 * (2 + 1 + public_inputs.len() + 1 + 8));
+
+    let calldata = from_hex(&to_hex(&calldata)).unwrap();
+    let (decoded_inputs, decoded_proof) = decode_call(&calldata).unwrap();
+    assert_eq!(decoded_inputs, public_inputs);
+    assert!(decoded_proof == proof);
+    assert!(verify_proof(&verifying_key, &decoded_proof, &decoded_inputs).unwrap());
+
+    let json = to_json(&public_inputs, &proof);
+    assert!(json.contains(&word_to_hex(&fr_to_word(&public_inputs[0]))));
+
+    // input above the field modulus is rejected
+    let mut wrong_calldata = calldata.clone();
+    for byte in wrong_calldata[4 + 3 * 32..4 + 4 * 32].iter_mut() {
+        *byte = 0xff;
+    }
+    assert!(matches!(
+        decode_call(&wrong_calldata),
+        Err(AbiError::InvalidFieldElement { index: 0 })
+    ));
+    assert!(matches!(decode_call(&calldata[4..]), Err(AbiError::InvalidSelector)));
+}
+
+#[test]
+pub fn keys_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+
+    let header = KeyHeader::new(CircuitType::OnchainWithdrawal, 1, 2, 1, false);
+    let params = setup_onchain_withdraw_circuit(1, 2, 1, &hash_params, false).unwrap();
+
+    let dir = std::env::temp_dir();
+    let params_path = dir.join(format!("{}-{}", std::process::id(), header.file_name("params")));
+    let vk_path = dir.join(format!("{}-{}", std::process::id(), header.file_name("vk")));
+
+    save_parameters(&params_path, &header, &params).unwrap();
+    save_verifying_key(&vk_path, &header, &params.vk).unwrap();
+
+    let loaded_params = load_parameters(&params_path, &header).unwrap();
+    assert!(loaded_params.vk == params.vk);
+    assert_eq!(loaded_params.h.len(), params.h.len());
+
+    let loaded_vk = load_verifying_key(&vk_path, &header).unwrap();
+    assert!(loaded_vk == params.vk);
+
+    // keys are refused for any other circuit shape
+
+    let other_depth = KeyHeader::new(CircuitType::OnchainWithdrawal, 1, 3, 1, false);
+    assert!(matches!(
+        load_parameters(&params_path, &other_depth),
+        Err(KeyError::HeaderMismatch { .. })
+    ));
+
+    let other_tokens = KeyHeader::new(CircuitType::OnchainWithdrawal, 1, 2, 2, false);
+    assert!(matches!(
+        load_verifying_key(&vk_path, &other_tokens),
+        Err(KeyError::HeaderMismatch { .. })
+    ));
+
+    let other_type = KeyHeader::new(CircuitType::Deposit, 1, 2, 1, false);
+    assert!(matches!(
+        load_verifying_key(&vk_path, &other_type),
+        Err(KeyError::HeaderMismatch { .. })
+    ));
+
+    let committed = KeyHeader::new(CircuitType::OnchainWithdrawal, 1, 2, 1, true);
+    assert!(matches!(
+        save_parameters(&params_path, &committed, &params),
+        Err(KeyError::InputsMismatch { expected: 1, found: 7 })
+    ));
+
+    std::fs::remove_file(&params_path).unwrap();
+    std::fs::remove_file(&vk_path).unwrap();
+}
+
+#[test]
+pub fn solidity_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+
+    let header = KeyHeader::new(CircuitType::OnchainWithdrawal, 1, 2, 1, false);
+    let params = setup_onchain_withdraw_circuit(1, 2, 1, &hash_params, false).unwrap();
+
+    let uints = verifying_key_to_uints(&params.vk);
+    assert_eq!(uints.len(), 14 + 2 * (header.num_inputs() + 1));
+
+    // alpha_g1 coordinates decode back to the same point
+    let mut encoded = G1Uncompressed::empty();
+    let bytes = hex::decode(format!("{}{}", &uints[0][2..], &uints[1][2..])).unwrap();
+    encoded.as_mut().copy_from_slice(&bytes);
+    assert!(encoded.into_affine().unwrap() == params.vk.alpha_g1);
+
+    let contract = generate_verifier_contract(&header, &params.vk);
+    assert_eq!(verifier_contract_name(&header), "OnchainWithdrawalVerifierB1D2T1");
+    assert!(contract.contains("contract OnchainWithdrawalVerifierB1D2T1"));
+    assert!(contract.contains("uint constant public NUM_INPUTS = 7;"));
+    assert!(contract.contains("vkGammaAbc = new uint[](16);"));
+    assert!(uints.iter().all(|value| contract.contains(value.as_str())));
+
+    let json = verifying_key_to_json(&header, &params.vk);
+    assert!(json.contains("\"circuit\": \"onchain_withdrawal\""));
+    assert!(json.contains("\"num_inputs\": 7"));
+    assert!(uints.iter().all(|value| json.contains(value.as_str())));
+}
+
+#[test]
+pub fn transfer_witness_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let mut rng = thread_rng();
+
+    let seckey_maker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_maker = PublicKey::from_private(
+        &seckey_maker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let seckey_taker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_taker = PublicKey::from_private(
+        &seckey_taker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let mut tree = AccountsTree::new(2, 1, &hash_params, &sign_params);
+    tree.update_account(0, pubkey_maker.clone(), usize_to_fr(0)).unwrap();
+    tree.update_balance(0, 0, 100).unwrap();
+    tree.update_account(1, pubkey_taker.clone(), usize_to_fr(3)).unwrap();
+
+    let mut transfer = Transfer {
+        account_id_from: 0,
+        account_id_to: 1,
+        pubkey_to: None,
+        token_id: 0,
+        amount: 10,
+        fee: 0,
+        nonce: 1,
+        sign: None,
+    };
+    transfer.sign(&seckey_maker, &hash_params, &sign_params);
+
+    let old_root = tree.get_root();
+    let (account_state_from, account_state_to) = transfer.update_tree_and_record_state(&mut tree).unwrap();
+
+    let circuit = TransferCircuit::<Bn256> {
+        account_state_from,
+        account_state_to,
+        account_id_from: Some(usize_to_fr(0)),
+        account_id_to: Some(usize_to_fr(1)),
+        pubkey_to: Some(pubkey_taker.0.clone()),
+        by_pubkey: Some(false),
+        new_account: Some(false),
+        token_id: Some(usize_to_fr(0)),
+        amount: Some(usize_to_fr(10)),
+        fee: Some(usize_to_fr(0)),
+        nonce: Some(usize_to_fr(1)),
+        sign: transfer.sign.clone(),
+        pubkey: Some(pubkey_maker.0.clone()),
+    };
+
+    assert!(synthesize_transfer(2, old_root, &hash_params, &sign_params, &circuit).is_satisfied());
+
+    // operator bumps the recipient nonce
+    let mut tampered = circuit.clone();
+    tampered.account_state_to.new_nonce = Some(usize_to_fr(4));
+    assert_eq!(
+        synthesize_transfer(2, old_root, &hash_params, &sign_params, &tampered).which_is_unsatisfied(),
+        Some("transfer/check to nonce the same"),
+    );
+
+    // operator rewrites the recipient pubkey
+    let mut tampered = circuit.clone();
+    tampered.account_state_to.new_pubkey = Some(pubkey_maker.0.clone());
+    assert!(!synthesize_transfer(2, old_root, &hash_params, &sign_params, &tampered).is_satisfied());
+}
+
+#[test]
+pub fn tampered_witness_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let mut rng = thread_rng();
+
+    let seckey_maker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_maker = PublicKey::from_private(
+        &seckey_maker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let mut tree = AccountsTree::new(2, 1, &hash_params, &sign_params);
+    tree.update_account(0, pubkey_maker.clone(), usize_to_fr(0)).unwrap();
+    tree.update_balance(0, 0, 100).unwrap();
+
+    // deposit amount wrapping around the field can't burn a balance
+
+    let deposit = Deposit {
+        pubkey: Some(pubkey_maker.clone()),
+        account_id: 0,
+        token_id: 0,
+        amount: 1,
+    };
+
+    let old_root = tree.get_root();
+    let mut account_state = deposit.update_tree_and_record_state(&mut tree).unwrap();
+    account_state.new_balance = Some(usize_to_fr(0));
+
+    let mut wrapped_amount = usize_to_fr(0);
+    wrapped_amount.sub_assign(&account_state.old_balance.unwrap());
+
+    let circuit = DepositCircuit::<Bn256> {
+        account_state,
+        pubkey: Some(pubkey_maker.0.clone()),
+        account_id: Some(usize_to_fr(0)),
+        token_id: Some(usize_to_fr(0)),
+        amount: Some(wrapped_amount),
+        new_account: Some(false),
+        padding: Some(false),
+    };
+
+    let cs = synthesize_deposit(2, old_root, &hash_params, &sign_params, &circuit);
+    assert_eq!(
+        cs.which_is_unsatisfied(),
+        Some("deposit/check amount range/repack top bits"),
+    );
+}
+
+#[test]
+pub fn deposit_owner_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let dep_params = setup_deposit_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let transfer_params = setup_transfer_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let of_w_params = setup_offchain_withdraw_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let on_w_params = setup_onchain_withdraw_circuit(1, 2, 1, &hash_params, false).unwrap();
+
+    let mut oper = Operator::new(2, 1, 1, 1, 1, 1, &hash_params, &sign_params,
+        &dep_params, &transfer_params, &of_w_params, &on_w_params);
+
+    let mut rng = thread_rng();
+
+    let seckey_maker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_maker = PublicKey::from_private(
+        &seckey_maker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let seckey_taker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_taker = PublicKey::from_private(
+        &seckey_taker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let deposit_maker = Deposit {
+        pubkey: Some(pubkey_maker.clone()),
+        account_id: 0,
+        token_id: 0,
+        amount: 100,
+    };
+    oper.add_deposit(deposit_maker.clone()).unwrap();
+
+    // deposit can't take over an account owned by another pubkey, admitted
+    // or batched
+
+    let stealing_deposit = Deposit {
+        pubkey: Some(pubkey_taker.clone()),
+        account_id: 0,
+        token_id: 0,
+        amount: 1,
+    };
+    assert!(matches!(
+        oper.add_deposit(stealing_deposit.clone()),
+        Err(OperatorError::AccountAlreadyOwned { account_id: 0 })
+    ));
+
+    oper.execute_deposit_batch().unwrap();
+    assert!(matches!(
+        oper.add_deposit(stealing_deposit.clone()),
+        Err(OperatorError::AccountAlreadyOwned { account_id: 0 })
+    ));
+
+    // owner can top up its account
+    oper.add_deposit(deposit_maker).unwrap();
+
+    // deposit without a pubkey is refused instead of panicking
+
+    let keyless_deposit = Deposit {
+        pubkey: None,
+        account_id: 1,
+        token_id: 0,
+        amount: 1,
+    };
+    assert!(matches!(
+        oper.add_deposit(keyless_deposit.clone()),
+        Err(OperatorError::InvalidRequest { account_id: 1 })
+    ));
+
+    let mut tree = oper.tree.clone();
+    let root = tree.get_root();
+    assert!(matches!(
+        keyless_deposit.update_tree_and_record_state(&mut tree),
+        Err(TreeError::InvalidRequest { account_id: 1 })
+    ));
+    assert_eq!(tree.get_root(), root);
+
+    // circuit can't take over an occupied leaf with another pubkey either
+
+    for new_account in [true, false] {
+        let mut tree = oper.tree.clone();
+        let old_root = tree.get_root();
+        let account_state = stealing_deposit.update_tree_and_record_state(&mut tree).unwrap();
+
+        let circuit = DepositCircuit::<Bn256> {
+            account_state,
+            pubkey: Some(pubkey_taker.0.clone()),
+            account_id: Some(usize_to_fr(0)),
+            token_id: Some(usize_to_fr(0)),
+            amount: Some(usize_to_fr(1)),
+            new_account: Some(new_account),
+            padding: Some(false),
+        };
+
+        let cs = synthesize_deposit(2, old_root, &hash_params, &sign_params, &circuit);
+        assert!(!cs.is_satisfied());
+    }
+}
+
+#[test]
+pub fn self_transfer_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let mut rng = thread_rng();
+
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let mut tree = AccountsTree::new(2, 1, &hash_params, &sign_params);
+    tree.update_account(0, pubkey.clone(), usize_to_fr(0)).unwrap();
+    tree.update_balance(0, 0, 100).unwrap();
+
+    let mut transfer = Transfer {
+        account_id_from: 0,
+        account_id_to: 0,
+        pubkey_to: None,
+        token_id: 0,
+        amount: 10,
+        fee: 1,
+        nonce: 1,
+        sign: None,
+    };
+    transfer.sign(&seckey, &hash_params, &sign_params);
+
+    // to state opens the leaf as left by the from update, self-transfer only
+    // pays the fee and bumps the nonce
+
+    let old_root = tree.get_root();
+    let stale_state = transfer.update_tree_and_record_state(&mut tree.clone()).unwrap().0;
+    let (account_state_from, account_state_to) = transfer.update_tree_and_record_state(&mut tree).unwrap();
+
+    assert_eq!(tree.get_balance(0, 0).unwrap(), 99);
+    assert_eq!(fr_to_usize(tree.get_nonce(0).unwrap()), Some(1));
+    assert_eq!(account_state_to.old_balance, account_state_from.new_balance);
+    assert_eq!(account_state_to.old_nonce, account_state_from.new_nonce);
+    assert_eq!(account_state_to.new_nonce, account_state_from.new_nonce);
+
+    let circuit = TransferCircuit::<Bn256> {
+        account_state_from,
+        account_state_to,
+        account_id_from: Some(usize_to_fr(0)),
+        account_id_to: Some(usize_to_fr(0)),
+        pubkey_to: Some(pubkey.0.clone()),
+        by_pubkey: Some(false),
+        new_account: Some(false),
+        token_id: Some(usize_to_fr(0)),
+        amount: Some(usize_to_fr(10)),
+        fee: Some(usize_to_fr(1)),
+        nonce: Some(usize_to_fr(1)),
+        sign: transfer.sign.clone(),
+        pubkey: Some(pubkey.0.clone()),
+    };
+
+    assert!(synthesize_transfer(2, old_root, &hash_params, &sign_params, &circuit).is_satisfied());
+
+    // to state opening the leaf as it was before the from update mints the amount
+    let mut tampered = circuit.clone();
+    tampered.account_state_to = stale_state;
+    tampered.account_state_to.new_balance = Some(usize_to_fr(110));
+    tampered.account_state_to.new_nonce = tampered.account_state_to.old_nonce;
+    assert!(!synthesize_transfer(2, old_root, &hash_params, &sign_params, &tampered).is_satisfied());
+}
+
+#[test]
+pub fn self_transfer_batch_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let dep_params = setup_deposit_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let transfer_params = setup_transfer_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let of_w_params = setup_offchain_withdraw_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let on_w_params = setup_onchain_withdraw_circuit(1, 2, 1, &hash_params, false).unwrap();
+
+    let mut oper = Operator::new(2, 1, 1, 1, 1, 1, &hash_params, &sign_params,
+        &dep_params, &transfer_params, &of_w_params, &on_w_params);
+    oper.set_fee_account(2, 0).unwrap();
+
+    let mut rng = thread_rng();
+
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey.clone()),
+        account_id: 0,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+    oper.execute_deposit_batch().unwrap();
+
+    // admitted self-transfer only pays the fee and bumps the nonce
+
+    let mut transfer = Transfer {
+        account_id_from: 0,
+        account_id_to: 0,
+        pubkey_to: None,
+        token_id: 0,
+        amount: 10,
+        fee: 1,
+        nonce: 1,
+        sign: None,
+    };
+    transfer.sign(&seckey, &hash_params, &sign_params);
+    oper.add_transfer(transfer).unwrap();
+
+    assert_eq!(oper.get_pending_balance(0, 0).unwrap(), 99);
+    assert_eq!(oper.get_next_nonce(0).unwrap(), 2);
+
+    let (public_inputs, proof) = oper.execute_transfer_batch().unwrap();
+    oper.verify_transfer_batch(&public_inputs, &proof).unwrap();
+
+    assert_eq!(oper.tree.get_balance(0, 0).unwrap(), 99);
+    assert_eq!(oper.tree.get_balance(2, 0).unwrap(), 1);
+    assert_eq!(fr_to_usize(oper.tree.get_nonce(0).unwrap()), Some(1));
+}
+
+#[test]
+pub fn amount_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let mut rng = thread_rng();
+
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    // 18 decimals amounts don't fit 64 bits
+
+    let million_tokens: u128 = 1_000_000 * 10u128.pow(18);
+    assert_eq!(fr_to_u128(u128_to_fr(million_tokens)), Some(million_tokens));
+    assert_eq!(fr_to_u128(u128_to_fr(u128::MAX)), Some(u128::MAX));
+
+    let mut wide = u128_to_fr(u128::MAX);
+    wide.add_assign(&usize_to_fr(1));
+    assert_eq!(fr_to_u128(wide), None);
+
+    let mut tree = AccountsTree::new(2, 1, &hash_params, &sign_params);
+
+    let mut deposit = Deposit {
+        pubkey: Some(pubkey.clone()),
+        account_id: 0,
+        token_id: 0,
+        amount: million_tokens,
+    };
+    let account_state = deposit.update_tree_and_record_state(&mut tree).unwrap();
+    assert_eq!(account_state.new_balance, Some(u128_to_fr(million_tokens)));
+    assert_eq!(tree.get_balance(0, 0).unwrap(), million_tokens);
+
+    // checked arithmetic leaves the tree untouched
+
+    let root = tree.get_root();
+
+    deposit.amount = u128::MAX;
+    assert!(matches!(
+        deposit.update_tree_and_record_state(&mut tree),
+        Err(TreeError::AmountOverflow { account_id: 0, balance, amount: u128::MAX }) if balance == million_tokens
+    ));
+
+    let mut transfer = Transfer {
+        account_id_from: 0,
+        account_id_to: 1,
+        pubkey_to: None,
+        token_id: 0,
+        amount: million_tokens,
+        fee: 1,
+        nonce: 1,
+        sign: None,
+    };
+    assert!(matches!(
+        transfer.update_tree_and_record_state(&mut tree),
+        Err(TreeError::InsufficientBalance { account_id: 0, .. })
+    ));
+
+    transfer.fee = 0;
+    transfer.nonce = 0;
+    assert!(matches!(
+        transfer.update_tree_and_record_state(&mut tree),
+        Err(TreeError::NonceMismatch { account_id: 0, expected: 1, actual: 0 })
+    ));
+    assert_eq!(tree.get_root(), root);
+
+    transfer.nonce = 1;
+    transfer.update_tree_and_record_state(&mut tree).unwrap();
+    assert_eq!(tree.get_balance(0, 0).unwrap(), 0);
+    assert_eq!(tree.get_balance(1, 0).unwrap(), million_tokens);
+
+    // a nonce wider than a usize is an error, not a panic
+
+    assert_eq!(fr_to_usize(u128_to_fr(u128::MAX)), None);
+
+    tree.update_nonce(1, u128_to_fr(u128::MAX)).unwrap();
+    assert!(matches!(tree.get_nonce_value(1), Err(TreeError::NonceOverflow { account_id: 1 })));
+
+    let transfer = Transfer {
+        account_id_from: 1,
+        account_id_to: 0,
+        pubkey_to: None,
+        token_id: 0,
+        amount: 1,
+        fee: 0,
+        nonce: 1,
+        sign: None,
+    };
+    let root = tree.get_root();
+    assert!(matches!(
+        transfer.update_tree_and_record_state(&mut tree),
+        Err(TreeError::NonceOverflow { account_id: 1 })
+    ));
+    assert_eq!(tree.get_root(), root);
+
+    // accounts out of tree range are an error, not a panic
+
+    let num_accounts = tree.num_accounts();
+    let transfer = Transfer {
+        account_id_from: 0,
+        account_id_to: num_accounts,
+        pubkey_to: None,
+        token_id: 0,
+        amount: 1,
+        fee: 0,
+        nonce: 1,
+        sign: None,
+    };
+    assert!(matches!(
+        transfer.update_tree_and_record_state(&mut tree),
+        Err(TreeError::UnknownAccount { account_id }) if account_id == num_accounts
+    ));
+
+    let withdrawal = OnchainWithdrawal {
+        account_id: num_accounts,
+        token_id: 0,
+        amount: None,
+    };
+    assert!(matches!(
+        withdrawal.update_tree_and_record_state(&mut tree),
+        Err(TreeError::UnknownAccount { account_id }) if account_id == num_accounts
+    ));
+    assert_eq!(tree.get_root(), root);
+
+    // so are tokens
+
+    let num_tokens = tree.num_tokens();
+    assert!(matches!(tree.get_account(num_accounts), Err(TreeError::UnknownAccount { .. })));
+    assert!(matches!(tree.get_balance(0, num_tokens), Err(TreeError::UnknownToken { .. })));
+    assert!(matches!(tree.update_balance(0, num_tokens, 1), Err(TreeError::UnknownToken { .. })));
+
+    let deposit = Deposit {
+        pubkey: Some(pubkey.clone()),
+        account_id: 0,
+        token_id: num_tokens,
+        amount: 1,
+    };
+    assert!(matches!(
+        deposit.update_tree_and_record_state(&mut tree),
+        Err(TreeError::UnknownToken { token_id }) if token_id == num_tokens
+    ));
+
+    let withdrawal = OffchainWithdrawal {
+        account_id: 0,
+        token_id: num_tokens,
+        amount: 1,
+        fee: 0,
+        nonce: 1,
+        sign: None,
+    };
+    assert!(matches!(
+        withdrawal.update_tree_and_record_state(&mut tree),
+        Err(TreeError::UnknownToken { token_id }) if token_id == num_tokens
+    ));
+
+    let withdrawal = OnchainWithdrawal {
+        account_id: 0,
+        token_id: num_tokens,
+        amount: None,
+    };
+    assert!(matches!(
+        withdrawal.update_tree_and_record_state(&mut tree),
+        Err(TreeError::UnknownToken { token_id }) if token_id == num_tokens
+    ));
+    assert_eq!(tree.get_root(), root);
+}
+
+#[test]
+pub fn sparse_tree_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let mut rng = thread_rng();
+
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    // realistic depth only stores touched accounts and nodes
+
+    let account_depth = 32;
+    let last_account = (1 << account_depth) - 1;
+
+    let mut tree = AccountsTree::new(account_depth, 1, &hash_params, &sign_params);
+    let empty_root = tree.get_root();
+
+    tree.update_account(5, pubkey.clone(), usize_to_fr(0)).unwrap();
+    tree.update_balance(5, 0, 100).unwrap();
+    tree.update_balance(last_account, 1, 7).unwrap();
+
+    assert_eq!(tree.accounts.len(), 2);
+    assert_eq!(tree.accounts_tree.num_nodes(), 2 * (account_depth + 1) - 1);
+    assert_eq!(tree.get_balance(6, 0).unwrap(), 0);
+    assert_eq!(tree.get_balance(last_account, 1).unwrap(), 7);
+
+    // paths of stored and empty leaves lead to the root
+
+    for account_id in [5, 6, last_account].iter() {
+        let path = tree.accounts_tree.get_leaf_path(*account_id);
+        let indices = tree.accounts_tree.get_leaf_indices(*account_id);
+        assert_eq!(path.len(), account_depth);
+
+        let leaf = tree.get_account(*account_id).unwrap().compress_to_leaf();
+        let mut node = poseidon_hash::<Bn256>(&hash_params, &leaf)[0];
+        for (neighbor, is_right) in path.iter().zip(indices.iter()) {
+            let pair = if *is_right { [*neighbor, node] } else { [node, *neighbor] };
+            node = poseidon_hash::<Bn256>(&hash_params, &pair)[0];
+        }
+        assert_eq!(node, tree.get_root());
+    }
+
+    // resetting a leaf drops its nodes and restores the empty root
+
+    let mut reset_tree = tree.clone();
+    reset_tree.update_balance(last_account, 1, 0).unwrap();
+    reset_tree.update_balance(5, 0, 0).unwrap();
+    reset_tree.update_account(5, tree.empty_account.pubkey.clone(), usize_to_fr(0)).unwrap();
+    assert_eq!(reset_tree.get_root(), empty_root);
+    assert_eq!(reset_tree.accounts_tree.num_nodes(), 0);
+
+    // circuits work on the sparse tree witnesses
+
+    let mut transfer = Transfer {
+        account_id_from: 5,
+        account_id_to: last_account,
+        pubkey_to: None,
+        token_id: 0,
+        amount: 10,
+        fee: 0,
+        nonce: 1,
+        sign: None,
+    };
+    transfer.sign(&seckey, &hash_params, &sign_params);
+
+    let old_root = tree.get_root();
+    let (account_state_from, account_state_to) = transfer.update_tree_and_record_state(&mut tree).unwrap();
+
+    let circuit = TransferCircuit::<Bn256> {
+        account_state_from,
+        account_state_to,
+        account_id_from: Some(usize_to_fr(5)),
+        account_id_to: Some(usize_to_fr(last_account)),
+        pubkey_to: Some(tree.get_pubkey(last_account).unwrap().0),
+        by_pubkey: Some(false),
+        new_account: Some(false),
+        token_id: Some(usize_to_fr(0)),
+        amount: Some(usize_to_fr(10)),
+        fee: Some(usize_to_fr(0)),
+        nonce: Some(usize_to_fr(1)),
+        sign: transfer.sign.clone(),
+        pubkey: Some(pubkey.0.clone()),
+    };
+
+    let cs = synthesize_transfer(account_depth, old_root, &hash_params, &sign_params, &circuit);
+    assert_eq!(cs.which_is_unsatisfied(), None);
+}
+
+#[test]
+pub fn sparse_operator_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let account_depth = 32;
+    let last_account = (1 << account_depth) - 2;
+
+    let dep_params = setup_deposit_circuit(1, account_depth, 1, &hash_params, &sign_params, false).unwrap();
+    let transfer_params = setup_transfer_circuit(1, account_depth, 1, &hash_params, &sign_params, false).unwrap();
+    let of_w_params = setup_offchain_withdraw_circuit(1, account_depth, 1, &hash_params, &sign_params, false).unwrap();
+    let on_w_params = setup_onchain_withdraw_circuit(1, account_depth, 1, &hash_params, false).unwrap();
+
+    let mut oper = Operator::new(account_depth, 1, 1, 1, 1, 1, &hash_params, &sign_params,
+        &dep_params, &transfer_params, &of_w_params, &on_w_params);
+
+    let mut rng = thread_rng();
+
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    // operator proves batches on a realistic depth, only touched accounts are stored
+
+    assert_eq!(oper.padding_account_id, last_account + 1);
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey.clone()),
+        account_id: 5,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+    let (public_inputs, proof) = oper.execute_deposit_batch().unwrap();
+    oper.verify_deposit_batch(&public_inputs, &proof).unwrap();
+
+    let mut transfer = Transfer {
+        account_id_from: 5,
+        account_id_to: last_account,
+        pubkey_to: None,
+        token_id: 0,
+        amount: 10,
+        fee: 0,
+        nonce: 1,
+        sign: None,
+    };
+    transfer.sign(&seckey, &hash_params, &sign_params);
+    oper.add_transfer(transfer).unwrap();
+
+    let (public_inputs, proof) = oper.execute_transfer_batch().unwrap();
+    oper.verify_transfer_batch(&public_inputs, &proof).unwrap();
+
+    assert_eq!(oper.tree.get_balance(5, 0).unwrap(), 90);
+    assert_eq!(oper.tree.get_balance(last_account, 0).unwrap(), 10);
+    // sender, recipient and the padding account
+    assert_eq!(oper.tree.accounts.len(), 3);
+}
+
+#[test]
+pub fn storage_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let mut rng = thread_rng();
+
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let account_depth = 16;
+    let balance_depth = 2;
+
+    let mut tree = AccountsTree::new(account_depth, balance_depth, &hash_params, &sign_params);
+    tree.update_account(3, pubkey.clone(), usize_to_fr(2)).unwrap();
+    tree.update_balance(3, 0, 100).unwrap();
+    tree.update_balance(3, 3, u128::MAX).unwrap();
+    tree.update_balance(70, 1, 5).unwrap();
+
+    let deposit_accum_hash: bn256::Fr = rng.gen();
+    let withdrawal_accum_hash: bn256::Fr = rng.gen();
+
+    // state round-trips through a file
+
+    let path = std::env::temp_dir().join(format!("{}-operator.state", std::process::id()));
+    save_state(&path, &tree, &deposit_accum_hash, &withdrawal_accum_hash, 9, &BlockHistory::new()).unwrap();
+
+    let state = load_state(&path, account_depth, balance_depth, &hash_params, &sign_params).unwrap();
+    assert_eq!(state.tree.get_root(), tree.get_root());
+    assert_eq!(state.tree.accounts.len(), 2);
+    assert_eq!(state.tree.accounts_tree.num_nodes(), tree.accounts_tree.num_nodes());
+    assert!(state.tree.get_pubkey(3).unwrap().0 == pubkey.0);
+    assert_eq!(state.tree.get_nonce(3).unwrap(), usize_to_fr(2));
+    assert_eq!(state.tree.get_balance(3, 3).unwrap(), u128::MAX);
+    assert_eq!(state.tree.get_balance(70, 1).unwrap(), 5);
+    assert_eq!(state.deposit_accum_hash, deposit_accum_hash);
+    assert_eq!(state.withdrawal_accum_hash, withdrawal_accum_hash);
+    assert_eq!(state.batch_number, 9);
+
+    assert!(matches!(
+        load_state(&path, account_depth + 1, balance_depth, &hash_params, &sign_params),
+        Err(StorageError::DepthMismatch { .. })
+    ));
+
+    std::fs::remove_file(&path).unwrap();
+
+    // damaged state is refused
+
+    let mut data = Vec::new();
+    write_state(&mut data, &tree, &deposit_accum_hash, &withdrawal_accum_hash, 9, &BlockHistory::new()).unwrap();
+
+    // last balance byte, the empty history count follows it
+    let mut tampered = data.clone();
+    let balances_end = tampered.len() - 8;
+    tampered[balances_end - 1] ^= 1;
+    assert!(matches!(
+        read_state(&tampered[..], account_depth, balance_depth, &hash_params, &sign_params),
+        Err(StorageError::RootMismatch { .. })
+    ));
+
+    let mut bad_magic = data.clone();
+    bad_magic[0] ^= 1;
+    assert!(matches!(
+        read_state(&bad_magic[..], account_depth, balance_depth, &hash_params, &sign_params),
+        Err(StorageError::InvalidHeader)
+    ));
+
+    // files without a history are an older version
+    let mut old_version = data.clone();
+    old_version[4] = 1;
+    assert!(matches!(
+        read_state(&old_version[..], account_depth, balance_depth, &hash_params, &sign_params),
+        Err(StorageError::InvalidHeader)
+    ));
+
+    assert!(matches!(
+        read_state(&data[..data.len() - 1], account_depth, balance_depth, &hash_params, &sign_params),
+        Err(StorageError::IoError(_))
+    ));
+}
+
+#[test]
+pub fn account_proof_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let mut rng = thread_rng();
+
+    let pubkey = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let mut tree = AccountsTree::new(16, 2, &hash_params, &sign_params);
+    tree.update_account(5, pubkey.clone(), usize_to_fr(1)).unwrap();
+    tree.update_balance(5, 2, 1000).unwrap();
+    tree.update_balance(6, 0, 7).unwrap();
+
+    // proof of a stored balance
+
+    let proof = tree.get_account_proof(5, 2).unwrap();
+    let root = tree.get_root();
+    assert!(proof.root == root);
+    assert!(proof.pubkey.0 == pubkey.0);
+    assert_eq!(proof.balance, 1000);
+    assert!(verify_account_proof(&proof, &root, 16, 2, &hash_params));
+
+    // untouched balances and accounts are proven as zero
+
+    assert!(verify_account_proof(&tree.get_account_proof(5, 1).unwrap(), &root, 16, 2, &hash_params));
+    let empty_proof = tree.get_account_proof(1000, 3).unwrap();
+    assert_eq!(empty_proof.balance, 0);
+    assert!(verify_account_proof(&empty_proof, &root, 16, 2, &hash_params));
+
+    // tampered proofs are refused
+
+    let mut bad_proof = proof.clone();
+    bad_proof.balance = 1001;
+    assert!(!verify_account_proof(&bad_proof, &root, 16, 2, &hash_params));
+
+    let mut bad_proof = proof.clone();
+    bad_proof.nonce = usize_to_fr(2);
+    assert!(!verify_account_proof(&bad_proof, &root, 16, 2, &hash_params));
+
+    let mut bad_proof = proof.clone();
+    bad_proof.account_id = 4;
+    assert!(!verify_account_proof(&bad_proof, &root, 16, 2, &hash_params));
+
+    let mut bad_proof = proof.clone();
+    bad_proof.token_id = 3;
+    assert!(!verify_account_proof(&bad_proof, &root, 16, 2, &hash_params));
+
+    let mut bad_proof = proof.clone();
+    bad_proof.account_path.pop();
+    assert!(!verify_account_proof(&bad_proof, &root, 16, 2, &hash_params));
+
+    // path lengths must match the tree depths
+
+    let mut bad_proof = proof.clone();
+    bad_proof.account_path.pop();
+    bad_proof.account_indices.pop();
+    assert!(!verify_account_proof(&bad_proof, &root, 16, 2, &hash_params));
+    assert!(!verify_account_proof(&proof, &root, 15, 2, &hash_params));
+    assert!(!verify_account_proof(&proof, &root, 16, 3, &hash_params));
+
+    // proof stays valid for the root it was taken at
+
+    tree.update_balance(5, 2, 999).unwrap();
+    assert!(verify_account_proof(&proof, &root, 16, 2, &hash_params));
+    assert!(!verify_account_proof(&proof, &tree.get_root(), 16, 2, &hash_params));
+    assert!(verify_account_proof(&tree.get_account_proof(5, 2).unwrap(), &tree.get_root(), 16, 2, &hash_params));
+}
+
+#[test]
+pub fn exit_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let exit_params = setup_exit_circuit(2, 1, &hash_params, &sign_params, false).unwrap();
+    let header = KeyHeader::new(CircuitType::Exit, 1, 2, 1, false);
+    assert_eq!(exit_params.vk.ic.len(), header.num_inputs() + 1);
+
+    let mut rng = thread_rng();
+
+    let seckey = PrivateKey::<Bn256>(rng.gen());
+    let pubkey = PublicKey::from_private(
+        &seckey,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let mut tree = AccountsTree::new(2, 1, &hash_params, &sign_params);
+    tree.update_account(1, pubkey.clone(), usize_to_fr(3)).unwrap();
+    tree.update_balance(1, 1, 500).unwrap();
+    tree.update_balance(2, 1, 20).unwrap();
+
+    let recipient = u128_to_fr(0x00c0_ffee_0000_0000_0000_0000_0000_beef);
+
+    let mut exit = Exit {
+        account_id: 1,
+        token_id: 1,
+        amount: 500,
+        recipient,
+        root: tree.get_root(),
+        sign: None,
+    };
+    exit.sign(&seckey, &hash_params, &sign_params);
+
+    // witness satisfies the circuit
+
+    let circuit = ExitCircuit::from_tree(&tree, &exit, &hash_params, &sign_params, false).unwrap();
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    circuit.clone().synthesize(&mut cs).unwrap();
+    assert_eq!(cs.find_unconstrained(), "");
+    assert_eq!(cs.which_is_unsatisfied(), None);
+
+    // claimed amount must be the balance under the root
+
+    let mut tampered = circuit.clone();
+    tampered.amount = Some(u128_to_fr(501));
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    tampered.synthesize(&mut cs).unwrap();
+    assert!(cs.which_is_unsatisfied().is_some());
+
+    let mut tampered = circuit.clone();
+    tampered.root = Some(bn256::Fr::one());
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    tampered.synthesize(&mut cs).unwrap();
+    assert!(cs.which_is_unsatisfied().is_some());
+
+    // recipient is signed by the owner
+
+    let mut tampered = circuit.clone();
+    tampered.recipient = Some(usize_to_fr(0xdead));
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    tampered.synthesize(&mut cs).unwrap();
+    assert!(cs.which_is_unsatisfied().is_some());
+
+    // helpers refuse exits the circuit can't prove
+
+    let mut bad_exit = exit.clone();
+    bad_exit.amount = 400;
+    bad_exit.sign(&seckey, &hash_params, &sign_params);
+    assert!(matches!(
+        ExitCircuit::from_tree(&tree, &bad_exit, &hash_params, &sign_params, false),
+        Err(OperatorError::BalanceMismatch { account_id: 1, balance: 500, amount: 400 })
+    ));
+
+    let mut bad_exit = exit.clone();
+    bad_exit.account_id = 2;
+    bad_exit.amount = 20;
+    bad_exit.sign(&seckey, &hash_params, &sign_params);
+    assert!(matches!(
+        prove_exit(&tree, &bad_exit, &exit_params, &hash_params, &sign_params, false),
+        Err(OperatorError::InvalidSignature { account_id: 2 })
+    ));
+
+    // proof from a saved state
+
+    let path = std::env::temp_dir().join(format!("{}-exit.state", std::process::id()));
+    save_state(&path, &tree, &bn256::Fr::zero(), &bn256::Fr::zero(), 1, &BlockHistory::new()).unwrap();
+
+    let root = tree.get_root();
+    tree.update_balance(1, 1, 0).unwrap();
+
+    let (public_inputs, proof) = prove_exit_from_state(
+        &path, 2, 1, &exit, &exit_params, &hash_params, &sign_params, false,
+    ).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(public_inputs, vec![
+        root,
+        usize_to_fr(1),
+        usize_to_fr(1),
+        u128_to_fr(500),
+        recipient,
+        Exit::pubkey_hash(&pubkey, &hash_params),
+    ]);
+    let verifying_key = prepare_verifying_key(&exit_params.vk);
+    assert!(verify_proof(&verifying_key, &proof, &public_inputs).unwrap());
+
+    // proof doesn't pay out to another recipient
+    let mut wrong_recipient = public_inputs.clone();
+    wrong_recipient[4] = usize_to_fr(0xdead);
+    assert!(!verify_proof(&verifying_key, &proof, &wrong_recipient).unwrap());
+
+    // exit is signed for its root, spent balance can't be exited against the current one
+    assert!(matches!(
+        prove_exit(&tree, &exit, &exit_params, &hash_params, &sign_params, false),
+        Err(OperatorError::RootMismatch { .. })
+    ));
+
+    exit.root = tree.get_root();
+    exit.sign(&seckey, &hash_params, &sign_params);
+    assert!(matches!(
+        prove_exit(&tree, &exit, &exit_params, &hash_params, &sign_params, false),
+        Err(OperatorError::BalanceMismatch { .. })
+    ));
+}
+
+#[test]
+pub fn log_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let dep_params = setup_deposit_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let transfer_params = setup_transfer_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let of_w_params = setup_offchain_withdraw_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let on_w_params = setup_onchain_withdraw_circuit(1, 2, 1, &hash_params, false).unwrap();
+
+    let new_operator = || Operator::new(2, 1, 1, 1, 1, 1, &hash_params, &sign_params,
+        &dep_params, &transfer_params, &of_w_params, &on_w_params);
+
+    let path = std::env::temp_dir().join(format!("{}-operator.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let mut oper = new_operator();
+    oper.set_log(&path);
+
+    let mut rng = thread_rng();
+
+    let seckey_maker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_maker = PublicKey::from_private(
+        &seckey_maker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+    let pubkey_taker = PublicKey::from_private(
+        &PrivateKey::<Bn256>(rng.gen()),
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    // transfer relying on a queued deposit is evicted, the eviction is logged
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_maker.clone()),
+        account_id: 0,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+
+    let mut transfer = Transfer {
+        account_id_from: 0,
+        account_id_to: 1,
+        pubkey_to: None,
+        token_id: 0,
+        amount: 10,
+        fee: 0,
+        nonce: 1,
+        sign: None,
+    };
+    transfer.sign(&seckey_maker, &hash_params, &sign_params);
+    oper.add_transfer(transfer.clone()).unwrap();
+    assert!(matches!(oper.execute_transfer_batch(), Err(OperatorError::RequestEvicted { .. })));
+
+    oper.execute_deposit_batch().unwrap();
+
+    // transfer by pubkey creates the recipient account
+
+    transfer.account_id_to = 0;
+    transfer.pubkey_to = Some(pubkey_taker.clone());
+    transfer.sign(&seckey_maker, &hash_params, &sign_params);
+    oper.add_transfer(transfer).unwrap();
+    oper.execute_transfer_batch().unwrap();
+
+    // requests left in queues are restored too
+
+    oper.add_onchain_withdrawal(OnchainWithdrawal {
+        account_id: 0,
+        token_id: 0,
+        amount: None,
+    }).unwrap();
+
+    // replay rebuilds tree, accumulators and queues without extending the log
+
+    let log_len = std::fs::metadata(&path).unwrap().len();
+
+    let mut restored = new_operator();
+    restored.set_log(&path);
+    assert_eq!(restored.replay_log(&path).unwrap(), 7);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), log_len);
+
+    assert_eq!(restored.tree.get_root(), oper.tree.get_root());
+    assert_eq!(restored.deposit_accum_hash, oper.deposit_accum_hash);
+    assert_eq!(restored.batch_number, 2);
+    assert_eq!(restored.tree.get_balance(0, 0).unwrap(), 90);
+    assert_eq!(restored.tree.get_balance(1, 0).unwrap(), 10);
+    assert_eq!(restored.transfer_queue.len(), 0);
+    assert_eq!(restored.onchain_withdrawal_queue.len(), 1);
+    assert_eq!(restored.get_pending_balance(0, 0).unwrap(), 0);
+
+    // replay rebuilds the block history
+    assert_eq!(restored.history.len(), 2);
+
+    // restored operator keeps logging
+
+    restored.execute_onchain_withdrawal_batch().unwrap();
+    let mut replayed = new_operator();
+    assert_eq!(replayed.replay_log(&path).unwrap(), 8);
+    assert_eq!(replayed.tree.get_root(), restored.tree.get_root());
+    assert_eq!(replayed.withdrawal_accum_hash, restored.withdrawal_accum_hash);
+    assert_eq!(replayed.history.len(), 3);
+
+    // torn record at the end is dropped and cut off
+
+    let log_len = std::fs::metadata(&path).unwrap().len();
+    {
+        use std::io::Write;
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 40, 1, 2, 3]).unwrap();
+    }
+    assert_eq!(new_operator().replay_log(&path).unwrap(), 8);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), log_len);
+
+    // damaged record followed by other records is refused
+
+    let mut data = std::fs::read(&path).unwrap();
+    data[10] ^= 1;
+    assert!(matches!(read_log(&data[..], &sign_params), Err(StorageError::CorruptedLog { offset: 5 })));
+
+    // corrupted length pointing past the end is not mistaken for a torn tail
+
+    data[10] ^= 1;
+    data[5] = 0x7f;
+    assert!(matches!(read_log(&data[..], &sign_params), Err(StorageError::CorruptedLog { offset: 5 })));
+
+    std::fs::write(&path, &data).unwrap();
+    assert!(matches!(
+        new_operator().replay_log(&path),
+        Err(OperatorError::StorageFailed(StorageError::CorruptedLog { offset: 5 }))
+    ));
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), log_len);
+
+    data[0] ^= 1;
+    assert!(matches!(read_log(&data[..], &sign_params), Err(StorageError::InvalidHeader)));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+pub fn history_path() {
+    let hash_params = Bn256PoseidonParams::new_for_params::<BlakeHasher>(5,6,52,126);
+    let sign_params = AltJubjubBn256::new();
+
+    let dep_params = setup_deposit_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let transfer_params = setup_transfer_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let of_w_params = setup_offchain_withdraw_circuit(1, 2, 1, &hash_params, &sign_params, false).unwrap();
+    let on_w_params = setup_onchain_withdraw_circuit(1, 2, 1, &hash_params, false).unwrap();
+
+    let new_operator = || Operator::new(2, 1, 1, 1, 1, 1, &hash_params, &sign_params,
+        &dep_params, &transfer_params, &of_w_params, &on_w_params);
+
+    let mut oper = new_operator();
+    assert!(oper.history.is_empty());
+    assert!(oper.history.latest().is_none());
+
+    let mut rng = thread_rng();
+
+    let seckey_maker = PrivateKey::<Bn256>(rng.gen());
+    let pubkey_maker = PublicKey::from_private(
+        &seckey_maker,
+        FixedGenerators::SpendingKeyGenerator,
+        &sign_params,
+    );
+
+    let genesis_root = oper.tree.get_root();
+
+    oper.add_deposit(Deposit {
+        pubkey: Some(pubkey_maker),
+        account_id: 0,
+        token_id: 0,
+        amount: 100,
+    }).unwrap();
+    oper.execute_deposit_batch().unwrap();
+    let deposit_root = oper.tree.get_root();
+
+    let mut transfer = Transfer {
+        account_id_from: 0,
+        account_id_to: 1,
+        pubkey_to: None,
+        token_id: 0,
+        amount: 10,
+        fee: 0,
+        nonce: 1,
+        sign: None,
+    };
+    transfer.sign(&seckey_maker, &hash_params, &sign_params);
+    oper.add_transfer(transfer).unwrap();
+    oper.execute_transfer_batch().unwrap();
+    let transfer_root = oper.tree.get_root();
+
+    let mut withdrawal = OffchainWithdrawal {
+        account_id: 0,
+        token_id: 0,
+        amount: 20,
+        fee: 0,
+        nonce: 2,
+        sign: None,
+    };
+    withdrawal.sign(&seckey_maker, &hash_params, &sign_params);
+    oper.add_offchain_withdrawal(withdrawal).unwrap();
+    oper.execute_offchain_withdrawal_batch().unwrap();
+
+    oper.add_onchain_withdrawal(OnchainWithdrawal {
+        account_id: 0,
+        token_id: 0,
+        amount: None,
+    }).unwrap();
+    oper.execute_onchain_withdrawal_batch().unwrap();
+
+    // lookups by number
+
+    assert_eq!(oper.history.len(), 4);
+
+    let block = oper.history.get_block(0).unwrap();
+    assert_eq!(block.batch_type, CircuitType::Deposit);
+    assert_eq!(block.deposits.len(), 1);
+    assert_eq!(block.old_root, genesis_root);
+    assert_eq!(block.new_root, deposit_root);
+    assert_eq!(block.old_deposit_accum_hash, bn256::Fr::zero());
+    assert_eq!(block.new_deposit_accum_hash, oper.deposit_accum_hash);
+
+    let block = oper.history.get_block(1).unwrap();
+    assert_eq!(block.batch_type, CircuitType::Transfer);
+    assert_eq!(block.old_root, deposit_root);
+    assert_eq!(block.new_root, transfer_root);
+    assert_eq!(block.transfers[0].account_id_to, 1);
+
+    // onchain withdrawals are recorded with the withdrawn amount
+    let block = oper.history.latest().unwrap();
+    assert_eq!(block.batch_number, 3);
+    assert_eq!(block.onchain_withdrawals[0].amount, Some(70));
+    assert_eq!(block.new_withdrawal_accum_hash, oper.withdrawal_accum_hash);
+
+    // lookups by root and request
+
+    assert_eq!(oper.history.find_block_by_root(&deposit_root).unwrap().batch_number, 0);
+    assert_eq!(oper.history.find_block_by_root(&transfer_root).unwrap().batch_number, 1);
+    assert_eq!(oper.history.find_transfer(0, 1).unwrap().batch_number, 1);
+    assert_eq!(oper.history.find_offchain_withdrawal(0, 2).unwrap().batch_number, 2);
+
+    // lookup misses
+
+    assert!(oper.history.get_block(4).is_none());
+    assert!(oper.history.find_block_by_root(&genesis_root).is_none());
+    assert!(oper.history.find_transfer(0, 2).is_none());
+    assert!(oper.history.find_transfer(1, 1).is_none());
+    assert!(oper.history.find_offchain_withdrawal(0, 1).is_none());
+
+    // saved state keeps the block history
+
+    let state_path = std::env::temp_dir().join(format!("{}-history.state", std::process::id()));
+    oper.save_state(&state_path).unwrap();
+
+    let mut loaded = new_operator();
+    loaded.load_state(&state_path).unwrap();
+    std::fs::remove_file(&state_path).unwrap();
+
+    assert_eq!(loaded.batch_number, 4);
+    assert_eq!(loaded.history.len(), 4);
+    assert_eq!(loaded.history.find_block_by_root(&transfer_root).unwrap().batch_number, 1);
+    assert_eq!(loaded.history.find_transfer(0, 1).unwrap().batch_number, 1);
+    assert_eq!(loaded.history.find_offchain_withdrawal(0, 2).unwrap().batch_number, 2);
+    assert!(loaded.history.find_transfer(0, 2).is_none());
+    assert!(loaded.history.get_block(4).is_none());
+
+    let block = loaded.history.get_block(0).unwrap();
+    assert_eq!(block.deposits.len(), 1);
+    assert_eq!(block.new_deposit_accum_hash, oper.deposit_accum_hash);
+
+    let block = loaded.history.latest().unwrap();
+    assert_eq!(block.onchain_withdrawals[0].amount, Some(70));
+    assert_eq!(block.new_withdrawal_accum_hash, oper.withdrawal_accum_hash);
+}
+
+// helpers ------------------------------------------------------------------------------
+// --------------------------------------------------------------------------------------
+
+fn synthesize_transfer(
+    account_depth: usize,
+    old_root: bn256::Fr,
+    hash_params: &Bn256PoseidonParams,
+    sign_params: &AltJubjubBn256,
+    circuit: &TransferCircuit<Bn256>,
+) -> TestConstraintSystem<Bn256> {
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    let old_root_alloc = AllocatedNum::alloc(
+        cs.namespace(|| "allocate old root"),
+        || Ok(old_root),
+    ).unwrap();
+    let fee_token_id_alloc = AllocatedNum::alloc(
+        cs.namespace(|| "allocate fee token id"),
+        || Ok(usize_to_fr(0)),
+    ).unwrap();
+    circuit.process(
+        cs.namespace(|| "transfer"),
+        account_depth,
+        1,
+        hash_params,
+        sign_params,
+        &fee_token_id_alloc,
+        &old_root_alloc,
+    ).unwrap();
+    cs
+}
+
+fn synthesize_deposit(
+    account_depth: usize,
+    old_root: bn256::Fr,
+    hash_params: &Bn256PoseidonParams,
+    sign_params: &AltJubjubBn256,
+    circuit: &DepositCircuit<Bn256>,
+) -> TestConstraintSystem<Bn256> {
+    let mut cs = TestConstraintSystem::<Bn256>::new();
+    let old_hash_alloc = AllocatedNum::alloc(
+        cs.namespace(|| "allocate old hash"),
+        || Ok(usize_to_fr(0)),
+    ).unwrap();
+    let old_root_alloc = AllocatedNum::alloc(
+        cs.namespace(|| "allocate old root"),
+        || Ok(old_root),
+    ).unwrap();
+    circuit.process_deposit(
+        cs.namespace(|| "deposit"),
+        account_depth,
+        1,
+        hash_params,
+        sign_params,
+        &old_hash_alloc,
+        &old_root_alloc,
+    ).unwrap();
+    cs
+}
+
+// proof passes verification and fails once tampered or checked against wrong inputs
+fn check_verification<F>(
+    verify: F,
+    public_inputs: &[bn256::Fr],
+    proof: &Proof<Bn256>,
+)
+    where F: Fn(&[bn256::Fr], &Proof<Bn256>) -> Result<(), OperatorError>,
+{
+    verify(public_inputs, proof).unwrap();
+
+    let mut tampered_proof = proof.clone();
+    tampered_proof.a = proof.c;
+    assert!(matches!(
+        verify(public_inputs, &tampered_proof),
+        Err(OperatorError::VerificationFailed(VerificationError::InvalidProof))
+    ));
+
+    let mut wrong_inputs = public_inputs.to_vec();
+    wrong_inputs[0].add_assign(&bn256::Fr::one());
+    assert!(matches!(
+        verify(&wrong_inputs, proof),
+        Err(OperatorError::VerificationFailed(VerificationError::InvalidProof))
+    ));
 }